@@ -0,0 +1,51 @@
+//! Counts the allocations made while a loop reads the same global over and
+//! over.  Run with `cargo bench --bench globals`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Runs a loop that reads `x` `reads` times and returns how many allocations
+/// the run made.
+fn run(reads: usize) -> usize {
+    let source = format!(
+        "var x = 1; var sum = 0; for (var i = 0; i < {}; i = i + 1) {{ sum = sum + x; }}",
+        reads
+    );
+    let mut vm = lox::Vm::new();
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    vm.interpret(&source).unwrap();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    for &reads in &[1_000, 10_000, 100_000] {
+        let start = Instant::now();
+        let allocations = run(reads);
+        println!(
+            "{:>7} global reads: {:>4} allocations in {:?}",
+            reads,
+            allocations,
+            start.elapsed()
+        );
+    }
+}
@@ -1,14 +1,45 @@
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+use crate::object::{Function, Obj, ObjValue};
 use crate::op::*;
 use crate::value::Value;
 
 // Maximum number of constants allowed in a chunk.  A constant index must fit
-// in a byte.
-const MAX_CONSTANTS: usize = std::u8::MAX as usize;
+// in the three byte operand of OP_CONSTANT_LONG.
+const MAX_CONSTANTS: usize = 1 << 24;
+
+// Constants past this index can only be loaded with OP_CONSTANT_LONG.
+const MAX_SHORT_CONSTANTS: usize = u8::MAX as usize + 1;
+
+// Longest distance a jump with a 24-bit offset can cover.
+const MAX_LONG_JUMP: usize = (1 << 24) - 1;
+
+// Most parameters and captured variables the compiler allows a function.
+const MAX_ARITY: usize = 255;
+const MAX_UPVALUES: usize = 255;
+
+// Compiled chunks are saved with this header, followed by a format version.
+const MAGIC: &[u8] = b"LOXC";
+const FORMAT_VERSION: u8 = 1;
 
+// Tags for each kind of constant in a compiled chunk.
+const TAG_NIL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_INT: u8 = 3;
+const TAG_NUMBER: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_FUNCTION: u8 = 6;
+
+#[derive(Clone, Default)]
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
-    pub lines: Vec<usize>,
+    // Line numbers, run-length encoded.  Each entry is a source line and the
+    // offset just past the last byte of code compiled from it, so the entries
+    // are sorted by offset and can be binary searched.
+    lines: Vec<(usize, usize)>,
 }
 
 impl Chunk {
@@ -20,12 +51,16 @@ impl Chunk {
         }
     }
 
-    /// Adds a value to the chunk's constant table.  Returns the value's index
-    /// in the constant table.
-    pub fn add_constant(&mut self, value: Value) -> Result<u8, String> {
+    /// Adds a value to the chunk's constant table, unless the same value is
+    /// already there.  Returns the value's index in the constant table.
+    pub fn add_constant(&mut self, value: Value) -> Result<usize, String> {
+        if let Some(index) = self.constants.iter().position(|c| is_same_constant(c, &value)) {
+            return Ok(index);
+        }
+
         if self.constants.len() < MAX_CONSTANTS {
             self.constants.push(value);
-            let index = self.constants.len() as u8 - 1;
+            let index = self.constants.len() - 1;
             Ok(index)
         } else {
             let message = String::from("Too many constants in one chunk.");
@@ -33,113 +68,710 @@ impl Chunk {
         }
     }
 
+    /// Adds a value to the constant table for an instruction whose operand is
+    /// a single byte.  Returns the value's index in the constant table.
+    pub fn add_short_constant(&mut self, value: Value) -> Result<u8, String> {
+        let index = self.add_constant(value)?;
+        if index < MAX_SHORT_CONSTANTS {
+            Ok(index as u8)
+        } else {
+            let message = String::from("Too many constants in one chunk.");
+            Err(message)
+        }
+    }
+
     pub fn emit(&mut self, byte: u8, line: usize) {
         self.code.push(byte);
-        self.lines.push(line);
+
+        match self.lines.last_mut() {
+            Some((last_line, end)) if *last_line == line => *end = self.code.len(),
+            _ => self.lines.push((line, self.code.len())),
+        }
+    }
+
+    /// Drops the code from `code_len` on, and the constants from
+    /// `constants_len` on, as when replacing code just compiled.
+    pub fn truncate(&mut self, code_len: usize, constants_len: usize) {
+        self.code.truncate(code_len);
+        self.constants.truncate(constants_len);
+
+        // Drop the line runs past the end and cut short the last one left.
+        if code_len == 0 {
+            self.lines.clear();
+            return;
+        }
+        let run = self.lines.partition_point(|&(_, end)| end < code_len);
+        self.lines.truncate(run + 1);
+        if let Some((_, end)) = self.lines.last_mut() {
+            *end = code_len;
+        }
+    }
+
+    /// Returns the source line of the code at `offset`.  Past the end of the
+    /// code, it's the last line.
+    pub fn line_at(&self, offset: usize) -> usize {
+        self.line_for_offset(offset)
+            .or_else(|| self.lines.last().map(|&(line, _)| line))
+            .unwrap_or(0)
+    }
+
+    /// Returns the source line of the code at `offset`, or None if `offset`
+    /// is past the end of the code.
+    ///
+    /// ```
+    /// let chunk = lox::compile_to_chunk("var a = 1;\nprint a;").ok().unwrap();
+    ///
+    /// assert_eq!(chunk.line_for_offset(4), Some(2));
+    /// assert_eq!(chunk.line_for_offset(chunk.code.len()), None);
+    /// ```
+    pub fn line_for_offset(&self, offset: usize) -> Option<usize> {
+        let run = self.lines.partition_point(|&(_, end)| end <= offset);
+        self.lines.get(run).map(|&(line, _)| line)
     }
 
-    pub fn emit_constant(&mut self, value: Value, line: usize) -> Result<u8, String> {
+    /// Emits an instruction to load a constant, using OP_CONSTANT_LONG when
+    /// the index doesn't fit in a byte.
+    pub fn emit_constant(&mut self, value: Value, line: usize) -> Result<usize, String> {
         let index = self.add_constant(value)?;
-        self.emit(OP_CONSTANT, line);
-        self.emit(index, line);
+        if index < MAX_SHORT_CONSTANTS {
+            self.emit(OP_CONSTANT, line);
+            self.emit(index as u8, line);
+        } else {
+            self.emit(OP_CONSTANT_LONG, line);
+            self.emit(((index >> 16) & 0xff) as u8, line);
+            self.emit(((index >> 8) & 0xff) as u8, line);
+            self.emit((index & 0xff) as u8, line);
+        }
         Ok(index)
     }
 
+    /// Emits a forward jump to be patched once its target is known.  Until
+    /// then there's no telling how far it goes, so it's emitted in the long
+    /// form; the compiler shortens the ones that turn out to fit in 16 bits.
     pub fn emit_jump(&mut self, instruction: u8, line: usize) -> usize {
+        let instruction = match instruction {
+            OP_JUMP => OP_JUMP_LONG,
+            OP_JUMP_IF_FALSE => OP_JUMP_IF_FALSE_LONG,
+            instruction => instruction,
+        };
         self.emit(instruction, line);
-        self.emit(0xff, line);
-        self.emit(0xff, line);
-        
-        self.code.len() - 2
+        for _ in 0..jump_operand_len(instruction) {
+            self.emit(0xff, line);
+        }
+
+        self.code.len() - jump_operand_len(instruction)
     }
 
+    /// Emits an OP_LOOP that jumps backwards to `loop_start`, or an
+    /// OP_LOOP_LONG if the loop is too long for a 16-bit offset.
+    pub fn emit_loop(&mut self, loop_start: usize, line: usize) -> Result<(), String> {
+        // +3 to adjust for the OP_LOOP instruction itself.
+        let offset = self.code.len() - loop_start + 3;
+        if offset <= u16::MAX as usize {
+            self.emit(OP_LOOP, line);
+            self.emit(((offset >> 8) & 0xff) as u8, line);
+            self.emit((offset & 0xff) as u8, line);
+            return Ok(());
+        }
+
+        // The long form has one more byte to jump back over.
+        let offset = offset + 1;
+        if offset > MAX_LONG_JUMP {
+            return Err(String::from("Loop body too large."));
+        }
+        self.emit(OP_LOOP_LONG, line);
+        self.emit(((offset >> 16) & 0xff) as u8, line);
+        self.emit(((offset >> 8) & 0xff) as u8, line);
+        self.emit((offset & 0xff) as u8, line);
+
+        Ok(())
+    }
 
+    /// Points the jump whose operand starts at `offset` at the end of the
+    /// code so far.
+    ///
+    /// A jump over more than 65535 bytes is encoded with a 24-bit offset.
     pub fn patch_jump(&mut self, offset: usize) -> Result<(), String> {
-        // -2 to adjust for the bytecode for the jump offset itself.
-        let jump = self.code.len() - offset - 2;
-        let max_jump = std::u16::MAX as usize;
+        let operand_len = jump_operand_len(self.code[offset - 1]);
+        // Adjust for the bytecode for the jump offset itself.
+        let jump = self.code.len() - offset - operand_len;
+        let max_jump = if operand_len == 3 { MAX_LONG_JUMP } else { u16::MAX as usize };
 
         if jump > max_jump {
             return Err(String::from("Too much code to jump over."));
         }
 
-        self.code[offset] = ((jump >> 8) & 0xff) as u8;
-        self.code[offset + 1] = (jump & 0xff) as u8;
+        self.write_jump(offset, jump);
 
         Ok(())
     }
 
-    pub fn disassemble(&self, name: &str) {
-        println!("== {} ==", name);
+    /// Writes a jump distance into the operand at `offset`, in as many bytes
+    /// as the jump instruction before it takes.
+    pub fn write_jump(&mut self, offset: usize, jump: usize) {
+        let operand_len = jump_operand_len(self.code[offset - 1]);
+        for i in 0..operand_len {
+            let shift = 8 * (operand_len - 1 - i);
+            self.code[offset + i] = ((jump >> shift) & 0xff) as u8;
+        }
+    }
+
+    /// Returns the offset a jump instruction at `offset` lands on, or None
+    /// if the instruction isn't a jump.
+    pub fn jump_target(&self, offset: usize) -> Option<usize> {
+        let instruction = self.code[offset];
+        let operand_len = jump_operand_len(instruction);
+        if operand_len == 0 {
+            return None;
+        }
+
+        let operand = &self.code[offset + 1..offset + 1 + operand_len];
+        let jump = operand.iter().fold(0, |jump, &byte| (jump << 8) | byte as usize);
+        let next = offset + 1 + operand_len;
+        if instruction == OP_LOOP || instruction == OP_LOOP_LONG {
+            next.checked_sub(jump)
+        } else {
+            Some(next + jump)
+        }
+    }
+
+    /// Returns true if `bytes` starts with the header of a compiled chunk.
+    pub fn is_compiled(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Serializes the chunk, along with the chunks of any functions among its
+    /// constants, so that it can be run later without compiling it again.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(FORMAT_VERSION);
+        self.write(&mut bytes);
+        bytes
+    }
+
+    /// Loads a chunk saved by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, String> {
+        let mut reader = Reader { bytes, offset: 0 };
+        if reader.read_bytes(MAGIC.len())? != MAGIC {
+            return Err(String::from("Not a compiled chunk."));
+        }
+        let version = reader.read_u8()?;
+        if version != FORMAT_VERSION {
+            return Err(format!("Unsupported compiled chunk version {}.", version));
+        }
+
+        let chunk = Chunk::read(&mut reader)?;
+        if reader.offset != bytes.len() {
+            return Err(String::from("Unexpected data after compiled chunk."));
+        }
+        Ok(chunk)
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        write_usize(bytes, self.code.len());
+        bytes.extend_from_slice(&self.code);
+
+        write_usize(bytes, self.lines.len());
+        for &(line, end) in self.lines.iter() {
+            write_usize(bytes, line);
+            write_usize(bytes, end);
+        }
+
+        write_usize(bytes, self.constants.len());
+        for constant in self.constants.iter() {
+            write_constant(bytes, constant);
+        }
+    }
+
+    fn read(reader: &mut Reader) -> Result<Chunk, String> {
+        let code_len = reader.read_usize()?;
+        let code = reader.read_bytes(code_len)?.to_vec();
+
+        let line_count = reader.read_usize()?;
+        let mut lines = Vec::new();
+        for _ in 0..line_count {
+            let line = reader.read_usize()?;
+            let end = reader.read_usize()?;
+            lines.push((line, end));
+        }
+
+        // Every byte of code needs a line, or `line_at` would fail.
+        let ends_sorted = lines.windows(2).all(|pair| pair[0].1 < pair[1].1);
+        if !ends_sorted || lines.last().map_or(0, |&(_, end)| end) != code.len() {
+            return Err(String::from("Invalid line information in compiled chunk."));
+        }
+
+        let constant_count = reader.read_usize()?;
+        let mut constants = Vec::new();
+        for _ in 0..constant_count {
+            constants.push(read_constant(reader)?);
+        }
+
+        Ok(Chunk {
+            code,
+            constants,
+            lines,
+        })
+    }
+
+    pub fn disassemble(&self, name: &str, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "== {} ==", name)?;
 
         let mut offset = 0;
         while offset < self.code.len() {
-            offset = self.disassemble_instruction(offset);
+            offset = self.disassemble_instruction(offset, out)?;
+        }
+
+        // Functions are compiled into chunks of their own.
+        for constant in self.constants.iter() {
+            if let Some(function) = constant.as_obj().and_then(|obj| obj.as_function()) {
+                function.chunk.disassemble(&function.name, out)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns what `disassemble` writes, under the name "code".
+    ///
+    /// ```
+    /// let mut chunk = lox::Chunk::new();
+    /// lox::compile("print 3;", &mut chunk).unwrap();
+    ///
+    /// assert_eq!(
+    ///     chunk.disassemble_to_string(),
+    ///     "== code ==\n\
+    ///      0000 0001 OP_CONSTANT      0000 3\n\
+    ///      0002    | OP_PRINT\n\
+    ///      0003    | OP_NIL\n\
+    ///      0004    | OP_RETURN\n"
+    /// );
+    /// ```
+    pub fn disassemble_to_string(&self) -> String {
+        let mut out = Vec::new();
+        self.disassemble("code", &mut out).expect("writing to a Vec can't fail");
+        String::from_utf8(out).expect("disassembly is UTF-8")
+    }
+
+    /// Returns the disassembly of the instructions from `start` up to `end`,
+    /// without a header.  `start` must be where an instruction begins; an
+    /// instruction that begins before `end` is shown whole.
+    ///
+    /// ```
+    /// let chunk = lox::compile_to_chunk("var a = 1;\nprint a + 2;").ok().unwrap();
+    ///
+    /// assert_eq!(
+    ///     chunk.disassemble_range(4, 8),
+    ///     "0004 0002 OP_GET_GLOBAL    0000 a\n\
+    ///      0006    | OP_CONSTANT      0002 2\n"
+    /// );
+    /// ```
+    pub fn disassemble_range(&self, start: usize, end: usize) -> String {
+        let mut out = Vec::new();
+        let mut offset = start;
+        while offset < end.min(self.code.len()) {
+            offset = self
+                .disassemble_instruction(offset, &mut out)
+                .expect("writing to a Vec can't fail");
         }
+        String::from_utf8(out).expect("disassembly is UTF-8")
     }
 
-    pub fn disassemble_instruction(&self, offset: usize) -> usize {
-        print!("{:04} ", offset);
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
-            print!("   | ");
+    /// Returns the number of bytes taken by the instruction at `offset`,
+    /// counting its operands.
+    pub fn instruction_len(&self, offset: usize) -> usize {
+        match self.code[offset] {
+            OP_CONSTANT | OP_GET_LOCAL | OP_SET_LOCAL | OP_GET_GLOBAL | OP_DEFINE_GLOBAL | OP_SET_GLOBAL
+            | OP_CALL | OP_GET_UPVALUE | OP_SET_UPVALUE | OP_CLASS | OP_GET_PROPERTY | OP_SET_PROPERTY
+            | OP_METHOD | OP_GET_SUPER | OP_LIST | OP_MAP | OP_TAIL_CALL | OP_DEFINE_CONST_GLOBAL => 2,
+            OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP => 3,
+            OP_CONSTANT_LONG | OP_JUMP_LONG | OP_JUMP_IF_FALSE_LONG | OP_LOOP_LONG => 4,
+            OP_CLOSURE => {
+                // Each captured variable takes two more bytes.
+                let upvalue_count = self
+                    .code
+                    .get(offset + 1)
+                    .and_then(|&constant| self.constants.get(constant as usize))
+                    .and_then(|value| value.as_obj())
+                    .and_then(|obj| obj.as_function())
+                    .map_or(0, |function| function.upvalue_count);
+                2 + 2 * upvalue_count
+            }
+            _ => 1,
+        }
+    }
+
+    pub fn disassemble_instruction(&self, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        write!(out, "{:04} ", offset)?;
+        // A jump in a damaged chunk can go past the end.
+        if offset >= self.code.len() {
+            writeln!(out, "     End of code")?;
+            return Ok(offset);
+        }
+        let line = self.line_at(offset);
+        if offset > 0 && line == self.line_at(offset - 1) {
+            write!(out, "   | ")?;
         } else {
-            print!("{:04} ", self.lines[offset]);
+            write!(out, "{:04} ", line)?;
+        }
+        // A chunk loaded from a damaged file may end partway through an
+        // instruction.
+        if offset + self.instruction_len(offset) > self.code.len() {
+            writeln!(out, "Truncated instruction: {}", self.code[offset])?;
+            return Ok(self.code.len());
         }
         match self.code[offset] {
-            OP_CONSTANT => self.constant_instruction("OP_CONSTANT", offset),
-            OP_NIL => simple_instruction("OP_NIL", offset),
-            OP_TRUE => simple_instruction("OP_TRUE", offset),
-            OP_FALSE => simple_instruction("OP_FALSE", offset),
-            OP_POP => simple_instruction("OP_POP", offset),
-            OP_GET_LOCAL => self.byte_instruction("OP_GET_LOCAL", offset),
-            OP_SET_LOCAL => self.byte_instruction("OP_SET_LOCAL", offset),
-            OP_GET_GLOBAL => self.constant_instruction("OP_GET_GLOBAL", offset),
-            OP_DEFINE_GLOBAL => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
-            OP_SET_GLOBAL => self.constant_instruction("OP_SET_GLOBAL", offset),
-            OP_EQUAL => simple_instruction("OP_EQUAL", offset),
-            OP_GREATER => simple_instruction("OP_GREATER", offset),
-            OP_LESS => simple_instruction("OP_LESS", offset),
-            OP_ADD => simple_instruction("OP_ADD", offset),
-            OP_SUBTRACT => simple_instruction("OP_SUBTRACT", offset),
-            OP_MULTIPLY => simple_instruction("OP_MULTIPLY", offset),
-            OP_DIVIDE => simple_instruction("OP_DIVIDE", offset),
-            OP_NOT => simple_instruction("OP_NOT", offset),
-            OP_NEGATE => simple_instruction("OP_NEGATE", offset),
-            OP_PRINT => simple_instruction("OP_PRINT", offset),
-            OP_JUMP => self.jump_instruction("OP_JUMP", 1, offset),
-            OP_JUMP_IF_FALSE => self.jump_instruction("OP_JUMP_IF_FALSE", 1, offset),
-            OP_RETURN => simple_instruction("OP_RETURN", offset),
+            OP_CONSTANT => self.constant_instruction("OP_CONSTANT", offset, out),
+            OP_CONSTANT_LONG => self.constant_long_instruction("OP_CONSTANT_LONG", offset, out),
+            OP_NIL => simple_instruction("OP_NIL", offset, out),
+            OP_TRUE => simple_instruction("OP_TRUE", offset, out),
+            OP_FALSE => simple_instruction("OP_FALSE", offset, out),
+            OP_POP => simple_instruction("OP_POP", offset, out),
+            OP_GET_LOCAL => self.byte_instruction("OP_GET_LOCAL", offset, out),
+            OP_SET_LOCAL => self.byte_instruction("OP_SET_LOCAL", offset, out),
+            OP_GET_GLOBAL => self.constant_instruction("OP_GET_GLOBAL", offset, out),
+            OP_DEFINE_GLOBAL => self.constant_instruction("OP_DEFINE_GLOBAL", offset, out),
+            OP_DEFINE_CONST_GLOBAL => self.constant_instruction("OP_DEFINE_CONST_GLOBAL", offset, out),
+            OP_SET_GLOBAL => self.constant_instruction("OP_SET_GLOBAL", offset, out),
+            OP_EQUAL => simple_instruction("OP_EQUAL", offset, out),
+            OP_GREATER => simple_instruction("OP_GREATER", offset, out),
+            OP_LESS => simple_instruction("OP_LESS", offset, out),
+            OP_ADD => simple_instruction("OP_ADD", offset, out),
+            OP_SUBTRACT => simple_instruction("OP_SUBTRACT", offset, out),
+            OP_MULTIPLY => simple_instruction("OP_MULTIPLY", offset, out),
+            OP_DIVIDE => simple_instruction("OP_DIVIDE", offset, out),
+            OP_MODULO => simple_instruction("OP_MODULO", offset, out),
+            OP_POWER => simple_instruction("OP_POWER", offset, out),
+            OP_BIT_AND => simple_instruction("OP_BIT_AND", offset, out),
+            OP_BIT_OR => simple_instruction("OP_BIT_OR", offset, out),
+            OP_BIT_XOR => simple_instruction("OP_BIT_XOR", offset, out),
+            OP_SHIFT_LEFT => simple_instruction("OP_SHIFT_LEFT", offset, out),
+            OP_SHIFT_RIGHT => simple_instruction("OP_SHIFT_RIGHT", offset, out),
+            OP_NOT => simple_instruction("OP_NOT", offset, out),
+            OP_NEGATE => simple_instruction("OP_NEGATE", offset, out),
+            OP_PRINT => simple_instruction("OP_PRINT", offset, out),
+            OP_JUMP => self.jump_instruction("OP_JUMP", offset, out),
+            OP_JUMP_IF_FALSE => self.jump_instruction("OP_JUMP_IF_FALSE", offset, out),
+            OP_LOOP => self.jump_instruction("OP_LOOP", offset, out),
+            OP_JUMP_LONG => self.jump_instruction("OP_JUMP_LONG", offset, out),
+            OP_JUMP_IF_FALSE_LONG => self.jump_instruction("OP_JUMP_IF_FALSE_LONG", offset, out),
+            OP_LOOP_LONG => self.jump_instruction("OP_LOOP_LONG", offset, out),
+            OP_CALL => self.byte_instruction("OP_CALL", offset, out),
+            OP_TAIL_CALL => self.byte_instruction("OP_TAIL_CALL", offset, out),
+            OP_CLOSURE => self.closure_instruction(offset, out),
+            OP_GET_UPVALUE => self.byte_instruction("OP_GET_UPVALUE", offset, out),
+            OP_SET_UPVALUE => self.byte_instruction("OP_SET_UPVALUE", offset, out),
+            OP_CLOSE_UPVALUE => simple_instruction("OP_CLOSE_UPVALUE", offset, out),
+            OP_CLASS => self.constant_instruction("OP_CLASS", offset, out),
+            OP_GET_PROPERTY => self.constant_instruction("OP_GET_PROPERTY", offset, out),
+            OP_SET_PROPERTY => self.constant_instruction("OP_SET_PROPERTY", offset, out),
+            OP_METHOD => self.constant_instruction("OP_METHOD", offset, out),
+            OP_INHERIT => simple_instruction("OP_INHERIT", offset, out),
+            OP_GET_SUPER => self.constant_instruction("OP_GET_SUPER", offset, out),
+            OP_LIST => self.byte_instruction("OP_LIST", offset, out),
+            OP_MAP => self.byte_instruction("OP_MAP", offset, out),
+            OP_GET_INDEX => simple_instruction("OP_GET_INDEX", offset, out),
+            OP_SET_INDEX => simple_instruction("OP_SET_INDEX", offset, out),
+            OP_RETURN => simple_instruction("OP_RETURN", offset, out),
             instruction => {
-                println!("Unknown opcode: {}", instruction);
-                offset + 1
+                writeln!(out, "Unknown opcode: {}", instruction)?;
+                Ok(offset + 1)
             }
         }
     }
 
-    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+    fn byte_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
         let slot = self.code[offset + 1];
-        println!("{:16} {:04}", name, slot);
-        offset + 2
+        writeln!(out, "{:16} {:04}", name, slot)?;
+        Ok(offset + 2)
+    }
+
+    fn jump_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        match self.jump_target(offset) {
+            Some(jump) => writeln!(out, "{:16} {:04} {}", name, offset, jump)?,
+            None => writeln!(out, "{:16} {:04} <before start>", name, offset)?,
+        }
+        Ok(offset + self.instruction_len(offset))
     }
 
-    fn jump_instruction(&self, name: &str, sign: i32, offset: usize) -> usize {
-        let hi = self.code[offset + 1] as usize;
-        let lo = self.code[offset + 2] as usize;
-        let jump = (hi << 8) | lo;
-        let jump = if sign < 0 { offset + 3 + jump } else { offset + 3 - jump };
-        println!("{:16} {:04} {}", name, offset, jump);
-        offset + 3
+    fn constant_long_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        let constant = ((self.code[offset + 1] as usize) << 16)
+            | ((self.code[offset + 2] as usize) << 8)
+            | self.code[offset + 3] as usize;
+        writeln!(out, "{:16} {:04} {}", name, constant, self.constant_text(constant))?;
+        Ok(offset + 4)
     }
 
-    fn constant_instruction(&self, name: &str, offset: usize) -> usize {
+    fn closure_instruction(&self, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
         let constant = self.code[offset + 1];
-        let value = &self.constants[constant as usize];
-        println!("{:16} {:04} {}", name, constant, value);
-        offset + 2
+        writeln!(out, "{:16} {:04} {}", "OP_CLOSURE", constant, self.constant_text(constant as usize))?;
+
+        let upvalue_count = (self.instruction_len(offset) - 2) / 2;
+        let mut offset = offset + 2;
+        for _ in 0..upvalue_count {
+            let is_local = self.code[offset];
+            let index = self.code[offset + 1];
+            let kind = if is_local != 0 { "local" } else { "upvalue" };
+            writeln!(out, "{:04}    |                     {} {}", offset, kind, index)?;
+            offset += 2;
+        }
+        Ok(offset)
+    }
+
+    fn constant_instruction(&self, name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+        let constant = self.code[offset + 1];
+        writeln!(out, "{:16} {:04} {}", name, constant, self.constant_text(constant as usize))?;
+        Ok(offset + 2)
+    }
+
+    /// Returns how the disassembler shows the constant at `index`, which may
+    /// be out of range in a damaged chunk.
+    fn constant_text(&self, index: usize) -> String {
+        match self.constants.get(index) {
+            Some(value) => value.to_string(),
+            None => String::from("<missing constant>"),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let end = self.offset.checked_add(len).filter(|&end| end <= self.bytes.len());
+        match end {
+            Some(end) => {
+                let bytes = &self.bytes[self.offset..end];
+                self.offset = end;
+                Ok(bytes)
+            }
+            None => Err(String::from("Unexpected end of compiled chunk.")),
+        }
+    }
+
+    fn read_u8(&mut self) -> Result<u8, String> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u64(&mut self) -> Result<u64, String> {
+        let mut buffer = [0; 8];
+        buffer.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_be_bytes(buffer))
+    }
+
+    fn read_usize(&mut self) -> Result<usize, String> {
+        let x = self.read_u64()?;
+        usize::try_from(x).map_err(|_| String::from("Size too large in compiled chunk."))
+    }
+
+    fn read_string(&mut self) -> Result<String, String> {
+        let len = self.read_usize()?;
+        let bytes = self.read_bytes(len)?.to_vec();
+        String::from_utf8(bytes).map_err(|_| String::from("Invalid string in compiled chunk."))
     }
 }
 
-fn simple_instruction(name: &str, offset: usize) -> usize {
-    println!("{}", name);
-    offset + 1
+fn write_usize(bytes: &mut Vec<u8>, x: usize) {
+    bytes.extend_from_slice(&(x as u64).to_be_bytes());
+}
+
+fn write_string(bytes: &mut Vec<u8>, s: &str) {
+    write_usize(bytes, s.len());
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+fn write_constant(bytes: &mut Vec<u8>, constant: &Value) {
+    match constant {
+        Value::Nil => bytes.push(TAG_NIL),
+        Value::Bool(false) => bytes.push(TAG_FALSE),
+        Value::Bool(true) => bytes.push(TAG_TRUE),
+        Value::Int(x) => {
+            bytes.push(TAG_INT);
+            bytes.extend_from_slice(&x.to_be_bytes());
+        }
+        Value::Number(x) => {
+            bytes.push(TAG_NUMBER);
+            bytes.extend_from_slice(&x.to_bits().to_be_bytes());
+        }
+        Value::Obj(obj) => match &obj.value {
+            ObjValue::String(s) => {
+                bytes.push(TAG_STRING);
+                write_string(bytes, s);
+            }
+            ObjValue::Function(function) => {
+                bytes.push(TAG_FUNCTION);
+                write_string(bytes, &function.name);
+                write_usize(bytes, function.arity);
+                write_usize(bytes, function.upvalue_count);
+                function.chunk.write(bytes);
+            }
+            _ => unreachable!("the compiler only makes string and function constants"),
+        },
+    }
+}
+
+fn read_constant(reader: &mut Reader) -> Result<Value, String> {
+    let value = match reader.read_u8()? {
+        TAG_NIL => Value::Nil,
+        TAG_FALSE => Value::Bool(false),
+        TAG_TRUE => Value::Bool(true),
+        TAG_INT => Value::Int(reader.read_u64()? as i64),
+        TAG_NUMBER => Value::Number(f64::from_bits(reader.read_u64()?)),
+        TAG_STRING => Value::Obj(Obj::new_string(reader.read_string()?)),
+        TAG_FUNCTION => {
+            let name = reader.read_string()?;
+            let arity = reader.read_usize()?;
+            let upvalue_count = reader.read_usize()?;
+            if arity > MAX_ARITY || upvalue_count > MAX_UPVALUES {
+                return Err(format!("Invalid function '{}' in compiled chunk.", name));
+            }
+            let chunk = Chunk::read(reader)?;
+            let function = Function::new(&name, arity, upvalue_count, chunk);
+            Value::Obj(Obj::new_function(function))
+        }
+        tag => return Err(format!("Unknown constant tag {} in compiled chunk.", tag)),
+    };
+    Ok(value)
+}
+
+// Constants are only shared when they are the same kind of value, so that `1`
+// and `1.0` don't end up as one entry even though they compare equal.
+fn is_same_constant(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => a == b,
+        (Value::Number(a), Value::Number(b)) => a.to_bits() == b.to_bits(),
+        (Value::Int(_), _) | (_, Value::Int(_)) => false,
+        (Value::Number(_), _) | (_, Value::Number(_)) => false,
+        _ => a == b,
+    }
+}
+
+/// Returns the number of bytes in a jump instruction's offset, or 0 if the
+/// instruction isn't a jump.
+fn jump_operand_len(instruction: u8) -> usize {
+    match instruction {
+        OP_JUMP | OP_JUMP_IF_FALSE | OP_LOOP => 2,
+        OP_JUMP_LONG | OP_JUMP_IF_FALSE_LONG | OP_LOOP_LONG => 3,
+        _ => 0,
+    }
+}
+
+fn simple_instruction(name: &str, offset: usize, out: &mut dyn Write) -> io::Result<usize> {
+    writeln!(out, "{}", name)?;
+    Ok(offset + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loading_rejects_too_many_upvalues() {
+        let mut chunk = Chunk::new();
+        let function = Function::new("f", 0, 1 << 40, Chunk::new());
+        chunk.add_constant(Value::Obj(Obj::new_function(function))).unwrap();
+
+        let error = Chunk::from_bytes(&chunk.to_bytes()).err().unwrap();
+        assert_eq!(error, "Invalid function 'f' in compiled chunk.");
+    }
+
+    #[test]
+    fn loading_rejects_too_many_parameters() {
+        let mut chunk = Chunk::new();
+        let function = Function::new("f", 256, 0, Chunk::new());
+        chunk.add_constant(Value::Obj(Obj::new_function(function))).unwrap();
+
+        assert!(Chunk::from_bytes(&chunk.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn disassembling_damaged_code() {
+        let mut chunk = Chunk::new();
+        for &byte in [OP_CONSTANT, 7, OP_LOOP, 0, 9, OP_CLOSURE].iter() {
+            chunk.emit(byte, 1);
+        }
+
+        assert_eq!(
+            chunk.disassemble_to_string(),
+            "== code ==\n\
+             0000 0001 OP_CONSTANT      0007 <missing constant>\n\
+             0002    | OP_LOOP          0002 <before start>\n\
+             0005    | Truncated instruction: 26\n"
+        );
+
+        // Tracing a jump that lands past the end.
+        let mut out = Vec::new();
+        assert_eq!(chunk.disassemble_instruction(10, &mut out).unwrap(), 10);
+        assert_eq!(out, b"0010      End of code\n");
+    }
+
+    #[test]
+    fn lines_of_several_instructions() {
+        let mut chunk = Chunk::new();
+        for &line in &[1, 1, 1, 2, 2, 4] {
+            chunk.emit(OP_NIL, line);
+        }
+
+        let lines: Vec<usize> = (0..chunk.code.len()).map(|offset| chunk.line_at(offset)).collect();
+        assert_eq!(lines, [1, 1, 1, 2, 2, 4]);
+        assert_eq!(chunk.lines, [(1, 3), (2, 5), (4, 6)]);
+        assert_eq!(chunk.line_for_offset(6), None);
+    }
+
+    #[test]
+    fn disassembling_a_program() {
+        let source = "var a = 1;\nfun f(x) {\n  return x + a;\n}\nprint f(a);";
+        let chunk = crate::compiler::compile_to_chunk(source).ok().unwrap();
+        assert_eq!(
+            chunk.disassemble_to_string(),
+            "== code ==\n\
+             0000 0001 OP_CONSTANT      0001 1\n\
+             0002    | OP_DEFINE_GLOBAL 0000 a\n\
+             0004 0004 OP_CLOSURE       0003 <fn f>\n\
+             0006 0002 OP_DEFINE_GLOBAL 0002 f\n\
+             0008 0005 OP_GET_GLOBAL    0002 f\n\
+             0010    | OP_GET_GLOBAL    0000 a\n\
+             0012    | OP_CALL          0001\n\
+             0014    | OP_PRINT\n\
+             0015    | OP_NIL\n\
+             0016    | OP_RETURN\n\
+             == f ==\n\
+             0000 0003 OP_GET_LOCAL     0001\n\
+             0002    | OP_GET_GLOBAL    0000 a\n\
+             0004    | OP_ADD\n\
+             0005    | OP_RETURN\n\
+             0006 0004 OP_NIL\n\
+             0007    | OP_RETURN\n"
+        );
+    }
+
+    #[test]
+    fn disassembling_a_range() {
+        let chunk = crate::compiler::compile_to_chunk("var a = 1;\nprint a;").ok().unwrap();
+        assert_eq!(chunk.disassemble_range(4, 6), "0004 0002 OP_GET_GLOBAL    0000 a\n");
+        // An instruction that begins before `end` is shown whole.
+        assert_eq!(chunk.disassemble_range(4, 7), "0004 0002 OP_GET_GLOBAL    0000 a\n0006    | OP_PRINT\n");
+        assert_eq!(chunk.disassemble_range(6, 100), "0006    | OP_PRINT\n0007    | OP_NIL\n0008    | OP_RETURN\n");
+        assert_eq!(chunk.disassemble_range(3, 3), "");
+
+        let mut chunk = Chunk::new();
+        for i in 0..256 {
+            chunk.add_constant(Value::Int(i)).unwrap();
+        }
+        chunk.emit_constant(Value::Int(256), 1).unwrap();
+        chunk.emit(OP_PRINT, 1);
+        assert_eq!(chunk.disassemble_range(0, 5), "0000 0001 OP_CONSTANT_LONG 0256 256\n0004    | OP_PRINT\n");
+    }
+
+    #[test]
+    fn lines_of_a_program() {
+        let chunk = crate::compiler::compile_to_chunk("var a = 1;\n\nprint a;\nprint 2;").ok().unwrap();
+        let lines: Vec<usize> = (0..chunk.code.len()).map(|offset| chunk.line_for_offset(offset).unwrap()).collect();
+        assert_eq!(lines, [1, 1, 1, 1, 3, 3, 3, 4, 4, 4, 4, 4]);
+        assert_eq!(chunk.line_for_offset(chunk.code.len()), None);
+        assert_eq!(Chunk::new().line_for_offset(0), None);
+    }
 }
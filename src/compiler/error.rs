@@ -4,23 +4,108 @@ use std::rc::Rc;
 
 use crate::scanner::{Token, TokenTag};
 
+/// A problem found while compiling, at the token where it was found.  The
+/// same type carries warnings, which don't stop the source compiling.
 #[derive(Debug)]
 pub struct ParseError {
     token: Rc<Token>,
     message: String,
+    is_warning: bool,
+}
+
+impl ParseError {
+    /// Returns the line of the token where the error was found.
+    ///
+    /// ```
+    /// let source = "var a = 1;\nprint a +;";
+    /// let mut chunk = lox::Chunk::new();
+    /// let errors = lox::compile(source, &mut chunk).unwrap_err();
+    /// assert_eq!(errors[0].line(), 2);
+    /// ```
+    pub fn line(&self) -> usize {
+        self.token.line
+    }
+
+    pub fn column(&self) -> usize {
+        self.token.column
+    }
+
+    /// Returns what went wrong, without the position.
+    ///
+    /// ```
+    /// let mut chunk = lox::Chunk::new();
+    /// let errors = lox::compile("print 1", &mut chunk).unwrap_err();
+    /// assert_eq!(errors[0].message(), "Expect ';' after value.");
+    /// ```
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// Formats the error as `Display` does, followed by the line of `source`
+    /// it was found on with a caret under the offending token.
+    ///
+    /// ```
+    /// let source = "var x = 1;\nprint x +;";
+    /// let mut chunk = lox::Chunk::new();
+    /// let errors = lox::compile(source, &mut chunk).unwrap_err();
+    /// assert_eq!(
+    ///     errors[0].render(source),
+    ///     "[line 2, col 10] Error at ';': unexpected token\n  |\n2 | print x +;\n  |          ^\n"
+    /// );
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let mut rendered = format!("{}\n", self);
+        let text = match source.lines().nth(self.token.source_line.wrapping_sub(1)) {
+            Some(text) => text,
+            None => return rendered,
+        };
+
+        let number = self.token.line.to_string();
+        let gutter = " ".repeat(number.len());
+        rendered += &format!("{} |\n{} | {}\n{} | ", gutter, number, text, gutter);
+
+        // Copy any tabs before the token so the caret lines up under it.
+        let before = self.token.column.saturating_sub(1);
+        rendered.extend(text.chars().take(before).map(|c| if c == '\t' { '\t' } else { ' ' }));
+        let width = match self.token.tag {
+            TokenTag::Eof | TokenTag::Error => 1,
+            _ => self.token.lexeme.chars().count(),
+        };
+        // A token may run past the end of the line, as a string can.
+        let width = width.min(text.chars().count().saturating_sub(before)).max(1);
+        rendered += &"^".repeat(width);
+        rendered.push('\n');
+        rendered
+    }
+
+    /// Returns true if the error was found at the end of the source, which
+    /// means the source may just be incomplete.
+    pub fn is_at_end(&self) -> bool {
+        self.token.tag == TokenTag::Eof
+    }
+
+    pub fn is_warning(&self) -> bool {
+        self.is_warning
+    }
 }
 
 pub fn parse_error<T>(token: &Rc<Token>, message: &str) -> Result<T, ParseError> {
     let token = Rc::clone(token);
     let message = String::from(message);
-    Err(ParseError { token, message })
+    Err(ParseError { token, message, is_warning: false })
+}
+
+pub fn warning(token: &Rc<Token>, message: String) -> ParseError {
+    let token = Rc::clone(token);
+    ParseError { token, message, is_warning: true }
 }
 
 impl Error for ParseError {}
 
 impl fmt::Display for ParseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "[line {}] Error", self.token.line)?;
+        let kind = if self.is_warning { "Warning" } else { "Error" };
+        write!(f, "[line {}, col {}] {}", self.token.line, self.token.column, kind)?;
 
         match self.token.tag {
             TokenTag::Eof => write!(f, " at end")?,
@@ -32,4 +117,60 @@ impl fmt::Display for ParseError {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::chunk::Chunk;
+    use crate::compiler::compile;
+
+    /// Returns the first error in `source`, rendered.
+    fn rendered(source: &str) -> String {
+        compile(source, &mut Chunk::new()).unwrap_err()[0].render(source)
+    }
+
+    /// Returns the line under the source line, with the caret.
+    fn caret(source: &str) -> String {
+        String::from(rendered(source).lines().last().unwrap())
+    }
+
+    #[test]
+    fn caret_under_the_token() {
+        assert_eq!(caret("print x +;"), "  |          ^");
+        // Tabs are copied, and characters count once however many bytes.
+        assert_eq!(caret("\tprint \"é\" + ;"), "  | \t            ^");
+        assert_eq!(caret("var a = 1; a b;"), "  |              ^");
+        assert_eq!(caret("fun f(a, b) {}\nf(1);"), "  | ^");
+    }
+
+    #[test]
+    fn caret_as_wide_as_the_token() {
+        assert_eq!(caret("1++;"), "  |  ^^");
+        assert_eq!(caret("print this;"), "  |       ^^^^");
+    }
+
+    #[test]
+    fn caret_at_the_end() {
+        assert_eq!(
+            rendered("print 1"),
+            "[line 1, col 8] Error at end: Expect ';' after value.\n  |\n1 | print 1\n  |        ^\n"
+        );
+    }
+
+    #[test]
+    fn gutter_fits_the_line_number() {
+        let source = format!("{}print 1 +;", "\n".repeat(11));
+        assert_eq!(
+            rendered(&source),
+            "[line 12, col 10] Error at ';': unexpected token\n   |\n12 | print 1 +;\n   |          ^\n"
+        );
+    }
+
+    #[test]
+    fn line_directive_shifts_reported_lines() {
+        let source = "//# line 100 \"page.tmpl\"\nprint 1;\nprint ;";
+        assert_eq!(
+            rendered(source),
+            "[line 101, col 7] Error at ';': unexpected token\n    |\n101 | print ;\n    |       ^\n"
+        );
+    }
+}
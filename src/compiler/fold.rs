@@ -0,0 +1,59 @@
+//! Folding arithmetic on number literals into a single constant.
+
+use crate::chunk::Chunk;
+use crate::op::*;
+use crate::value::Value;
+
+/// Where an operand's code starts, so that it can be replaced once folded.
+#[derive(Clone, Copy)]
+pub struct Mark {
+    pub code_len: usize,
+    pub constants_len: usize,
+}
+
+impl Mark {
+    pub fn new(chunk: &Chunk) -> Self {
+        Mark {
+            code_len: chunk.code.len(),
+            constants_len: chunk.constants.len(),
+        }
+    }
+}
+
+/// Returns the constant loaded by the code from `start` to `end`, if that
+/// code is nothing but a single constant load.
+pub fn constant_at(chunk: &Chunk, start: usize, end: usize) -> Option<&Value> {
+    let index = match chunk.code.get(start..end)? {
+        [OP_CONSTANT, index] => *index as usize,
+        [OP_CONSTANT_LONG, hi, mid, lo] => ((*hi as usize) << 16) | ((*mid as usize) << 8) | *lo as usize,
+        _ => return None,
+    };
+    chunk.constants.get(index)
+}
+
+/// Works out `a op b` the way the VM would, for the operators that fold.
+/// Returns None for anything that should be left until run time: operands
+/// that aren't numbers, integer overflow, and division by zero.
+pub fn fold(op: u8, a: &Value, b: &Value) -> Option<Value> {
+    if let (Value::Int(a), Value::Int(b)) = (a, b) {
+        let x = match op {
+            OP_ADD => a.checked_add(*b),
+            OP_SUBTRACT => a.checked_sub(*b),
+            OP_MULTIPLY => a.checked_mul(*b),
+            OP_DIVIDE => a.checked_div(*b),
+            _ => None,
+        };
+        return x.map(Value::Int);
+    }
+
+    let a = a.as_f64()?;
+    let b = b.as_f64()?;
+    let x = match op {
+        OP_ADD => a + b,
+        OP_SUBTRACT => a - b,
+        OP_MULTIPLY => a * b,
+        OP_DIVIDE if b != 0.0 => a / b,
+        _ => return None,
+    };
+    Some(Value::Number(x))
+}
@@ -5,12 +5,32 @@ use crate::scanner::Token;
 pub struct Local {
     pub name: Rc<Token>,
     pub depth: i32,
+    pub is_captured: bool,
+    pub is_const: bool,
 }
 
 impl Local {
     pub fn new(name: &Rc<Token>) -> Self {
         let name = Rc::clone(name);
         let depth = -1;
-        Local { name, depth }
+        let is_captured = false;
+        let is_const = false;
+        Local {
+            name,
+            depth,
+            is_captured,
+            is_const,
+        }
     }
-}
\ No newline at end of file
+}
+
+/// A variable captured by a closure.  The index refers to a local slot of the
+/// enclosing function if `is_local` is set, and otherwise to one of the
+/// enclosing function's own upvalues.  `is_const` is set if the captured
+/// variable was declared with `const`.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Upvalue {
+    pub index: u8,
+    pub is_local: bool,
+    pub is_const: bool,
+}
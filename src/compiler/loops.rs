@@ -0,0 +1,24 @@
+/// Bookkeeping for a loop that is being compiled, so `break` and `continue`
+/// know how far to unwind and where to jump.
+pub struct Loop {
+    /// Where `continue` jumps back to, or None if it jumps forward to code
+    /// that hasn't been compiled yet, as in a do-while loop.
+    pub start: Option<usize>,
+    pub scope_depth: i32,
+    pub breaks: Vec<usize>,
+    /// Forward `continue` jumps waiting to be patched.
+    pub continues: Vec<usize>,
+}
+
+impl Loop {
+    pub fn new(start: Option<usize>, scope_depth: i32) -> Self {
+        let breaks = Vec::new();
+        let continues = Vec::new();
+        Loop {
+            start,
+            scope_depth,
+            breaks,
+            continues,
+        }
+    }
+}
@@ -1,34 +1,51 @@
 #![allow(dead_code)]
 
 mod error;
+mod fold;
 mod locals;
+mod loops;
+mod peephole;
 
+use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::rc::Rc;
 
 
 use crate::chunk::Chunk;
+use crate::intern::Interner;
+use crate::object::{Function, Obj};
 use crate::op::*;
 use crate::scanner::TokenTag::*;
 use crate::scanner::{Scanner, Token, TokenTag};
 use crate::value::Value;
 
 use Precedence::*;
-use locals::Local;
+use fold::Mark;
+use locals::{Local, Upvalue};
+use loops::Loop;
 use error::*;
 
+pub use error::ParseError;
+
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 enum Precedence {
     Base,
+    Comma,
     Assignment,
-    //Or,
-    //And,
+    Conditional,
+    Or,
+    And,
     Equality,
     Comparison,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
     Term,
     Factor,
     Unary,
-    //Call,
+    Power,
+    Call,
     //Primary,
 }
 
@@ -37,31 +54,122 @@ fn precedence_of(token: &Token) -> Precedence {
 
     match token.tag {
         Minus | Plus => Term,
-        Slash | Star => Factor,
+        Slash | Star | Percent => Factor,
+        StarStar => Power,
         BangEqual | EqualEqual => Equality,
         Greater | GreaterEqual | Less | LessEqual => Comparison,
+        Pipe => BitOr,
+        Caret => BitXor,
+        Ampersand => BitAnd,
+        LessLess | GreaterGreater => Shift,
+        TokenTag::And => Precedence::And,
+        TokenTag::Or => Precedence::Or,
+        LeftParen | LeftBracket | Dot => Call,
+        Question => Conditional,
+        TokenTag::Comma => Precedence::Comma,
         _ => Base,
     }
 }
 
 type ParseResult = Result<(), ParseError>;
 
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    // A method named `init`, which returns the instance it initializes.
+    Initializer,
+}
+
+/// The compiler state of a function set aside while a function nested inside
+/// it is compiled.
+struct EnclosingFunction {
+    kind: FunctionKind,
+    locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
+    scope_depth: i32,
+    loops: Vec<Loop>,
+}
+
+/// The compiler state of a class whose body is being compiled.
+struct ClassCompiler {
+    has_superclass: bool,
+}
+
+/// Settings that change how source is compiled.
+#[derive(Clone, Copy, Default)]
+pub struct CompileOptions {
+    /// Print the value of an expression left without a semicolon at the end
+    /// of the source, as a calculator would.
+    pub repl: bool,
+    /// Make declaring a global that was already declared an error, as it
+    /// is for locals, rather than replacing it.
+    pub strict: bool,
+    /// Warn when a local has the same name as a variable in an enclosing
+    /// scope, or a global, which it hides.
+    pub warn_shadowing: bool,
+}
+
 struct Parser<'a> {
     scanner: Scanner<'a>,
     current: Rc<Token>,
     previous: Rc<Token>,
+    // The kind of function being compiled.
+    kind: FunctionKind,
     locals: Vec<Local>,
+    upvalues: Vec<Upvalue>,
     scope_depth: i32,
+    loops: Vec<Loop>,
+    enclosing: Vec<EnclosingFunction>,
+    // The class bodies enclosing the code being compiled, innermost last.
+    classes: Vec<ClassCompiler>,
+    // Names of the globals declared with `const`.
+    global_consts: HashSet<String>,
+    // The globals declared so far and the lines they were declared on.
+    declared_globals: HashMap<String, usize>,
+    warnings: Vec<ParseError>,
+    // For each global: its arity, if it's declared once, with `fun`, and
+    // never assigned to, or else None.
+    global_functions: HashMap<String, Option<usize>>,
+    // The global read by the last variable expression, if it was one.
+    last_global: Option<Rc<Token>>,
+    // Calls of globals by name, checked against `global_functions` once the
+    // whole source has been compiled.
+    global_calls: Vec<(Rc<Token>, usize)>,
+    // Where the last call compiled ends, so that a call ending a return
+    // value can be made a tail call.
+    last_call_end: Option<usize>,
+    // Identifier names, so that each is allocated only once.
+    strings: Interner,
+    options: CompileOptions,
 }
 
 const MAX_LOCALS: usize = 255;
 
+const MAX_UPVALUES: usize = 255;
+
+// Maximum number of parameters a function can declare, and so the maximum
+// number of arguments a call can pass.  The count must fit in a byte.
+const MAX_PARAMETERS: usize = 255;
+
+// The name given to functions declared without one.
+const ANONYMOUS_FUNCTION: &str = "anonymous";
+
+// Maximum number of items in a list literal.  The count must fit in a byte.
+const MAX_LIST_ITEMS: usize = 255;
+
+// Maximum number of entries in a map literal.  The count must fit in a byte.
+const MAX_MAP_ENTRIES: usize = 255;
+
 impl<'a> Parser<'a> {
-    fn new(source: &str) -> Parser {
+    fn new(source: &str, options: CompileOptions) -> Parser<'_> {
         let token = Token {
             tag: Eof,
             lexeme: String::from(""),
             line: 0,
+            column: 0,
+            source_line: 0,
         };
         let token = Rc::new(token);
 
@@ -69,8 +177,85 @@ impl<'a> Parser<'a> {
             scanner: Scanner::new(source),
             current: Rc::clone(&token),
             previous: Rc::clone(&token),
+            kind: FunctionKind::Script,
             locals: Vec::with_capacity(MAX_LOCALS),
+            upvalues: Vec::new(),
             scope_depth: 0,
+            loops: Vec::new(),
+            enclosing: Vec::new(),
+            classes: Vec::new(),
+            global_consts: HashSet::new(),
+            declared_globals: HashMap::new(),
+            warnings: Vec::new(),
+            global_functions: HashMap::new(),
+            last_global: None,
+            global_calls: Vec::new(),
+            last_call_end: None,
+            strings: Interner::default(),
+            options,
+        }
+    }
+
+    /// Sets the current function's state aside to compile a nested function.
+    fn begin_function(&mut self, kind: FunctionKind) {
+        let enclosing = EnclosingFunction {
+            kind: mem::replace(&mut self.kind, kind),
+            locals: mem::replace(&mut self.locals, Vec::with_capacity(MAX_LOCALS)),
+            upvalues: mem::take(&mut self.upvalues),
+            scope_depth: mem::replace(&mut self.scope_depth, 0),
+            loops: mem::take(&mut self.loops),
+        };
+        self.enclosing.push(enclosing);
+
+        // Slot zero holds the function being called, or the receiver in the
+        // case of a method.
+        let lexeme = match kind {
+            FunctionKind::Script | FunctionKind::Function => "",
+            FunctionKind::Method | FunctionKind::Initializer => "this",
+        };
+        let mut callee = Local::new(&self.synthetic_token(lexeme));
+        callee.depth = 0;
+        self.locals.push(callee);
+    }
+
+    /// Makes an identifier that isn't in the source, at the previous token.
+    fn synthetic_token(&self, lexeme: &str) -> Rc<Token> {
+        let token = Token {
+            tag: Identifier,
+            lexeme: String::from(lexeme),
+            line: self.previous.line,
+            column: self.previous.column,
+            source_line: self.previous.source_line,
+        };
+        Rc::new(token)
+    }
+
+    /// Restores the state of the function enclosing the one just compiled.
+    /// Returns the variables the compiled function captures.
+    fn end_function(&mut self) -> Vec<Upvalue> {
+        let enclosing = self.enclosing.pop().expect("end_function without begin_function");
+        self.kind = enclosing.kind;
+        self.locals = enclosing.locals;
+        self.scope_depth = enclosing.scope_depth;
+        self.loops = enclosing.loops;
+        mem::replace(&mut self.upvalues, enclosing.upvalues)
+    }
+
+    /// The locals of the function `level` functions deep, where the script is
+    /// level zero and the function being compiled is the deepest.
+    fn locals_at(&mut self, level: usize) -> &mut Vec<Local> {
+        if level == self.enclosing.len() {
+            &mut self.locals
+        } else {
+            &mut self.enclosing[level].locals
+        }
+    }
+
+    fn upvalues_at(&mut self, level: usize) -> &mut Vec<Upvalue> {
+        if level == self.enclosing.len() {
+            &mut self.upvalues
+        } else {
+            &mut self.enclosing[level].upvalues
         }
     }
 
@@ -78,12 +263,45 @@ impl<'a> Parser<'a> {
         self.scope_depth += 1;
     }
 
+    /// Starts a loop whose body may `break` out of it or `continue` back to
+    /// `start`.  Without a start, `continue` jumps forward to wherever
+    /// `patch_continues` is called.
+    fn begin_loop(&mut self, start: Option<usize>) {
+        self.loops.push(Loop::new(start, self.scope_depth));
+    }
+
+    /// Ends the innermost loop, patching any `break` jumps to land here.
+    fn end_loop(&mut self, chunk: &mut Chunk, token: &Rc<Token>) -> ParseResult {
+        let innermost = self.loops.pop().expect("end_loop without begin_loop");
+        for offset in innermost.breaks {
+            chunk
+                .patch_jump(offset)
+                .or_else(|e| parse_error(token, &e))?;
+        }
+        Ok(())
+    }
+
+    /// Patches the innermost loop's forward `continue` jumps to land here.
+    fn patch_continues(&mut self, chunk: &mut Chunk, token: &Rc<Token>) -> ParseResult {
+        let innermost = self.loops.last_mut().expect("patch_continues without begin_loop");
+        for offset in mem::take(&mut innermost.continues) {
+            chunk
+                .patch_jump(offset)
+                .or_else(|e| parse_error(token, &e))?;
+        }
+        Ok(())
+    }
+
     fn end_scope(&mut self, chunk: &mut Chunk) {
         self.scope_depth -= 1;
 
-        while self.locals.len() > 0 && self.locals[self.locals.len()-1].depth > self.scope_depth {
-            chunk.emit(OP_POP, self.previous.line);
-            self.locals.pop();
+        while !self.locals.is_empty() && self.locals[self.locals.len()-1].depth > self.scope_depth {
+            let local = self.locals.pop().unwrap();
+            if local.is_captured {
+                chunk.emit(OP_CLOSE_UPVALUE, self.previous.line);
+            } else {
+                chunk.emit(OP_POP, self.previous.line);
+            }
         }
     }
 
@@ -91,7 +309,8 @@ impl<'a> Parser<'a> {
         let token = self.scanner.next_token();
         let token = Rc::new(token);
         if token.tag == Error {
-            parse_error(&token, "error advancing")
+            // Error tokens carry the scanner's message in their lexeme.
+            parse_error(&token, &token.lexeme)
         } else {
             self.previous = mem::replace(&mut self.current, token);
             Ok(())
@@ -107,7 +326,7 @@ impl<'a> Parser<'a> {
             return Ok(false);
         }
         self.advance()?;
-        return Ok(true);
+        Ok(true)
     }
 
     fn consume(&mut self, tag: TokenTag, msg: &str) -> ParseResult {
@@ -133,34 +352,90 @@ impl<'a> Parser<'a> {
                 let token = Rc::clone(&self.previous);
                 self.named_variable(chunk, &token, can_assign)?;
             }
+            This => {
+                if self.classes.is_empty() {
+                    return parse_error(&self.previous, "Can't use 'this' outside of a class.");
+                }
+                let token = Rc::clone(&self.previous);
+                self.named_variable(chunk, &token, false)?;
+            }
+            Super => {
+                let super_token = Rc::clone(&self.previous);
+                self.consume(Dot, "Expect '.' after 'super'.")?;
+                self.consume(Identifier, "Expect superclass method name.")?;
+
+                match self.classes.last() {
+                    None => return parse_error(&super_token, "Can't use 'super' outside of a class."),
+                    Some(class) if !class.has_superclass => {
+                        return parse_error(&super_token, "Can't use 'super' in a class with no superclass.");
+                    }
+                    Some(_) => {}
+                }
+                let name = identifier_constant(chunk, &mut self.strings, &self.previous)?;
+
+                // Bind the superclass's method to `this`.
+                let this_token = self.synthetic_token("this");
+                self.named_variable(chunk, &this_token, false)?;
+                self.named_variable(chunk, &super_token, false)?;
+                chunk.emit(OP_GET_SUPER, self.previous.line);
+                chunk.emit(name, self.previous.line);
+            }
             StringLiteral => {
-                // The string is in the lexeme. We need to trim the leading and
-                // trailing quotes.
-                let s = &self.previous.lexeme;
-                let s = &s[0..s.len()];
-                let s = Value::new_string(s);
+                // The scanner has already dropped the quotes and interpreted
+                // any escapes, so the lexeme is the string's contents.
+                let s = Value::new_string(&self.previous.lexeme);
 
                 chunk
                     .emit_constant(s, self.previous.line)
                     .or_else(|e| parse_error(&self.previous, &e))?;
             }
             Number => {
-                let x: f64 = self
-                    .previous
-                    .lexeme
-                    .parse()
-                    .or_else(|_| parse_error(&self.previous, "Cannot parse number"))?;
-
-                let x = Value::Number(x);
+                // Literals without a fraction or exponent are integers.
+                let lexeme = &self.previous.lexeme;
+                let x = if lexeme.contains(['.', 'e']) && !lexeme.starts_with("0x") {
+                    lexeme.parse().map(Value::Number).ok()
+                } else {
+                    let parsed = match lexeme.strip_prefix("0x") {
+                        Some(hex) => i64::from_str_radix(hex, 16),
+                        None => lexeme.parse(),
+                    };
+                    match parsed {
+                        Ok(x) => Some(Value::Int(x)),
+                        Err(_) => return parse_error(&self.previous, "Integer literal too large."),
+                    }
+                }
+                .map_or_else(|| parse_error(&self.previous, "Cannot parse number"), Ok)?;
 
                 chunk
                     .emit_constant(x, self.previous.line)
                     .or_else(|e| parse_error(&self.previous, &e))?;
             }
             LeftParen => {
-                self.parse(Assignment, chunk)?;
+                // An empty group is an error rather than nil.
+                if self.check(RightParen) {
+                    return parse_error(&self.current, "Expect expression inside parentheses.");
+                }
+                self.comma_expression(chunk)?;
                 self.consume(RightParen, "Expect ')' after expression.")?;
             }
+            Fun => {
+                // An anonymous function.  At the start of a statement `fun`
+                // declares a named function instead.
+                let name = self.synthetic_token(ANONYMOUS_FUNCTION);
+                self.function(chunk, &name, FunctionKind::Function)?;
+            }
+            LeftBracket => {
+                let item_count = self.list_items(chunk)?;
+                chunk.emit(OP_LIST, self.previous.line);
+                chunk.emit(item_count, self.previous.line);
+            }
+            LeftBrace => {
+                // A map literal.  At the start of a statement `{` begins a
+                // block instead.
+                let entry_count = self.map_entries(chunk)?;
+                chunk.emit(OP_MAP, self.previous.line);
+                chunk.emit(entry_count, self.previous.line);
+            }
             Minus => {
                 self.parse(Factor, chunk)?;
                 chunk.emit(OP_NEGATE, self.previous.line);
@@ -169,6 +444,12 @@ impl<'a> Parser<'a> {
                 self.parse(Factor, chunk)?;
                 chunk.emit(OP_NOT, self.previous.line);
             }
+            Dot if self.current.tag == Number => {
+                return parse_error(&self.previous, "Expect a digit before '.' in a number, as in 0.5.");
+            }
+            Dot => {
+                return parse_error(&self.previous, "Expect an expression before '.' to access a property of.");
+            }
             _ => {
                 parse_error(&self.previous, "unexpected token")?;
             }
@@ -177,7 +458,9 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
-    fn infix_rule(&mut self, chunk: &mut Chunk) -> ParseResult {
+    /// Compiles the operator just consumed and its right operand.  `left`
+    /// marks where the code for the left operand starts.
+    fn infix_rule(&mut self, chunk: &mut Chunk, can_assign: bool, left: Mark) -> ParseResult {
         let line = self.previous.line;
 
         match self.previous.tag {
@@ -212,21 +495,125 @@ impl<'a> Parser<'a> {
                 chunk.emit(OP_GREATER, line);
                 chunk.emit(OP_NOT, line);
             }
-            Plus => {
-                self.parse(Factor, chunk)?;
-                chunk.emit(OP_ADD, line);
+            Pipe => {
+                self.parse(BitXor, chunk)?;
+                chunk.emit(OP_BIT_OR, line);
             }
-            Minus => {
-                self.parse(Factor, chunk)?;
-                chunk.emit(OP_SUBTRACT, line);
+            Caret => {
+                self.parse(BitAnd, chunk)?;
+                chunk.emit(OP_BIT_XOR, line);
             }
-            Star => {
-                self.parse(Unary, chunk)?;
-                chunk.emit(OP_MULTIPLY, line);
+            Ampersand => {
+                self.parse(Shift, chunk)?;
+                chunk.emit(OP_BIT_AND, line);
+            }
+            LessLess => {
+                self.parse(Term, chunk)?;
+                chunk.emit(OP_SHIFT_LEFT, line);
             }
-            Slash => {
+            GreaterGreater => {
+                self.parse(Term, chunk)?;
+                chunk.emit(OP_SHIFT_RIGHT, line);
+            }
+            Plus => self.arithmetic(chunk, OP_ADD, Factor, left, line)?,
+            Minus => self.arithmetic(chunk, OP_SUBTRACT, Factor, left, line)?,
+            Star => self.arithmetic(chunk, OP_MULTIPLY, Unary, left, line)?,
+            Slash => self.arithmetic(chunk, OP_DIVIDE, Unary, left, line)?,
+            Percent => {
                 self.parse(Unary, chunk)?;
-                chunk.emit(OP_DIVIDE, line);
+                chunk.emit(OP_MODULO, line);
+            }
+            StarStar => {
+                // Parsing the right operand at the same level makes `**`
+                // associate to the right.
+                self.parse(Power, chunk)?;
+                chunk.emit(OP_POWER, line);
+            }
+            TokenTag::And => {
+                // If the left operand is falsey it's the result, so skip the
+                // right operand entirely.
+                let end_jump = chunk.emit_jump(OP_JUMP_IF_FALSE, line);
+                chunk.emit(OP_POP, line);
+                self.parse(Equality, chunk)?;
+                chunk
+                    .patch_jump(end_jump)
+                    .or_else(|e| parse_error(&self.previous, &e))?;
+            }
+            TokenTag::Or => {
+                // There's no OP_JUMP_IF_TRUE.  Instead a falsey left operand
+                // jumps over the OP_JUMP that would skip the right operand.
+                let else_jump = chunk.emit_jump(OP_JUMP_IF_FALSE, line);
+                let end_jump = chunk.emit_jump(OP_JUMP, line);
+                chunk
+                    .patch_jump(else_jump)
+                    .or_else(|e| parse_error(&self.previous, &e))?;
+                chunk.emit(OP_POP, line);
+                self.parse(Precedence::And, chunk)?;
+                chunk
+                    .patch_jump(end_jump)
+                    .or_else(|e| parse_error(&self.previous, &e))?;
+            }
+            TokenTag::Comma => {
+                // Discard the left operand; the right one is the result.
+                chunk.emit(OP_POP, line);
+                self.parse(Assignment, chunk)?;
+            }
+            Question => {
+                // Both branches parse at this same level so that nested
+                // conditionals associate to the right.
+                let else_jump = chunk.emit_jump(OP_JUMP_IF_FALSE, line);
+                chunk.emit(OP_POP, line);
+                self.parse(Conditional, chunk)?;
+                self.consume(Colon, "Expect ':' after then branch of conditional.")?;
+
+                let end_jump = chunk.emit_jump(OP_JUMP, line);
+                chunk
+                    .patch_jump(else_jump)
+                    .or_else(|e| parse_error(&self.previous, &e))?;
+                chunk.emit(OP_POP, line);
+                self.parse(Conditional, chunk)?;
+                chunk
+                    .patch_jump(end_jump)
+                    .or_else(|e| parse_error(&self.previous, &e))?;
+            }
+            LeftParen => {
+                // A call of a global by name can be checked against the
+                // function declared with that name, if there is one.
+                let callee = match chunk.code.get(left.code_len..) {
+                    Some([OP_GET_GLOBAL, _]) => self.last_global.take(),
+                    _ => None,
+                };
+                let arg_count = self.argument_list(chunk)?;
+                if let Some(callee) = callee {
+                    self.global_calls.push((callee, arg_count as usize));
+                }
+                chunk.emit(OP_CALL, line);
+                chunk.emit(arg_count, line);
+                self.last_call_end = Some(chunk.code.len());
+            }
+            LeftBracket => {
+                self.expression(chunk)?;
+                self.consume(RightBracket, "Expect ']' after index.")?;
+
+                if can_assign && self.matches(Equal)? {
+                    self.expression(chunk)?;
+                    chunk.emit(OP_SET_INDEX, line);
+                } else {
+                    chunk.emit(OP_GET_INDEX, line);
+                }
+            }
+            Dot => {
+                self.consume(Identifier, "Expect property name after '.'.")?;
+                let name = identifier_constant(chunk, &mut self.strings, &self.previous)?;
+
+                if can_assign && self.matches(Equal)? {
+                    self.expression(chunk)?;
+                    chunk.emit(OP_SET_PROPERTY, line);
+                    chunk.emit(name, line);
+                } else {
+                    chunk.emit(OP_GET_PROPERTY, line);
+                    chunk.emit(name, line);
+                }
             }
             _ => {
                 parse_error(&self.previous, "expected operator")?;
@@ -236,18 +623,43 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// Compiles an arithmetic operator.  When both operands turn out to be
+    /// number literals the result is worked out now and loaded as a constant
+    /// in place of both.
+    fn arithmetic(&mut self, chunk: &mut Chunk, op: u8, precedence: Precedence, left: Mark, line: usize) -> ParseResult {
+        let right_start = chunk.code.len();
+        self.parse(precedence, chunk)?;
+
+        let a = fold::constant_at(chunk, left.code_len, right_start);
+        let b = fold::constant_at(chunk, right_start, chunk.code.len());
+        match a.zip(b).and_then(|(a, b)| fold::fold(op, a, b)) {
+            Some(value) => {
+                chunk.truncate(left.code_len, left.constants_len);
+                chunk
+                    .emit_constant(value, line)
+                    .or_else(|e| parse_error(&self.previous, &e))?;
+            }
+            None => chunk.emit(op, line),
+        }
+
+        Ok(())
+    }
+
     fn parse(&mut self, precedence: Precedence, chunk: &mut Chunk) -> ParseResult {
         self.advance()?;
 
         let can_assign = precedence <= Precedence::Assignment;
+        let start = Mark::new(chunk);
         self.prefix_rule(chunk, can_assign)?;
 
         while precedence <= precedence_of(&self.current) {
             self.advance()?;
-            self.infix_rule(chunk)?;
+            self.infix_rule(chunk, can_assign, start)?;
         }
 
-        if can_assign && self.matches(Equal)? {
+        let assigns = matches!(self.current.tag, Equal | PlusPlus | MinusMinus);
+        if can_assign && assigns {
+            self.advance()?;
             return parse_error(&self.previous, "Invalid assignment target.");
         }
 
@@ -258,9 +670,86 @@ impl<'a> Parser<'a> {
         self.parse(Precedence::Assignment, chunk)
     }
 
+    /// Parses an expression that may use the comma operator.  Elsewhere a
+    /// comma separates arguments or list items.
+    fn comma_expression(&mut self, chunk: &mut Chunk) -> ParseResult {
+        self.parse(Precedence::Comma, chunk)
+    }
+
+    fn argument_list(&mut self, chunk: &mut Chunk) -> Result<u8, ParseError> {
+        let mut arg_count = 0;
+        if !self.check(RightParen) {
+            loop {
+                self.expression(chunk)?;
+                if arg_count == MAX_PARAMETERS {
+                    return parse_error(&self.previous, "Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                // A comma may follow the last one.
+                if !self.matches(TokenTag::Comma)? || self.check(RightParen) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightParen, "Expect ')' after arguments.")?;
+
+        Ok(arg_count as u8)
+    }
+
+    fn list_items(&mut self, chunk: &mut Chunk) -> Result<u8, ParseError> {
+        let mut item_count = 0;
+        if !self.check(RightBracket) {
+            loop {
+                self.expression(chunk)?;
+                if item_count == MAX_LIST_ITEMS {
+                    return parse_error(&self.previous, "Can't have more than 255 items in a list literal.");
+                }
+                item_count += 1;
+
+                // A comma may follow the last one.
+                if !self.matches(TokenTag::Comma)? || self.check(RightBracket) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightBracket, "Expect ']' after list items.")?;
+
+        Ok(item_count as u8)
+    }
+
+    fn map_entries(&mut self, chunk: &mut Chunk) -> Result<u8, ParseError> {
+        let mut entry_count = 0;
+        if !self.check(RightBrace) {
+            loop {
+                self.expression(chunk)?;
+                self.consume(Colon, "Expect ':' after map key.")?;
+                self.expression(chunk)?;
+                if entry_count == MAX_MAP_ENTRIES {
+                    return parse_error(&self.previous, "Can't have more than 255 entries in a map literal.");
+                }
+                entry_count += 1;
+
+                // A comma may follow the last one.
+                if !self.matches(TokenTag::Comma)? || self.check(RightBrace) {
+                    break;
+                }
+            }
+        }
+        self.consume(RightBrace, "Expect '}' after map entries.")?;
+
+        Ok(entry_count as u8)
+    }
+
     fn declaration(&mut self, chunk: &mut Chunk) -> ParseResult {
-        if self.matches(Var)? {
+        if self.matches(Class)? {
+            self.class_declaration(chunk)
+        } else if self.matches(Fun)? {
+            self.fun_declaration(chunk)
+        } else if self.matches(Var)? {
             self.var_declaration(chunk)
+        } else if self.matches(Const)? {
+            self.const_declaration(chunk)
         } else {
             self.statement(chunk)
         }
@@ -268,27 +757,280 @@ impl<'a> Parser<'a> {
 
     fn named_variable(&mut self, chunk: &mut Chunk, token: &Rc<Token>, can_assign: bool) -> ParseResult {
 
+        let level = self.enclosing.len();
         let kind = if let Some(arg) = self.resolve_local(token)? {
-            (arg, OP_GET_LOCAL, OP_SET_LOCAL)
+            let is_const = self.locals[arg as usize].is_const;
+            (arg, OP_GET_LOCAL, OP_SET_LOCAL, is_const)
+        } else if let Some(arg) = self.resolve_upvalue(level, token)? {
+            let is_const = self.upvalues[arg as usize].is_const;
+            (arg, OP_GET_UPVALUE, OP_SET_UPVALUE, is_const)
         } else {
-            let arg = identifier_constant(chunk, token)?;
-            (arg, OP_GET_GLOBAL, OP_SET_GLOBAL)
+            let arg = identifier_constant(chunk, &mut self.strings, token)?;
+            let is_const = self.global_consts.contains(&token.lexeme);
+            (arg, OP_GET_GLOBAL, OP_SET_GLOBAL, is_const)
         };
 
-        let (arg, get_op, set_op) = kind;
+        let (arg, get_op, set_op, is_const) = kind;
 
         if can_assign && self.matches(Equal)? {
             self.expression(chunk)?;
+            if is_const {
+                return const_assignment_error(token);
+            }
+            chunk.emit(set_op, token.line);
+            chunk.emit(arg, token.line);
+            self.assigned(set_op, token);
+        } else if let Some(op) = self.compound_assignment(can_assign)? {
+            chunk.emit(get_op, token.line);
+            chunk.emit(arg, token.line);
+            self.expression(chunk)?;
+            if is_const {
+                return const_assignment_error(token);
+            }
+            chunk.emit(op, token.line);
             chunk.emit(set_op, token.line);
             chunk.emit(arg, token.line);
+            self.assigned(set_op, token);
+        } else if let Some(op) = self.increment(can_assign)? {
+            // Like `x += 1`, this gives the variable's new value.
+            if is_const {
+                return const_assignment_error(token);
+            }
+            chunk.emit(get_op, token.line);
+            chunk.emit(arg, token.line);
+            chunk
+                .emit_constant(Value::Int(1), token.line)
+                .or_else(|e| parse_error(token, &e))?;
+            chunk.emit(op, token.line);
+            chunk.emit(set_op, token.line);
+            chunk.emit(arg, token.line);
+            self.assigned(set_op, token);
         } else {
             chunk.emit(get_op, token.line);
             chunk.emit(arg, token.line);
+            if get_op == OP_GET_GLOBAL {
+                self.last_global = Some(Rc::clone(token));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Notes an assignment to a variable.  A global that's assigned to may
+    /// no longer hold the function it was declared as.
+    fn assigned(&mut self, set_op: u8, token: &Rc<Token>) {
+        if set_op == OP_SET_GLOBAL {
+            self.global_functions.insert(token.lexeme.clone(), None);
+        }
+    }
+
+    /// Checks each call of a global by name against the function declared
+    /// with that name, now that all the declarations have been seen.
+    fn check_global_calls(&self) -> Vec<ParseError> {
+        self.global_calls
+            .iter()
+            .filter_map(|(callee, arg_count)| {
+                let arity = self.global_functions.get(&callee.lexeme).copied().flatten()?;
+                if arity == *arg_count {
+                    return None;
+                }
+                let message = format!("Expected {} arguments but got {}.", arity, arg_count);
+                parse_error::<()>(callee, &message).err()
+            })
+            .collect()
+    }
+
+    /// Matches a compound assignment operator such as `+=`.  Returns the
+    /// arithmetic instruction it applies.
+    fn compound_assignment(&mut self, can_assign: bool) -> Result<Option<u8>, ParseError> {
+        if !can_assign {
+            return Ok(None);
+        }
+
+        let op = match self.current.tag {
+            PlusEqual => OP_ADD,
+            MinusEqual => OP_SUBTRACT,
+            StarEqual => OP_MULTIPLY,
+            SlashEqual => OP_DIVIDE,
+            _ => return Ok(None),
+        };
+        self.advance()?;
+
+        Ok(Some(op))
+    }
+
+    /// Matches a `++` or `--` after a variable.  Returns the operator that
+    /// updates the variable.
+    fn increment(&mut self, can_assign: bool) -> Result<Option<u8>, ParseError> {
+        if !can_assign {
+            return Ok(None);
+        }
+
+        let op = match self.current.tag {
+            PlusPlus => OP_ADD,
+            MinusMinus => OP_SUBTRACT,
+            _ => return Ok(None),
+        };
+        self.advance()?;
+
+        Ok(Some(op))
+    }
+
+    fn class_declaration(&mut self, chunk: &mut Chunk) -> ParseResult {
+        self.consume(Identifier, "Expect class name.")?;
+        let name = Rc::clone(&self.previous);
+        let name_constant = identifier_constant(chunk, &mut self.strings, &name)?;
+        self.declare_variable()?;
+
+        chunk.emit(OP_CLASS, name.line);
+        chunk.emit(name_constant, name.line);
+        self.define_variable(chunk, name.line, name_constant);
+
+        let has_superclass = self.matches(Less)?;
+        if has_superclass {
+            self.consume(Identifier, "Expect superclass name.")?;
+            let superclass = Rc::clone(&self.previous);
+            if superclass.lexeme == name.lexeme {
+                return parse_error(&superclass, "A class can't inherit from itself.");
+            }
+            self.named_variable(chunk, &superclass, false)?;
+
+            // The superclass stays on the stack as a local named `super`,
+            // for methods to capture.
+            self.begin_scope();
+            let super_token = self.synthetic_token("super");
+            self.add_local(&super_token)?;
+            self.mark_initialized();
+
+            self.named_variable(chunk, &name, false)?;
+            chunk.emit(OP_INHERIT, superclass.line);
+        }
+
+        // Keep the class on the stack while its methods are added to it.
+        self.named_variable(chunk, &name, false)?;
+
+        self.classes.push(ClassCompiler { has_superclass });
+        let body = self.class_body(chunk);
+        self.classes.pop();
+        body?;
+
+        chunk.emit(OP_POP, self.previous.line);
+
+        if has_superclass {
+            self.end_scope(chunk);
+        }
+
+        Ok(())
+    }
+
+    fn class_body(&mut self, chunk: &mut Chunk) -> ParseResult {
+        self.consume(LeftBrace, "Expect '{' before class body.")?;
+        while !self.check(RightBrace) && !self.check(Eof) {
+            self.method(chunk)?;
+        }
+        self.consume(RightBrace, "Expect '}' after class body.")
+    }
+
+    fn method(&mut self, chunk: &mut Chunk) -> ParseResult {
+        self.consume(Identifier, "Expect method name.")?;
+        let name = Rc::clone(&self.previous);
+        let name_constant = identifier_constant(chunk, &mut self.strings, &name)?;
+
+        let kind = if name.lexeme == "init" {
+            FunctionKind::Initializer
+        } else {
+            FunctionKind::Method
+        };
+        self.function(chunk, &name, kind)?;
+        chunk.emit(OP_METHOD, name.line);
+        chunk.emit(name_constant, name.line);
+
+        Ok(())
+    }
+
+    fn fun_declaration(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let redeclared = self.global_functions.contains_key(&self.current.lexeme);
+        let global = self.parse_variable(chunk, "Expect function name.")?;
+        let name = Rc::clone(&self.previous);
+
+        // A function may refer to itself, so it's usable before its body is
+        // compiled.
+        if self.scope_depth > 0 {
+            self.mark_initialized();
+        }
+
+        let arity = self.function(chunk, &name, FunctionKind::Function)?;
+        self.define_variable(chunk, name.line, global);
+
+        if self.scope_depth == 0 && !redeclared {
+            self.global_functions.insert(name.lexeme.clone(), Some(arity));
         }
 
         Ok(())
     }
 
+    /// Compiles a function's parameters and body into a chunk of its own, and
+    /// emits the resulting function as a constant.  Returns its arity.
+    fn function(&mut self, chunk: &mut Chunk, name: &Rc<Token>, kind: FunctionKind) -> Result<usize, ParseError> {
+        let mut function_chunk = Chunk::new();
+
+        self.begin_function(kind);
+        let arity = self.function_body(&mut function_chunk);
+        let upvalues = self.end_function();
+        // A call at the end of the body isn't at the end of this chunk.
+        self.last_call_end = None;
+        let arity = arity?;
+        peephole::optimize(&mut function_chunk);
+
+        let function = Function::new(&name.lexeme, arity, upvalues.len(), function_chunk);
+        let function = Value::Obj(Obj::new_function(function));
+        let index = chunk
+            .add_short_constant(function)
+            .or_else(|e| parse_error(name, &e))?;
+
+        // The closure is followed by where to find each captured variable.
+        let line = self.previous.line;
+        chunk.emit(OP_CLOSURE, line);
+        chunk.emit(index, line);
+        for upvalue in upvalues {
+            chunk.emit(upvalue.is_local as u8, line);
+            chunk.emit(upvalue.index, line);
+        }
+
+        Ok(arity)
+    }
+
+    /// Compiles the parameter list and body of a function.  Returns the
+    /// function's arity.
+    fn function_body(&mut self, chunk: &mut Chunk) -> Result<usize, ParseError> {
+        self.begin_scope();
+
+        let mut arity = 0;
+        self.consume(LeftParen, "Expect '(' after function name.")?;
+        if !self.check(RightParen) {
+            loop {
+                if arity == MAX_PARAMETERS {
+                    return parse_error(&self.current, "Can't have more than 255 parameters.");
+                }
+                arity += 1;
+
+                self.parse_variable(chunk, "Expect parameter name.")?;
+                self.mark_initialized();
+
+                if !self.matches(TokenTag::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.consume(RightParen, "Expect ')' after parameters.")?;
+        self.consume(LeftBrace, "Expect '{' before function body.")?;
+        self.block(chunk)?;
+
+        self.emit_return(chunk, self.previous.line);
+
+        Ok(arity)
+    }
+
     fn var_declaration(&mut self, chunk: &mut Chunk) -> ParseResult {
         let global = self.parse_variable(chunk, "Expected variable name")?;
 
@@ -307,6 +1049,30 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    fn const_declaration(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let global = self.parse_variable(chunk, "Expect constant name.")?;
+        let name = Rc::clone(&self.previous);
+
+        let line = self.previous.line;
+
+        self.consume(Equal, "Expect '=' after constant name.")?;
+        self.expression(chunk)?;
+        self.consume(Semicolon, "Expect ';' after constant declaration.")?;
+
+        if self.scope_depth > 0 {
+            let last_offset = self.locals.len() - 1;
+            self.locals[last_offset].is_const = true;
+            self.mark_initialized();
+        } else {
+            // The VM also keeps track, for code compiled later.
+            self.global_consts.insert(name.lexeme.clone());
+            chunk.emit(OP_DEFINE_CONST_GLOBAL, line);
+            chunk.emit(global, line);
+        }
+
+        Ok(())
+    }
+
     fn define_variable(&mut self, chunk: &mut Chunk, line: usize, global: u8) {
         if self.scope_depth == 0 {
             chunk.emit(OP_DEFINE_GLOBAL, line);
@@ -323,7 +1089,7 @@ impl<'a> Parser<'a> {
         if self.scope_depth > 0 {
             Ok(0)
         } else {
-            identifier_constant(chunk, &self.previous)
+            identifier_constant(chunk, &mut self.strings, &self.previous)
         }
     }
 
@@ -336,6 +1102,23 @@ impl<'a> Parser<'a> {
         if self.scope_depth > 0 {
             let name = Rc::clone(&self.previous);
             self.add_local(&name)?;
+            if self.options.warn_shadowing {
+                self.check_shadowing(&name);
+            }
+        } else {
+            let name = Rc::clone(&self.previous);
+            if self.global_consts.contains(&name.lexeme) {
+                let message = format!("Cannot redeclare constant '{}'.", name.lexeme);
+                return parse_error(&name, &message);
+            }
+            if self.options.strict && self.declared_globals.contains_key(&name.lexeme) {
+                let message = format!("Already a variable named '{}' in this scope.", name.lexeme);
+                return parse_error(&name, &message);
+            }
+            self.declared_globals.insert(name.lexeme.clone(), name.line);
+
+            // Redeclaring a global replaces it.
+            self.global_functions.insert(name.lexeme.clone(), None);
         }
 
         Ok(())
@@ -343,11 +1126,20 @@ impl<'a> Parser<'a> {
 
     fn add_local(&mut self, name: &Rc<Token>) -> ParseResult {
         if self.locals.len() >= MAX_LOCALS {
-            return parse_error(name, "Exceeded maximum number of local variables.");
+            // A function's first slot holds the function itself, or `this`.
+            let (owner, limit) = match self.kind {
+                FunctionKind::Script => ("the script", MAX_LOCALS),
+                _ => ("a function", MAX_LOCALS - 1),
+            };
+            let message = format!(
+                "Can't declare '{}': {} can have at most {} local variables.",
+                name.lexeme, owner, limit
+            );
+            return parse_error(name, &message);
         }
 
         for local in self.locals.iter().rev() {
-            if local.depth == -1 && local.depth < self.scope_depth {
+            if local.depth != -1 && local.depth < self.scope_depth {
                 break;
             }
 
@@ -361,17 +1153,68 @@ impl<'a> Parser<'a> {
         Ok(())
     }
 
+    /// Warns if the local just declared as `name` hides a variable that code
+    /// in its scope could otherwise use.
+    fn check_shadowing(&mut self, name: &Rc<Token>) {
+        let outer_locals = self.enclosing.iter().rev().flat_map(|function| function.locals.iter().rev());
+        let line = self
+            .locals
+            .iter()
+            .rev()
+            .skip(1)
+            .chain(outer_locals)
+            .find(|local| local.name.lexeme == name.lexeme)
+            .map(|local| local.name.line)
+            .or_else(|| self.declared_globals.get(&name.lexeme).copied());
+
+        if let Some(line) = line {
+            let message = format!("'{}' shadows the variable declared on line {}.", name.lexeme, line);
+            self.warnings.push(error::warning(name, message));
+        }
+    }
+
     fn resolve_local(&mut self, name: &Rc<Token>) -> Result<Option<u8>, ParseError> {
-        for (i, local) in self.locals.iter().enumerate().rev() {
-            if local.name.lexeme == name.lexeme {
-                if local.depth == -1 {
-                    return parse_error(name, "Cannot read local variable in its own initializer.");
-                }
-                return Ok(Some(i as u8));
-            }
+        resolve_local_in(&self.locals, name)
+    }
+
+    /// Resolves `name` as a variable that the function at `level` captures
+    /// from the functions enclosing it.  Returns the upvalue's index.
+    fn resolve_upvalue(&mut self, level: usize, name: &Rc<Token>) -> Result<Option<u8>, ParseError> {
+        if level == 0 {
+            return Ok(None);
+        }
+        let enclosing = level - 1;
+
+        if let Some(index) = resolve_local_in(self.locals_at(enclosing), name)? {
+            let local = &mut self.locals_at(enclosing)[index as usize];
+            local.is_captured = true;
+            let is_const = local.is_const;
+            let upvalue = Upvalue { index, is_local: true, is_const };
+            return self.add_upvalue(level, upvalue, name).map(Some);
+        }
+
+        if let Some(index) = self.resolve_upvalue(enclosing, name)? {
+            let is_const = self.upvalues_at(enclosing)[index as usize].is_const;
+            let upvalue = Upvalue { index, is_local: false, is_const };
+            return self.add_upvalue(level, upvalue, name).map(Some);
+        }
+
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, level: usize, upvalue: Upvalue, name: &Rc<Token>) -> Result<u8, ParseError> {
+        let upvalues = self.upvalues_at(level);
+
+        if let Some(index) = upvalues.iter().position(|&u| u == upvalue) {
+            return Ok(index as u8);
+        }
+
+        if upvalues.len() >= MAX_UPVALUES {
+            return parse_error(name, "Too many closure variables in function.");
         }
 
-        return Ok(None);
+        upvalues.push(upvalue);
+        Ok((upvalues.len() - 1) as u8)
     }
 
     fn synchronize(&mut self) {
@@ -381,7 +1224,7 @@ impl<'a> Parser<'a> {
             }
 
             match self.current.tag {
-                Class | Fun | Var | For | If | While | Print | Return => {
+                Class | Const | Fun | Var | Do | For | If | Switch | While | Print | Return => {
                     return;
                 }
                 _ => {
@@ -399,6 +1242,26 @@ impl<'a> Parser<'a> {
             self.print_statement(chunk)
         } else if self.matches(If)? {
             self.if_statement(chunk)
+        } else if self.matches(While)? {
+            self.while_statement(chunk)
+        } else if self.matches(Do)? {
+            self.do_statement(chunk)
+        } else if self.matches(Return)? {
+            self.return_statement(chunk)
+        } else if self.matches(Break)? {
+            self.break_statement(chunk)
+        } else if self.matches(Continue)? {
+            self.continue_statement(chunk)
+        } else if self.matches(For)? {
+            self.begin_scope();
+            let result = self.for_statement(chunk);
+            self.end_scope(chunk);
+            result
+        } else if self.matches(Switch)? {
+            self.begin_scope();
+            let result = self.switch_statement(chunk);
+            self.end_scope(chunk);
+            result
         } else if self.matches(LeftBrace)? {
             self.begin_scope();
             self.block(chunk)?;
@@ -410,10 +1273,99 @@ impl<'a> Parser<'a> {
     }
 
     fn block(&mut self, chunk: &mut Chunk) -> ParseResult {
+        // Once the block returns, breaks or continues, nothing after it in
+        // the block can run.  The rest of the block is still compiled, as
+        // it's often left there on purpose while debugging.
+        let mut exited = false;
+        let mut unreachable = None;
         while !self.check(RightBrace) && !self.check(Eof) {
+            if exited && unreachable.is_none() {
+                unreachable = Some(Rc::clone(&self.current));
+            }
+            exited |= matches!(self.current.tag, Return | Break | Continue);
             self.declaration(chunk)?;
         }
         self.consume(RightBrace, "Expected '}' after block.")?;
+
+        if let Some(token) = unreachable {
+            self.warnings.push(error::warning(&token, String::from("Unreachable code.")));
+        }
+        Ok(())
+    }
+
+    /// Compiles a switch statement.  Cases are tried in order and the first
+    /// whose value equals the discriminant runs, so a case repeating an
+    /// earlier value never runs.  There's no fall-through between cases, and
+    /// a default case must come last.
+    fn switch_statement(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let switch_token = Rc::clone(&self.previous);
+
+        self.consume(LeftParen, "Expect '(' after 'switch'.")?;
+        self.expression(chunk)?;
+        self.consume(RightParen, "Expect ')' after switch value.")?;
+        self.consume(LeftBrace, "Expect '{' before switch body.")?;
+
+        // The discriminant is kept in a local that user code can't name,
+        // since 'switch' is a keyword.
+        self.add_local(&switch_token)?;
+        self.mark_initialized();
+        let slot = (self.locals.len() - 1) as u8;
+
+        let mut end_jumps = Vec::new();
+        let mut has_default = false;
+        while !self.check(RightBrace) && !self.check(Eof) {
+            if self.matches(Case)? {
+                if has_default {
+                    return parse_error(&self.previous, "Can't have a case after the default case.");
+                }
+                let line = self.previous.line;
+
+                chunk.emit(OP_GET_LOCAL, line);
+                chunk.emit(slot, line);
+                self.expression(chunk)?;
+                self.consume(Colon, "Expect ':' after case value.")?;
+                chunk.emit(OP_EQUAL, line);
+
+                let next_case = chunk.emit_jump(OP_JUMP_IF_FALSE, line);
+                chunk.emit(OP_POP, line);
+                self.case_body(chunk)?;
+                end_jumps.push(chunk.emit_jump(OP_JUMP, self.previous.line));
+
+                chunk
+                    .patch_jump(next_case)
+                    .or_else(|e| parse_error(&self.previous, &e))?;
+                chunk.emit(OP_POP, line);
+            } else if self.matches(Default)? {
+                if has_default {
+                    return parse_error(&self.previous, "Can't have more than one default case.");
+                }
+                has_default = true;
+
+                self.consume(Colon, "Expect ':' after 'default'.")?;
+                self.case_body(chunk)?;
+            } else {
+                return parse_error(&self.current, "Expect 'case' or 'default' in switch body.");
+            }
+        }
+        self.consume(RightBrace, "Expect '}' after switch body.")?;
+
+        for offset in end_jumps {
+            chunk
+                .patch_jump(offset)
+                .or_else(|e| parse_error(&switch_token, &e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Compiles the statements of a case, up to the next case or the end of
+    /// the switch.
+    fn case_body(&mut self, chunk: &mut Chunk) -> ParseResult {
+        self.begin_scope();
+        while !self.check(Case) && !self.check(Default) && !self.check(RightBrace) && !self.check(Eof) {
+            self.declaration(chunk)?;
+        }
+        self.end_scope(chunk);
         Ok(())
     }
 
@@ -430,7 +1382,14 @@ impl<'a> Parser<'a> {
     fn expression_statement(&mut self, chunk: &mut Chunk) -> ParseResult {
         let line = self.previous.line;
 
-        self.expression(chunk)?;
+        self.comma_expression(chunk)?;
+
+        let at_top_level = self.enclosing.is_empty() && self.scope_depth == 0;
+        if self.options.repl && at_top_level && self.check(Eof) {
+            chunk.emit(OP_PRINT, line);
+            return Ok(());
+        }
+
         self.consume(Semicolon, "Expect ';' after value.")?;
         chunk.emit(OP_POP, line);
 
@@ -445,6 +1404,9 @@ impl<'a> Parser<'a> {
         self.expression(chunk)?;
         self.consume(RightParen, "Expect ')' after condition.")?;
 
+        // OP_JUMP_IF_FALSE leaves the condition on the stack, so both the
+        // then branch and the else path start by popping it.  Either way it's
+        // popped exactly once, whether or not there's an else clause.
         let then_jump = chunk.emit_jump(OP_JUMP_IF_FALSE, line);
         let line = self.current.line;
         chunk.emit(OP_POP, line);
@@ -452,7 +1414,7 @@ impl<'a> Parser<'a> {
 
         let line = self.current.line;
         let else_jump = chunk.emit_jump(OP_JUMP, line);
-        
+
         chunk
             .patch_jump(then_jump)
             .or_else(|e| parse_error(&if_token, &e))?;
@@ -469,46 +1431,670 @@ impl<'a> Parser<'a> {
 
         Ok(())
     }
+
+    /// Emits an OP_POP for each local deeper than `depth` without forgetting
+    /// them, for jumps that leave their scope early.
+    fn emit_pops_to(&self, chunk: &mut Chunk, depth: i32, line: usize) {
+        for local in self.locals.iter().rev() {
+            if local.depth <= depth {
+                break;
+            }
+            if local.is_captured {
+                chunk.emit(OP_CLOSE_UPVALUE, line);
+            } else {
+                chunk.emit(OP_POP, line);
+            }
+        }
+    }
+
+    fn while_statement(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let while_token = Rc::clone(&self.previous);
+        let line = self.previous.line;
+        let loop_start = chunk.code.len();
+
+        self.consume(LeftParen, "Expect '(' after 'while'.")?;
+        self.expression(chunk)?;
+        self.consume(RightParen, "Expect ')' after condition.")?;
+
+        let exit_jump = chunk.emit_jump(OP_JUMP_IF_FALSE, line);
+        chunk.emit(OP_POP, line);
+
+        self.begin_loop(Some(loop_start));
+        let body = self.statement(chunk);
+
+        chunk
+            .emit_loop(loop_start, line)
+            .or_else(|e| parse_error(&while_token, &e))?;
+
+        chunk
+            .patch_jump(exit_jump)
+            .or_else(|e| parse_error(&while_token, &e))?;
+        chunk.emit(OP_POP, line);
+
+        self.end_loop(chunk, &while_token)?;
+        body
+    }
+
+    /// Compiles a do-while loop, which tests its condition after running the
+    /// body, so the body always runs at least once.
+    fn do_statement(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let do_token = Rc::clone(&self.previous);
+
+        // `continue` skips ahead to the condition.
+        self.begin_loop(None);
+        let result = self.do_loop(chunk, &do_token);
+        let end = self.end_loop(chunk, &do_token);
+        result.and(end)
+    }
+
+    fn do_loop(&mut self, chunk: &mut Chunk, do_token: &Rc<Token>) -> ParseResult {
+        let line = do_token.line;
+        let loop_start = chunk.code.len();
+
+        self.statement(chunk)?;
+        self.patch_continues(chunk, do_token)?;
+
+        self.consume(While, "Expect 'while' after do-while body.")?;
+        self.consume(LeftParen, "Expect '(' after 'while'.")?;
+        self.expression(chunk)?;
+        self.consume(RightParen, "Expect ')' after condition.")?;
+        self.consume(Semicolon, "Expect ';' after do-while condition.")?;
+
+        let exit_jump = chunk.emit_jump(OP_JUMP_IF_FALSE, line);
+        chunk.emit(OP_POP, line);
+        chunk
+            .emit_loop(loop_start, line)
+            .or_else(|e| parse_error(do_token, &e))?;
+
+        chunk
+            .patch_jump(exit_jump)
+            .or_else(|e| parse_error(do_token, &e))?;
+        chunk.emit(OP_POP, line);
+
+        Ok(())
+    }
+
+    /// Compiles `for (init; cond; incr) body`.  The caller opens the scope
+    /// that holds any variable declared by the initializer.
+    fn for_statement(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let for_token = Rc::clone(&self.previous);
+        let line = self.previous.line;
+
+        self.consume(LeftParen, "Expect '(' after 'for'.")?;
+        if self.matches(Semicolon)? {
+            // No initializer.
+        } else if self.matches(Var)? {
+            self.var_declaration(chunk)?;
+        } else {
+            self.expression_statement(chunk)?;
+        }
+
+        let mut loop_start = chunk.code.len();
+
+        let mut exit_jump = None;
+        if !self.matches(Semicolon)? {
+            self.comma_expression(chunk)?;
+            self.consume(Semicolon, "Expect ';' after loop condition.")?;
+
+            exit_jump = Some(chunk.emit_jump(OP_JUMP_IF_FALSE, line));
+            chunk.emit(OP_POP, line);
+        }
+
+        if !self.matches(RightParen)? {
+            // The increment runs after the body, so jump over it now and loop
+            // back to it once the body is done.
+            let body_jump = chunk.emit_jump(OP_JUMP, line);
+
+            let increment_start = chunk.code.len();
+            self.comma_expression(chunk)?;
+            chunk.emit(OP_POP, line);
+            self.consume(RightParen, "Expect ')' after for clauses.")?;
+
+            chunk
+                .emit_loop(loop_start, line)
+                .or_else(|e| parse_error(&for_token, &e))?;
+            loop_start = increment_start;
+
+            chunk
+                .patch_jump(body_jump)
+                .or_else(|e| parse_error(&for_token, &e))?;
+        }
+
+        self.begin_loop(Some(loop_start));
+        let body = self.statement(chunk);
+
+        chunk
+            .emit_loop(loop_start, line)
+            .or_else(|e| parse_error(&for_token, &e))?;
+
+        if let Some(exit_jump) = exit_jump {
+            chunk
+                .patch_jump(exit_jump)
+                .or_else(|e| parse_error(&for_token, &e))?;
+            chunk.emit(OP_POP, line);
+        }
+
+        self.end_loop(chunk, &for_token)?;
+        body
+    }
+
+    fn return_statement(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let return_token = Rc::clone(&self.previous);
+        let line = self.previous.line;
+
+        if self.matches(Semicolon)? {
+            self.emit_return(chunk, line);
+            return Ok(());
+        }
+
+        self.expression(chunk)?;
+        self.consume(Semicolon, "Expect ';' after return value.")?;
+        if self.kind == FunctionKind::Initializer {
+            return parse_error(&return_token, "Can't return a value from an initializer.");
+        }
+
+        // The value of a call ending the return value is returned as it is,
+        // so the callee can take over this function's frame.  The return is
+        // still needed for code that jumps past the call, and for callees
+        // that aren't Lox functions.  The script's frame has to stay, as
+        // the end of the program.
+        let call_end = chunk.code.len();
+        if self.last_call_end == Some(call_end) && self.kind != FunctionKind::Script {
+            chunk.code[call_end - 2] = OP_TAIL_CALL;
+        }
+        chunk.emit(OP_RETURN, line);
+
+        Ok(())
+    }
+
+    /// Emits a return without a value, which gives an initializer's instance
+    /// and nil from anything else.
+    fn emit_return(&self, chunk: &mut Chunk, line: usize) {
+        if self.kind == FunctionKind::Initializer {
+            chunk.emit(OP_GET_LOCAL, line);
+            chunk.emit(0, line);
+        } else {
+            chunk.emit(OP_NIL, line);
+        }
+        chunk.emit(OP_RETURN, line);
+    }
+
+    fn break_statement(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let break_token = Rc::clone(&self.previous);
+        let line = self.previous.line;
+
+        let loop_depth = match self.loops.last() {
+            Some(innermost) => innermost.scope_depth,
+            None => return parse_error(&break_token, "Can't use 'break' outside of a loop."),
+        };
+        self.consume(Semicolon, "Expect ';' after 'break'.")?;
+
+        // Discard the locals declared inside the loop before leaving it.
+        self.emit_pops_to(chunk, loop_depth, line);
+
+        let offset = chunk.emit_jump(OP_JUMP, line);
+        self.loops.last_mut().unwrap().breaks.push(offset);
+
+        Ok(())
+    }
+
+    fn continue_statement(&mut self, chunk: &mut Chunk) -> ParseResult {
+        let continue_token = Rc::clone(&self.previous);
+        let line = self.previous.line;
+
+        let (loop_start, loop_depth) = match self.loops.last() {
+            Some(innermost) => (innermost.start, innermost.scope_depth),
+            None => return parse_error(&continue_token, "Can't use 'continue' outside of a loop."),
+        };
+        self.consume(Semicolon, "Expect ';' after 'continue'.")?;
+
+        self.emit_pops_to(chunk, loop_depth, line);
+
+        match loop_start {
+            Some(loop_start) => chunk
+                .emit_loop(loop_start, line)
+                .or_else(|e| parse_error(&continue_token, &e)),
+            None => {
+                let offset = chunk.emit_jump(OP_JUMP, line);
+                self.loops.last_mut().unwrap().continues.push(offset);
+                Ok(())
+            }
+        }
+    }
+}
+
+fn resolve_local_in(locals: &[Local], name: &Rc<Token>) -> Result<Option<u8>, ParseError> {
+    for (i, local) in locals.iter().enumerate().rev() {
+        if local.name.lexeme == name.lexeme {
+            if local.depth == -1 {
+                return parse_error(name, "Cannot read local variable in its own initializer.");
+            }
+            return Ok(Some(i as u8));
+        }
+    }
+
+    Ok(None)
+}
+
+fn const_assignment_error<T>(name: &Rc<Token>) -> Result<T, ParseError> {
+    let message = format!("Cannot assign to constant '{}'.", name.lexeme);
+    parse_error(name, &message)
 }
 
 /// Adds the token's lexeme to the chunk's constant table.  Returns the index
 /// in the constant table.
-fn identifier_constant(chunk: &mut Chunk, token: &Rc<Token>) -> Result<u8, ParseError> {
-    let constant = Value::new_string(&token.lexeme);
+fn identifier_constant(chunk: &mut Chunk, strings: &mut Interner, token: &Rc<Token>) -> Result<u8, ParseError> {
+    let constant = Value::Obj(strings.intern(&token.lexeme));
     chunk
-        .add_constant(constant)
+        .add_short_constant(constant)
         .or_else(|e| parse_error(token, &e))
 }
 
-pub fn compile(source: &str, chunk: &mut Chunk) -> bool {
-    let mut ok = true;
+/// Compiles `source` into `chunk`.  Parsing carries on past an error, so
+/// every error found in the source is returned, in the order found.
+///
+/// ```
+/// let mut chunk = lox::Chunk::new();
+/// let errors = lox::compile("print 1 +;\nprint (2;", &mut chunk).unwrap_err();
+/// assert_eq!(errors.len(), 2);
+/// ```
+pub fn compile(source: &str, chunk: &mut Chunk) -> Result<(), Vec<ParseError>> {
+    compile_with_options(source, chunk, &CompileOptions::default())
+}
+
+/// Compiles `source` into a new chunk, which can be inspected, saved, or run
+/// any number of times.
+///
+/// ```
+/// let chunk = lox::compile_to_chunk("print 1;").unwrap();
+/// let mut vm = lox::Vm::new();
+/// let options = lox::VmOptions::default();
+/// for _ in 0..2 {
+///     let mut out = Vec::new();
+///     vm.interpret_chunk(chunk.clone(), &options, &mut out).unwrap();
+///     assert_eq!(out, b"1\n");
+/// }
+/// ```
+pub fn compile_to_chunk(source: &str) -> Result<Chunk, Vec<ParseError>> {
+    let mut chunk = Chunk::new();
+    compile(source, &mut chunk)?;
+    Ok(chunk)
+}
 
-    let mut parser = Parser::new(source);
+/// Compiles `source` as `compile` does, with settings that change how.
+///
+/// ```
+/// let options = lox::CompileOptions { strict: true, ..Default::default() };
+/// let result = lox::compile_with_options("var x = 1; var x = 2;", &mut lox::Chunk::new(), &options);
+/// assert!(result.is_err());
+/// ```
+///
+/// Any warnings are dropped; `compile_with_warnings` returns them.
+pub fn compile_with_options(
+    source: &str,
+    chunk: &mut Chunk,
+    options: &CompileOptions,
+) -> Result<(), Vec<ParseError>> {
+    compile_with_warnings(source, chunk, options, &mut Vec::new())
+}
+
+/// Compiles `source` as `compile_with_options` does, adding any warnings to
+/// `warnings`.
+///
+/// ```
+/// let source = "var x = 1; { var x = 2; }";
+/// let options = lox::CompileOptions { warn_shadowing: true, ..Default::default() };
+/// let mut warnings = Vec::new();
+/// lox::compile_with_warnings(source, &mut lox::Chunk::new(), &options, &mut warnings).unwrap();
+/// assert_eq!(warnings.len(), 1);
+/// ```
+pub fn compile_with_warnings(
+    source: &str,
+    chunk: &mut Chunk,
+    options: &CompileOptions,
+    warnings: &mut Vec<ParseError>,
+) -> Result<(), Vec<ParseError>> {
+    let mut errors = Vec::new();
+
+    let mut parser = Parser::new(source, *options);
     if let Err(e) = parser.advance() {
-        ok = false;
-        eprintln!("{}", e);
+        errors.push(e);
     }
     loop {
         match parser.matches(Eof) {
             Ok(false) => {
                 if let Err(e) = parser.declaration(chunk) {
-                    ok = false;
-                    eprintln!("{}", e);
+                    errors.push(e);
                     parser.synchronize();
                 }
             }
             Ok(true) => break,
-            Err(e) => {
-                ok = false;
-                eprintln!("{}", e);
-            }
+            Err(e) => errors.push(e),
         }
     }
+    chunk.emit(OP_NIL, parser.previous.line);
     chunk.emit(OP_RETURN, parser.previous.line);
+    warnings.append(&mut parser.warnings);
+    // Calls are checked last, but their errors belong among the rest.
+    errors.extend(parser.check_global_calls());
+    errors.sort_by_key(|error| (error.line(), error.column()));
+
+    if errors.is_empty() {
+        peephole::optimize(chunk);
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the messages of the errors compiling `source` gives.
+    fn errors(source: &str) -> Vec<String> {
+        match compile(source, &mut Chunk::new()) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.iter().map(|error| String::from(error.message())).collect(),
+        }
+    }
+
+    /// Returns the constant a literal compiles to.
+    fn literal(source: &str) -> Value {
+        let chunk = compile_to_chunk(&format!("print {};", source)).ok().unwrap();
+        chunk.constants[0].clone()
+    }
+
+    #[test]
+    fn integer_literals() {
+        assert!(matches!(literal("7"), Value::Int(7)));
+        assert!(matches!(literal("7.0"), Value::Number(x) if x == 7.0));
+        assert!(matches!(literal("9223372036854775807"), Value::Int(i64::MAX)));
+        assert!(matches!(literal("0x7FFFFFFFFFFFFFFF"), Value::Int(i64::MAX)));
+        assert!(matches!(literal("0x1a2b"), Value::Int(0x1a2b)));
+    }
+
+    #[test]
+    fn float_literals() {
+        assert!(matches!(literal("1.5e-3"), Value::Number(x) if x == 0.0015));
+        // An exponent makes a float even without a fraction.
+        assert!(matches!(literal("1e3"), Value::Number(x) if x == 1000.0));
+    }
+
+    #[test]
+    fn integer_literal_too_large() {
+        assert_eq!(errors("print 99999999999999999999;"), ["Integer literal too large."]);
+        assert_eq!(errors("print 9223372036854775808;"), ["Integer literal too large."]);
+        assert!(matches!(literal("9223372036854775807"), Value::Int(i64::MAX)));
+        assert_eq!(errors("print 0xFFFFFFFFFFFFFFFF;"), ["Integer literal too large."]);
+        assert!(matches!(literal("1e20"), Value::Number(x) if x == 1e20));
+    }
+
+    #[test]
+    fn too_many_locals() {
+        let locals: String = (0..256).map(|i| format!("var v{};", i)).collect();
+        assert_eq!(
+            errors(&format!("{{ {} }}", locals))[0],
+            "Can't declare 'v255': the script can have at most 255 local variables."
+        );
+
+        // In a function the first slot is taken.
+        let locals: String = (0..255).map(|i| format!("var v{};", i)).collect();
+        assert_eq!(
+            errors(&format!("fun f() {{ {} }}", locals))[0],
+            "Can't declare 'v254': a function can have at most 254 local variables."
+        );
+        let locals: String = (0..254).map(|i| format!("var v{};", i)).collect();
+        assert!(errors(&format!("fun f() {{ {} }}", locals)).is_empty());
+    }
+
+    #[test]
+    fn redeclaring_a_const_global() {
+        assert_eq!(errors("const X = 1; var X = 2;"), ["Cannot redeclare constant 'X'."]);
+        assert_eq!(errors("const X = 1; const X = 2;"), ["Cannot redeclare constant 'X'."]);
+        assert_eq!(errors("const X = 1; fun X() {}"), ["Cannot redeclare constant 'X'."]);
+        assert_eq!(errors("var X = 1; const X = 2;"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn errors_give_line_and_column() {
+        let errors = compile("var a = 1;\nprint a +;", &mut Chunk::new()).unwrap_err();
+        assert!(errors[0].to_string().starts_with("[line 2, col 10] Error at ';'"));
+    }
+
+    #[test]
+    fn break_outside_a_loop() {
+        assert_eq!(errors("break;"), ["Can't use 'break' outside of a loop."]);
+        // A function body starts outside of any loop around it.
+        assert_eq!(errors("while (true) { fun f() { break; } }")[0], "Can't use 'break' outside of a loop.");
+    }
+
+    #[test]
+    fn continue_outside_a_loop() {
+        assert_eq!(errors("continue;"), ["Can't use 'continue' outside of a loop."]);
+    }
+
+    #[test]
+    fn calling_a_known_function_with_the_wrong_arity() {
+        assert_eq!(errors("fun f(a, b) {}\nf(1);"), ["Expected 2 arguments but got 1."]);
+        assert_eq!(errors("fun f() {}\nf(1, 2);"), ["Expected 0 arguments but got 2."]);
+
+        let mut chunk = Chunk::new();
+        let found = compile("fun add(a, b) { return a + b; }\nprint add(1);", &mut chunk).unwrap_err();
+        assert_eq!(found[0].line(), 2);
+
+        // The check can't know what a reassigned or redeclared name holds.
+        assert!(errors("fun f(a) {}\nf = clock;\nf();").is_empty());
+        assert!(errors("fun f(a) {}\nfun f() {}\nf();").is_empty());
+    }
+
+    #[test]
+    fn this_outside_a_class() {
+        assert_eq!(errors("print this;"), ["Can't use 'this' outside of a class."]);
+        assert_eq!(errors("fun f() { return this; }")[0], "Can't use 'this' outside of a class.");
+    }
+
+    #[test]
+    fn repeated_literals_share_a_constant() {
+        let mut chunk = Chunk::new();
+        compile("print 1; print 1; print 1;", &mut chunk).unwrap();
+        assert_eq!(chunk.constants.len(), 1);
+
+        let mut chunk = Chunk::new();
+        compile("var a = \"s\"; a = \"s\"; print a;", &mut chunk).unwrap();
+        assert_eq!(chunk.constants.len(), 2);
+    }
+
+    #[test]
+    fn string_literals() {
+        assert!(literal("\"a 'b' [c]; {d}\"") == Value::new_string("a 'b' [c]; {d}"));
+        assert!(literal("\"\"") == Value::new_string(""));
+    }
+
+    #[test]
+    fn reporting_every_syntax_error() {
+        let errors = compile("var a = ;\nprint 1;\nprint (2;", &mut Chunk::new()).unwrap_err();
+        let reported: Vec<(usize, usize, &str)> =
+            errors.iter().map(|error| (error.line(), error.column(), error.message())).collect();
+        assert_eq!(reported, [(1, 9, "unexpected token"), (3, 9, "Expect ')' after expression.")]);
+    }
+
+    #[test]
+    fn locals_live_in_stack_slots() {
+        let mut chunk = Chunk::new();
+        compile("{ var a = 1; print a; }", &mut chunk).unwrap();
+        // The name isn't needed at runtime, so only the value is a constant.
+        assert_eq!(chunk.constants.len(), 1);
+        assert!(chunk.code.contains(&OP_GET_LOCAL));
+    }
+
+    #[test]
+    fn assigning_to_a_const() {
+        assert_eq!(errors("const PI = 3.14; PI = 3;"), ["Cannot assign to constant 'PI'."]);
+        assert_eq!(errors("{ const PI = 3.14; PI = 3; }")[0], "Cannot assign to constant 'PI'.");
+        assert_eq!(errors("{ const PI = 3.14; fun f() { PI += 1; } }")[0], "Cannot assign to constant 'PI'.");
+        assert_eq!(errors("const PI = 3.14; { var PI = 3; PI = 4; }"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn misusing_inheritance() {
+        assert_eq!(errors("class A < A {}"), ["A class can't inherit from itself."]);
+        assert_eq!(errors("print super.x;"), ["Can't use 'super' outside of a class."]);
+        assert_eq!(errors("class A { f() { super.f(); } }")[0], "Can't use 'super' in a class with no superclass.");
+    }
 
-    if ok {
-        chunk.disassemble("code");
+    #[test]
+    fn returning_a_value_from_init() {
+        assert_eq!(errors("class P { init() { return 1; } }")[0], "Can't return a value from an initializer.");
+        assert_eq!(errors("class P { init() { return; } }"), Vec::<String>::new());
     }
 
-    return ok;
+    #[test]
+    fn incrementing_a_non_variable() {
+        assert_eq!(errors("1++;"), ["Invalid assignment target."]);
+    }
+
+    #[test]
+    fn folding_literal_arithmetic() {
+        let disassembly = |source| compile_to_chunk(source).ok().unwrap().disassemble_to_string();
+        assert_eq!(
+            disassembly("print 1 + 2;"),
+            "== code ==\n\
+             0000 0001 OP_CONSTANT      0000 3\n\
+             0002    | OP_PRINT\n\
+             0003    | OP_NIL\n\
+             0004    | OP_RETURN\n"
+        );
+        assert_eq!(disassembly("print 2 + 3 * 4;"), disassembly("print 14;"));
+        assert_eq!(disassembly("print 1.5 * 2;"), disassembly("print 3.0;"));
+        // Division by zero is left for the VM to report.
+        assert!(disassembly("print 1 / 0;").contains("OP_DIVIDE"));
+        assert!(disassembly("print 1 % 0;").contains("OP_MODULO"));
+    }
+
+    #[test]
+    fn dropping_instructions_that_cancel_out() {
+        let chunk = compile_to_chunk("var a = 1; nil; print !(a != 2);").ok().unwrap();
+        let listing = chunk.disassemble_to_string();
+        assert_eq!(chunk.code.len(), 12);
+        assert!(!listing.contains("OP_POP"));
+        assert!(!listing.contains("OP_NOT"));
+    }
+
+    #[test]
+    fn empty_group() {
+        assert_eq!(errors("print ();"), ["Expect expression inside parentheses."]);
+        assert_eq!(errors("print (1 + ());")[0], "Expect expression inside parentheses.");
+    }
+
+    /// Returns the errors compiling `source` strictly gives.
+    fn strict_errors(source: &str) -> Vec<String> {
+        let options = CompileOptions { strict: true, ..CompileOptions::default() };
+        match compile_with_options(source, &mut Chunk::new(), &options) {
+            Ok(()) => Vec::new(),
+            Err(errors) => errors.iter().map(|error| String::from(error.message())).collect(),
+        }
+    }
+
+    #[test]
+    fn strict_mode_rejects_redeclared_globals() {
+        assert_eq!(strict_errors("var x = 1; var x = 2;"), ["Already a variable named 'x' in this scope."]);
+        assert_eq!(strict_errors("var x = 1; fun x() {}"), ["Already a variable named 'x' in this scope."]);
+        assert_eq!(strict_errors("var x = 1; { var x = 2; }"), Vec::<String>::new());
+        assert_eq!(errors("var x = 1; var x = 2;"), Vec::<String>::new());
+    }
+
+    /// Returns the line and message of each warning about `source`, warning
+    /// of shadowed variables too.
+    fn warnings(source: &str) -> Vec<(usize, String)> {
+        let options = CompileOptions { warn_shadowing: true, ..CompileOptions::default() };
+        let mut warnings = Vec::new();
+        compile_with_warnings(source, &mut Chunk::new(), &options, &mut warnings).unwrap();
+        assert!(warnings.iter().all(ParseError::is_warning));
+        warnings.iter().map(|warning| (warning.line(), String::from(warning.message()))).collect()
+    }
+
+    #[test]
+    fn warning_of_shadowed_variables() {
+        let warning = |line, message: &str| (line, String::from(message));
+        assert_eq!(
+            warnings("{\n  var x = 1;\n  {\n    var x = 2;\n  }\n}"),
+            [warning(4, "'x' shadows the variable declared on line 2.")]
+        );
+        assert_eq!(
+            warnings("var g = 1;\nfun f() {\n  var g = 2;\n}"),
+            [warning(3, "'g' shadows the variable declared on line 1.")]
+        );
+        assert_eq!(
+            warnings("{\n  var a = 1;\n  fun f(a) {}\n}"),
+            [warning(3, "'a' shadows the variable declared on line 2.")]
+        );
+        assert_eq!(warnings("{ var a = 1; } { var a = 2; }"), []);
+
+        // Locals the compiler declares for itself aren't the user's to rename.
+        assert_eq!(warnings("switch (1) { case 1: switch (2) { case 2: print 1; } }"), []);
+        let source = "class A {}\nclass B < A {\n  m() {\n    class C < A {}\n    class D < C {}\n  }\n}";
+        assert_eq!(warnings(source), []);
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let unreachable = |line| (line, String::from("Unreachable code."));
+        let source = "fun f() {\n  return 1;\n  print 2;\n}";
+        assert_eq!(warnings(source), [unreachable(3)]);
+        assert!(errors(source).is_empty());
+        assert_eq!(warnings("while (true) { break; print 1; }"), [unreachable(1)]);
+        assert_eq!(warnings("for (;;) { continue; print 1; }"), [unreachable(1)]);
+        // Only an exit at the same level ends the block.
+        assert_eq!(warnings("fun f(x) { if (x) return 1; print 2; }"), []);
+    }
+
+    #[test]
+    fn shadowing_is_allowed_by_default() {
+        let mut warnings = Vec::new();
+        let source = "var x = 1; { var x = 2; }";
+        compile_with_warnings(source, &mut Chunk::new(), &CompileOptions::default(), &mut warnings).unwrap();
+        assert!(warnings.is_empty());
+    }
+
+    #[test]
+    fn marking_calls_in_tail_position() {
+        let disassembly = |source| compile_to_chunk(source).ok().unwrap().disassemble_to_string();
+        assert!(disassembly("fun f(n) { return f(n); }").contains("OP_TAIL_CALL"));
+        assert!(!disassembly("fun f(n) { return 1 + f(n); }").contains("OP_TAIL_CALL"));
+        assert!(!disassembly("fun f(n) { f(n); }").contains("OP_TAIL_CALL"));
+        // The script's frame stays until the end of the program.
+        assert!(!disassembly("fun f() {} return f();").contains("OP_TAIL_CALL"));
+    }
+
+    #[test]
+    fn compiling_to_a_new_chunk() {
+        let chunk = compile_to_chunk("var a = 1;\nprint a;").ok().unwrap();
+        assert_eq!(chunk.code.last(), Some(&OP_RETURN));
+        assert_eq!(chunk.line_for_offset(chunk.code.len() - 1), Some(2));
+        assert!(chunk.disassemble_to_string().contains("OP_DEFINE_GLOBAL"));
+
+        assert_eq!(compile_to_chunk("").ok().unwrap().code, [OP_NIL, OP_RETURN]);
+        assert!(compile_to_chunk("print;").is_err());
+    }
+
+    #[test]
+    fn leading_dot() {
+        let message = "Expect an expression before '.' to access a property of.";
+        assert_eq!(errors("print .name;"), [message]);
+        assert_eq!(errors("var a = 1 + .b;"), [message]);
+        let message = "Expect a digit before '.' in a number, as in 0.5.";
+        assert_eq!(errors("print .5;"), [message]);
+        assert_eq!(errors("print 1 - .25;"), [message]);
+    }
+
+    #[test]
+    fn trailing_commas() {
+        assert!(errors("print [1, 2,]; print {\"a\": 1,}; print max(1, 2,);").is_empty());
+        assert!(errors("fun f(a, b) {} f(1, 2,);").is_empty());
+        assert!(!errors("print [,];").is_empty());
+        assert!(!errors("print {,};").is_empty());
+        assert!(!errors("print max(,);").is_empty());
+        assert!(!errors("print [1,,];").is_empty());
+    }
 }
@@ -0,0 +1,123 @@
+//! A pass over finished bytecode that drops instructions which cancel out,
+//! and shortens forward jumps that don't need a 24-bit offset.
+//!
+//! It runs once a chunk's jumps are all patched, so it works out where each
+//! jump lands, never removes code that something jumps into the middle of,
+//! and patches the jumps again afterwards.
+
+use std::collections::HashSet;
+use std::mem;
+
+use crate::chunk::Chunk;
+use crate::op::*;
+
+/// Removes `OP_NIL; OP_POP` pairs, and `OP_NOT; OP_NOT` pairs applied to a
+/// value that is already a bool.  Long forward jumps whose offset fits in 16
+/// bits become short ones.
+pub fn optimize(chunk: &mut Chunk) {
+    let mut starts = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        starts.push(offset);
+        offset += chunk.instruction_len(offset);
+    }
+
+    let targets: HashSet<usize> = starts.iter().filter_map(|&start| chunk.jump_target(start)).collect();
+
+    let mut keep = vec![true; starts.len()];
+    let mut i = 0;
+    while i + 1 < starts.len() {
+        let op = chunk.code[starts[i]];
+        let next = chunk.code[starts[i + 1]];
+        // Jumping to the second instruction of a pair needs the first.
+        let removable = !targets.contains(&starts[i + 1]);
+
+        let nil_pop = op == OP_NIL && next == OP_POP;
+        // Negating a bool twice gives it back, but for any other value it
+        // turns it into a bool, so the pair has to stay.
+        let not_not = op == OP_NOT
+            && next == OP_NOT
+            && i > 0
+            && keep[i - 1]
+            && !targets.contains(&starts[i])
+            && produces_bool(chunk.code[starts[i - 1]]);
+
+        if removable && (nil_pop || not_not) {
+            keep[i] = false;
+            keep[i + 1] = false;
+            i += 2;
+        } else {
+            i += 1;
+        }
+    }
+
+    // Removing code only brings a jump closer to its target, so one that
+    // fits in 16 bits now will still fit afterwards.
+    let short_forms: Vec<Option<u8>> = starts
+        .iter()
+        .map(|&start| {
+            let short = short_form(chunk.code[start])?;
+            let distance = chunk.jump_target(start)? - (start + 4);
+            if distance <= u16::MAX as usize {
+                Some(short)
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    if keep.iter().all(|&kept| kept) && short_forms.iter().all(Option::is_none) {
+        return;
+    }
+
+    // Copy the instructions that are left, noting where each one moves to.
+    let mut optimized = Chunk::new();
+    let mut moved_to = vec![0; chunk.code.len() + 1];
+    for (i, &start) in starts.iter().enumerate() {
+        moved_to[start] = optimized.code.len();
+        if !keep[i] {
+            continue;
+        }
+
+        let line = chunk.line_at(start);
+        if let Some(short) = short_forms[i] {
+            // The offset is written below.
+            optimized.emit(short, line);
+            optimized.emit(0xff, line);
+            optimized.emit(0xff, line);
+        } else {
+            for &byte in chunk.code[start..start + chunk.instruction_len(start)].iter() {
+                optimized.emit(byte, line);
+            }
+        }
+    }
+    moved_to[chunk.code.len()] = optimized.code.len();
+
+    for (&start, &kept) in starts.iter().zip(keep.iter()) {
+        let target = match chunk.jump_target(start) {
+            Some(target) if kept => target,
+            _ => continue,
+        };
+        let (start, target) = (moved_to[start], moved_to[target]);
+        // Jumps count from the end of the instruction, in either direction.
+        let next = start + optimized.instruction_len(start);
+        optimized.write_jump(start + 1, target.abs_diff(next));
+    }
+
+    optimized.constants = mem::take(&mut chunk.constants);
+    *chunk = optimized;
+}
+
+/// Returns the short form of a long forward jump.
+fn short_form(op: u8) -> Option<u8> {
+    match op {
+        OP_JUMP_LONG => Some(OP_JUMP),
+        OP_JUMP_IF_FALSE_LONG => Some(OP_JUMP_IF_FALSE),
+        _ => None,
+    }
+}
+
+/// Returns true if the instruction always leaves a bool on the stack.
+fn produces_bool(op: u8) -> bool {
+    matches!(op, OP_TRUE | OP_FALSE | OP_EQUAL | OP_GREATER | OP_LESS | OP_NOT)
+}
@@ -0,0 +1,268 @@
+//! Line editing for the REPL on a terminal.  The up and down arrows step
+//! through earlier lines, and the left and right arrows move along the line.
+//!
+//! Lines are kept in `~/.lox_history` between sessions.  Set `LOX_HISTORY`
+//! to use another file, or to an empty string to keep no history at all.
+//!
+//! The terminal is switched out of line mode with `stty`.  Where that can't
+//! be done, lines are read as they are, without editing.
+
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+use lox::LineReader;
+
+pub struct Editor {
+    history: Vec<String>,
+    file: Option<PathBuf>,
+}
+
+impl Editor {
+    /// Creates an editor that starts with the lines saved in the history file.
+    pub fn new() -> Self {
+        let file = history_file();
+        let history = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .map(|text| text.lines().map(String::from).collect())
+            .unwrap_or_default();
+        Editor { history, file }
+    }
+
+    fn edit(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        let mut stdin = io::stdin().lock();
+        let mut stdout = io::stdout();
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Where we are in the history; one past the end is the new line.
+        let mut recalled = self.history.len();
+        // The new line, kept while looking through the history.
+        let mut draft = Vec::new();
+
+        loop {
+            let key = match read_key(&mut stdin)? {
+                Some(key) => key,
+                None if line.is_empty() => return Ok(None),
+                None => Key::Enter,
+            };
+            match key {
+                Key::Enter => {
+                    writeln!(stdout)?;
+                    let mut text: String = line.into_iter().collect();
+                    text.push('\n');
+                    return Ok(Some(text));
+                }
+                Key::EndOfInput if line.is_empty() => return Ok(None),
+                Key::EndOfInput => continue,
+                Key::Char(c) => {
+                    line.insert(cursor, c);
+                    cursor += 1;
+                }
+                Key::Backspace if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                Key::Backspace => continue,
+                Key::Left => cursor = cursor.saturating_sub(1),
+                Key::Right => cursor = (cursor + 1).min(line.len()),
+                Key::Up if recalled > 0 => {
+                    if recalled == self.history.len() {
+                        draft = line;
+                    }
+                    recalled -= 1;
+                    line = self.history[recalled].chars().collect();
+                    cursor = line.len();
+                }
+                Key::Down if recalled < self.history.len() => {
+                    recalled += 1;
+                    line = match self.history.get(recalled) {
+                        Some(text) => text.chars().collect(),
+                        None => draft.clone(),
+                    };
+                    cursor = line.len();
+                }
+                Key::Up | Key::Down | Key::Other => continue,
+            }
+
+            // Redraw the whole line, then put the cursor back.
+            let text: String = line.iter().collect();
+            write!(stdout, "\r{} {}\x1b[K", prompt, text)?;
+            if cursor < line.len() {
+                write!(stdout, "\x1b[{}D", line.len() - cursor)?;
+            }
+            stdout.flush()?;
+        }
+    }
+}
+
+impl LineReader for Editor {
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+        match RawMode::enable() {
+            Some(_raw) => self.edit(prompt),
+            None => LineReader::read_line(&mut io::stdin().lock(), prompt),
+        }
+    }
+
+    fn add_history(&mut self, line: &str) {
+        if self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.history.push(String::from(line));
+
+        if let Some(file) = &self.file {
+            // Losing history isn't worth interrupting the session over.
+            let _ = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)
+                .and_then(|mut file| writeln!(file, "{}", line));
+        }
+    }
+}
+
+/// Returns the file to keep history in, if any.
+fn history_file() -> Option<PathBuf> {
+    match env::var_os("LOX_HISTORY") {
+        Some(path) if path.is_empty() => None,
+        Some(path) => Some(PathBuf::from(path)),
+        None => Some(PathBuf::from(env::var_os("HOME")?).join(".lox_history")),
+    }
+}
+
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    EndOfInput,
+    Up,
+    Down,
+    Left,
+    Right,
+    Other,
+}
+
+/// Reads one key press, or returns None if stdin is closed.
+fn read_key(input: &mut impl BufRead) -> io::Result<Option<Key>> {
+    let byte = match read_byte(input)? {
+        Some(byte) => byte,
+        None => return Ok(None),
+    };
+    let key = match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x04 => Key::EndOfInput,
+        0x1b => {
+            // Arrow keys send ESC [ and a letter.
+            if read_byte(input)? != Some(b'[') {
+                return Ok(Some(Key::Other));
+            }
+            match read_byte(input)? {
+                Some(b'A') => Key::Up,
+                Some(b'B') => Key::Down,
+                Some(b'C') => Key::Right,
+                Some(b'D') => Key::Left,
+                _ => Key::Other,
+            }
+        }
+        byte if byte < 0x20 => Key::Other,
+        byte => {
+            // Gather the rest of a UTF-8 sequence.
+            let len = match byte {
+                0xf0..=0xff => 4,
+                0xe0..=0xef => 3,
+                0xc0..=0xdf => 2,
+                _ => 1,
+            };
+            let mut bytes = vec![byte];
+            for _ in 1..len {
+                bytes.extend(read_byte(input)?);
+            }
+            match std::str::from_utf8(&bytes).ok().and_then(|s| s.chars().next()) {
+                Some(c) => Key::Char(c),
+                None => Key::Other,
+            }
+        }
+    };
+    Ok(Some(key))
+}
+
+fn read_byte(input: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    match input.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+/// Turns off the terminal's own line editing and echo while it's alive.
+struct RawMode {
+    saved: String,
+}
+
+impl RawMode {
+    fn enable() -> Option<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["-icanon", "-echo", "min", "1"])?;
+        Some(RawMode {
+            saved: saved.trim().to_string(),
+        })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+/// Runs `stty` on the terminal, returning what it prints if it succeeds.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reading_keys() {
+        let mut input = "a\x1b[A\x1b[D\u{e9}\r\x7f".as_bytes();
+        let mut keys = Vec::new();
+        while let Some(key) = read_key(&mut input).unwrap() {
+            keys.push(key);
+        }
+        assert!(matches!(
+            keys[..],
+            [Key::Char('a'), Key::Up, Key::Left, Key::Char('\u{e9}'), Key::Enter, Key::Backspace]
+        ));
+    }
+
+    #[test]
+    fn history_is_appended_to_the_file() {
+        let file = env::temp_dir().join(format!("lox_history_test_{}", std::process::id()));
+        let _ = fs::remove_file(&file);
+        let mut editor = Editor {
+            history: Vec::new(),
+            file: Some(file.clone()),
+        };
+
+        editor.add_history("print 1;");
+        editor.add_history("print 1;");
+        editor.add_history("print 2;");
+        assert_eq!(editor.history, ["print 1;", "print 2;"]);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "print 1;\nprint 2;\n");
+        fs::remove_file(&file).unwrap();
+    }
+}
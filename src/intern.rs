@@ -0,0 +1,131 @@
+//! String interning, so that equal strings can share one object and be
+//! compared by address.
+
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use crate::chunk::Chunk;
+use crate::object::{Function, Obj, ObjValue};
+use crate::value::Value;
+
+/// Hands out one string object for each distinct string.
+#[derive(Default)]
+pub struct Interner {
+    strings: HashMap<String, Rc<Obj>>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, s: &str) -> Rc<Obj> {
+        if let Some(obj) = self.strings.get(s) {
+            return Rc::clone(obj);
+        }
+
+        let obj = Obj::new_string(String::from(s));
+        self.strings.insert(String::from(s), Rc::clone(&obj));
+        obj
+    }
+
+    /// Returns the interned string equal to `s`, if there is one.
+    pub fn get(&self, s: &str) -> Option<Rc<Obj>> {
+        self.strings.get(s).map(Rc::clone)
+    }
+
+    /// Replaces the string constants in `chunk`, and in the chunks of the
+    /// functions declared in it, with interned ones.
+    pub fn intern_constants(&mut self, chunk: &mut Chunk) {
+        for constant in chunk.constants.iter_mut() {
+            let obj = match constant {
+                Value::Obj(obj) => obj,
+                _ => continue,
+            };
+
+            if let Some(s) = obj.as_str() {
+                let interned = self.intern(s);
+                *obj = interned;
+                continue;
+            }
+
+            // A freshly compiled or loaded function isn't shared yet.
+            let function = match Rc::get_mut(obj).map(|obj| &mut obj.value) {
+                Some(ObjValue::Function(function)) => Rc::get_mut(function),
+                _ => None,
+            };
+            if let Some(function) = function {
+                self.intern_constants(&mut function.chunk);
+                continue;
+            }
+
+            // One in a chunk that's been cloned is shared with the copy, so
+            // it's rebuilt with a chunk of its own.
+            if let ObjValue::Function(function) = &obj.value {
+                let mut chunk = function.chunk.clone();
+                self.intern_constants(&mut chunk);
+                let function = Function::new(&function.name, function.arity, function.upvalue_count, chunk);
+                *obj = Obj::new_function(function);
+            }
+        }
+    }
+}
+
+/// A global variable's name.  Names are interned, so they're compared and
+/// hashed by address rather than by their characters.
+#[derive(Clone)]
+pub struct Symbol(Rc<Obj>);
+
+impl Symbol {
+    /// Makes a symbol from an interned string.
+    pub fn new(name: Rc<Obj>) -> Self {
+        Symbol(name)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str().expect("expected string for a symbol")
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler;
+
+    #[test]
+    fn equal_strings_share_an_object() {
+        let mut strings = Interner::default();
+        let a = strings.intern("name");
+        assert!(Rc::ptr_eq(&a, &strings.intern("name")));
+        assert!(!Rc::ptr_eq(&a, &strings.intern("other")));
+        assert!(strings.get("missing").is_none());
+
+        // Symbols compare by address, not contents.
+        assert!(Symbol::new(Rc::clone(&a)) == Symbol::new(strings.intern("name")));
+        assert!(Symbol::new(a) != Symbol::new(Obj::new_string(String::from("name"))));
+    }
+
+    #[test]
+    fn interning_the_constants_of_nested_functions() {
+        let mut chunk = compiler::compile_to_chunk("fun f() { return g; }").ok().unwrap();
+        let mut strings = Interner::default();
+        strings.intern_constants(&mut chunk);
+
+        let f = chunk.constants.iter().find_map(|c| c.as_obj().and_then(|obj| obj.as_function())).unwrap();
+        match &f.chunk.constants[0] {
+            Value::Obj(name) => assert!(Rc::ptr_eq(name, &strings.get("g").unwrap())),
+            _ => panic!("expected the name of g"),
+        }
+    }
+}
@@ -0,0 +1,35 @@
+//! A Lox interpreter that can be embedded in other programs.
+//!
+//! ```
+//! use std::collections::HashMap;
+//!
+//! let mut globals = HashMap::new();
+//! lox::define_natives(&mut globals);
+//! assert!(lox::interpret("print 1 + 1;", &mut globals).is_ok());
+//! ```
+
+mod chunk;
+mod compiler;
+mod intern;
+mod memory;
+mod natives;
+mod object;
+mod op;
+mod repl;
+mod scanner;
+mod value;
+mod vm;
+
+pub use crate::chunk::Chunk;
+pub use crate::compiler::{
+    compile, compile_to_chunk, compile_with_options, compile_with_warnings, CompileOptions, ParseError,
+};
+pub use crate::natives::{define_native, define_natives};
+pub use crate::object::NativeFn;
+pub use crate::repl::{repl, LineReader, ReplConfig};
+pub use crate::scanner::{scan_tokens, Token, TokenTag};
+pub use crate::value::{NumberFormat, Value};
+pub use crate::vm::{
+    get_global, interpret, interpret_chunk, interpret_with_options, interpret_with_writer, set_global,
+    InterpretError, Vm, VmOptions,
+};
@@ -1,57 +1,115 @@
-mod chunk;
-mod compiler;
-mod object;
-mod op;
-mod scanner;
-mod value;
-mod vm;
-
-use crate::vm::InterpretError;
-use std::collections::HashMap;
+mod editor;
+
+use editor::Editor;
+use lox::{Chunk, InterpretError, ReplConfig, Vm, VmOptions};
 use std::env;
-use std::error::Error;
 use std::fs;
-use std::io::{self, Write};
+use std::io::{self, IsTerminal, Read};
 use std::process;
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+
+    let trace = args.iter().any(|arg| arg == "--trace");
+    args.retain(|arg| arg != "--trace");
+    let options = VmOptions {
+        trace,
+        ..Default::default()
+    };
 
-    if args.len() == 1 {
-        repl();
+    let dump = args.iter().any(|arg| arg == "--dump" || arg == "-d");
+    args.retain(|arg| arg != "--dump" && arg != "-d");
+
+    if args.len() == 2 && dump {
+        dump_file(&args[1]);
+    } else if args.len() == 1 && io::stdin().is_terminal() {
+        repl(&options);
+    } else if args.len() == 1 {
+        // Run a program piped in on stdin.
+        run_file("-", &options);
     } else if args.len() == 2 {
-        run_file(&args[1]);
+        run_file(&args[1], &options);
+    } else if args.len() == 5 && args[1] == "compile" && args[3] == "-o" {
+        compile_file(&args[2], &args[4]);
     } else {
-        eprintln!("Usage: lox [path]");
+        eprintln!("Usage: lox [--trace] [path | -]");
+        eprintln!("       lox --dump <path>");
+        eprintln!("       lox compile <path> -o <output>");
         process::exit(64);
     }
 }
 
-fn repl() {
-    fn read_line(prompt: &str) -> Result<String, Box<dyn Error>> {
-        print!("{} ", prompt);
+fn repl(options: &VmOptions) {
+    let config = ReplConfig::default();
+    match lox::repl(&config, options, &mut Editor::new(), &mut io::stdout()) {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(74);
+        }
+    }
+}
 
-        // Have to flush or the prompt never gets printed.
-        io::stdout().flush().unwrap();
-        let mut buffer = String::new();
-        let _result = io::stdin().read_line(&mut buffer)?;
+/// Compiles the file at `path` and saves the chunk to `output`, to be run
+/// later by `run_file`.
+fn compile_file(path: &str, output: &str) {
+    let source = match fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error opening file '{}': {}", path, e);
+            process::exit(74);
+        }
+    };
+
+    let chunk = match lox::compile_to_chunk(&source) {
+        Ok(chunk) => chunk,
+        Err(errors) => {
+            for error in errors {
+                eprint!("{}", error.render(&source));
+            }
+            process::exit(65);
+        }
+    };
 
-        Ok(buffer)
+    if let Err(e) = fs::write(output, chunk.to_bytes()) {
+        eprintln!("Error writing file '{}': {}", output, e);
+        process::exit(74);
     }
+}
 
-    println!("Welcome to lox!");
-    let mut globals = HashMap::new();
-    loop {
-        let result = read_line(">").map(|line| vm::interpret(&line, &mut globals));
+/// Reads the whole file at `path`, where `-` means stdin.
+fn read_file(path: &str) -> io::Result<Vec<u8>> {
+    if path == "-" {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes)?;
+        Ok(bytes)
+    } else {
+        fs::read(path)
+    }
+}
 
-        if result.is_err() {
-            eprintln!("{}", result.err().unwrap());
+/// Loads the file at `path`, which holds either source code to compile or a
+/// chunk saved by `compile_file`.  Exits if the file can't be loaded.
+fn load_file(path: &str) -> Chunk {
+    let bytes = match read_file(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Error opening file '{}': {}", path, e);
+            process::exit(74);
         }
+    };
+
+    if Chunk::is_compiled(&bytes) {
+        return match Chunk::from_bytes(&bytes) {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                eprintln!("Error loading file '{}': {}", path, e);
+                process::exit(65);
+            }
+        };
     }
-}
 
-fn run_file(path: &str) {
-    let source = match fs::read_to_string(path) {
+    let source = match String::from_utf8(bytes) {
         Ok(source) => source,
         Err(e) => {
             eprintln!("Error opening file '{}': {}", path, e);
@@ -59,10 +117,33 @@ fn run_file(path: &str) {
         }
     };
 
-    let mut globals = HashMap::new();
-    match vm::interpret(&source, &mut globals) {
+    let chunk = match lox::compile_to_chunk(&source) {
+        Ok(chunk) => chunk,
+        Err(errors) => {
+            for error in errors {
+                eprint!("{}", error.render(&source));
+            }
+            process::exit(65);
+        }
+    };
+    chunk
+}
+
+/// Prints the bytecode for the file at `path` without running it.
+fn dump_file(path: &str) {
+    let chunk = load_file(path);
+    if let Err(e) = chunk.disassemble("code", &mut io::stdout()) {
+        eprintln!("{}", e);
+        process::exit(74);
+    }
+}
+
+fn run_file(path: &str, options: &VmOptions) {
+    let chunk = load_file(path);
+
+    match Vm::new().interpret_chunk(chunk, options, &mut io::stdout()) {
         Ok(_) => {}
         Err(InterpretError::Compile) => process::exit(65),
-        Err(InterpretError::Runtime) => process::exit(70),
+        Err(InterpretError::Runtime(_)) => process::exit(70),
     }
 }
@@ -0,0 +1,312 @@
+//! Mark-and-sweep collection of the objects a running program allocates.
+//!
+//! Objects are reference counted, so most garbage is freed as soon as the
+//! last value referring to it goes away.  What reference counting can't free
+//! is a cycle, such as an instance with a field that refers back to it.  The
+//! heap keeps a handle on every object the VM allocates, and a collection
+//! drops the handles on objects the program can no longer reach, emptying
+//! each one first so that any cycle through it is broken.
+//!
+//! The host may hold on to values the program can no longer reach.  An
+//! object is only emptied if every reference to it comes from the heap or
+//! from other unreachable objects; anything else is taken to be the host's,
+//! and keeps the object and everything it refers to.
+
+use std::cell::RefCell;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::Rc;
+
+use crate::object::{Closure, Obj, ObjValue, Upvalue};
+use crate::value::Value;
+
+/// How many objects may be allocated before the first collection.
+const GC_INITIAL_THRESHOLD: usize = 256;
+
+/// How much the heap may grow, relative to what survived the last
+/// collection, before the next one.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// The objects allocated by the VM.
+pub struct Heap {
+    objects: Vec<Rc<Obj>>,
+    next_gc: usize,
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Heap {
+            objects: Vec::new(),
+            next_gc: GC_INITIAL_THRESHOLD,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.len()
+    }
+
+    /// Starts tracking a newly allocated object.
+    pub fn track(&mut self, obj: Rc<Obj>) {
+        self.objects.push(obj);
+    }
+
+    /// Returns true once enough has been allocated since the last collection
+    /// to be worth another.
+    pub fn should_collect(&self) -> bool {
+        self.objects.len() >= self.next_gc
+    }
+
+    /// Frees every object the marker didn't reach, unless it's held by
+    /// something outside the heap.
+    pub fn sweep(&mut self, marker: &mut Marker) {
+        for value in self.held_elsewhere(marker) {
+            marker.mark_value(&value);
+        }
+        marker.trace();
+
+        self.objects.retain(|obj| {
+            if marker.is_marked(&obj.value) {
+                return true;
+            }
+            release(&obj.value, marker);
+            false
+        });
+
+        self.next_gc = cmp::max(self.objects.len() * GC_HEAP_GROW_FACTOR, GC_INITIAL_THRESHOLD);
+    }
+
+    /// Returns the values among the objects the marker didn't reach that have
+    /// more references than the heap and those objects account for.
+    fn held_elsewhere(&self, marker: &Marker) -> Vec<Value> {
+        let unreached: Vec<&Rc<Obj>> = self.objects.iter().filter(|obj| !marker.is_marked(&obj.value)).collect();
+
+        let mut counts = RefCounts::default();
+        for obj in unreached.iter() {
+            // The heap's handle on the object, and the object's on its
+            // contents.
+            *counts.objs.entry(Rc::as_ptr(obj)).or_insert(0) += 1;
+            *counts.contents.entry(identity(&obj.value)).or_insert(0) += 1;
+            counts.add_references(&obj.value);
+        }
+
+        let mut held = Vec::new();
+        // An upvalue is only accounted for if each closure sharing it is.
+        for (upvalue, count) in mem::take(&mut counts.upvalues).into_values() {
+            if let Upvalue::Closed(value) = &*upvalue.borrow() {
+                if Rc::strong_count(upvalue) == count {
+                    counts.add_value(value);
+                } else {
+                    held.push(value.clone());
+                }
+            }
+        }
+
+        for obj in unreached {
+            let objs = counts.objs.get(&Rc::as_ptr(obj)).copied().unwrap_or(0);
+            let contents = counts.contents.get(&identity(&obj.value)).copied().unwrap_or(0);
+            let held_obj = Rc::strong_count(obj) > objs;
+            let held_contents = contents_strong_count(&obj.value).is_some_and(|count| count > contents);
+            if held_obj || held_contents {
+                held.push(Value::Obj(Rc::clone(obj)));
+            }
+        }
+        held
+    }
+}
+
+/// The references from a set of objects, counted by what they refer to.
+#[derive(Default)]
+struct RefCounts<'a> {
+    objs: HashMap<*const Obj, usize>,
+    contents: HashMap<*const (), usize>,
+    upvalues: HashMap<*const RefCell<Upvalue>, (&'a Rc<RefCell<Upvalue>>, usize)>,
+}
+
+impl<'a> RefCounts<'a> {
+    fn add_value(&mut self, value: &Value) {
+        if let Value::Obj(obj) = value {
+            *self.objs.entry(Rc::as_ptr(obj)).or_insert(0) += 1;
+        }
+    }
+
+    fn add_contents<T>(&mut self, contents: &Rc<T>) {
+        *self.contents.entry(Rc::as_ptr(contents) as *const ()).or_insert(0) += 1;
+    }
+
+    /// Counts what `value` refers to.
+    fn add_references(&mut self, value: &'a ObjValue) {
+        match value {
+            ObjValue::Closure(closure) => {
+                for upvalue in closure.upvalues.iter() {
+                    let entry = self.upvalues.entry(Rc::as_ptr(upvalue));
+                    entry.or_insert((upvalue, 0)).1 += 1;
+                }
+            }
+            ObjValue::Class(class) => {
+                for method in class.methods.borrow().values() {
+                    self.add_contents(method);
+                }
+            }
+            ObjValue::Instance(instance) => {
+                self.add_contents(&instance.class);
+                for field in instance.fields.borrow().values() {
+                    self.add_value(field);
+                }
+            }
+            ObjValue::BoundMethod(bound) => {
+                self.add_value(&bound.receiver);
+                self.add_contents(&bound.method);
+            }
+            ObjValue::List(list) => {
+                for item in list.items.borrow().iter() {
+                    self.add_value(item);
+                }
+            }
+            ObjValue::Map(map) => {
+                for value in map.entries.borrow().values() {
+                    self.add_value(value);
+                }
+            }
+            ObjValue::String(_) | ObjValue::Function(_) | ObjValue::Native(_) => {}
+        }
+    }
+}
+
+/// Returns how many references there are to the contents of an object that
+/// `release` would empty.
+fn contents_strong_count(value: &ObjValue) -> Option<usize> {
+    let count = match value {
+        ObjValue::Closure(closure) => Rc::strong_count(closure),
+        ObjValue::Class(class) => Rc::strong_count(class),
+        ObjValue::Instance(instance) => Rc::strong_count(instance),
+        ObjValue::List(list) => Rc::strong_count(list),
+        ObjValue::Map(map) => Rc::strong_count(map),
+        ObjValue::String(_) | ObjValue::Function(_) | ObjValue::Native(_) | ObjValue::BoundMethod(_) => return None,
+    };
+    Some(count)
+}
+
+/// Empties an unreachable object so that any cycle it's part of no longer
+/// keeps it alive.
+fn release(value: &ObjValue, marker: &Marker) {
+    match value {
+        // An upvalue can be shared with a closure that's still reachable.
+        ObjValue::Closure(closure) => {
+            for upvalue in closure.upvalues.iter() {
+                if !marker.is_upvalue_marked(upvalue) {
+                    *upvalue.borrow_mut() = Upvalue::Closed(Value::Nil);
+                }
+            }
+        }
+        ObjValue::Class(class) => class.methods.borrow_mut().clear(),
+        ObjValue::Instance(instance) => instance.fields.borrow_mut().clear(),
+        ObjValue::List(list) => list.items.borrow_mut().clear(),
+        ObjValue::Map(map) => map.entries.borrow_mut().clear(),
+        ObjValue::String(_) | ObjValue::Function(_) | ObjValue::Native(_) | ObjValue::BoundMethod(_) => {}
+    }
+}
+
+/// Identifies an object by the address of its contents.  Several `Obj`s can
+/// share the same contents, as when a closure is both a value and a method,
+/// so the `Obj` itself won't do.
+fn identity(value: &ObjValue) -> *const () {
+    match value {
+        ObjValue::String(s) => s as *const String as *const (),
+        ObjValue::Function(function) => Rc::as_ptr(function) as *const (),
+        ObjValue::Closure(closure) => Rc::as_ptr(closure) as *const (),
+        ObjValue::Native(native) => Rc::as_ptr(native) as *const (),
+        ObjValue::Class(class) => Rc::as_ptr(class) as *const (),
+        ObjValue::Instance(instance) => Rc::as_ptr(instance) as *const (),
+        ObjValue::BoundMethod(bound) => Rc::as_ptr(bound) as *const (),
+        ObjValue::List(list) => Rc::as_ptr(list) as *const (),
+        ObjValue::Map(map) => Rc::as_ptr(map) as *const (),
+    }
+}
+
+/// Finds the objects reachable from a set of roots.
+#[derive(Default)]
+pub struct Marker {
+    marked: HashSet<*const ()>,
+    // Marked objects whose references haven't been followed yet.
+    gray: Vec<ObjValue>,
+}
+
+impl Marker {
+    pub fn mark_value(&mut self, value: &Value) {
+        if let Value::Obj(obj) = value {
+            self.mark_object(&obj.value);
+        }
+    }
+
+    pub fn mark_object(&mut self, value: &ObjValue) {
+        if !self.marked.insert(identity(value)) {
+            return;
+        }
+        match value {
+            ObjValue::String(_) | ObjValue::Function(_) | ObjValue::Native(_) => {}
+            _ => self.gray.push(value.clone()),
+        }
+    }
+
+    pub fn mark_closure(&mut self, closure: &Rc<Closure>) {
+        self.mark_object(&ObjValue::Closure(Rc::clone(closure)));
+    }
+
+    pub fn mark_upvalue(&mut self, upvalue: &Rc<RefCell<Upvalue>>) {
+        if !self.marked.insert(Rc::as_ptr(upvalue) as *const ()) {
+            return;
+        }
+        // An open upvalue refers to a stack slot, which is a root anyway.
+        if let Upvalue::Closed(value) = &*upvalue.borrow() {
+            self.mark_value(value);
+        }
+    }
+
+    /// Marks everything reachable from the objects marked so far.
+    pub fn trace(&mut self) {
+        while let Some(value) = self.gray.pop() {
+            match &value {
+                ObjValue::Closure(closure) => {
+                    for upvalue in closure.upvalues.iter() {
+                        self.mark_upvalue(upvalue);
+                    }
+                }
+                ObjValue::Class(class) => {
+                    for method in class.methods.borrow().values() {
+                        self.mark_closure(method);
+                    }
+                }
+                ObjValue::Instance(instance) => {
+                    self.mark_object(&ObjValue::Class(Rc::clone(&instance.class)));
+                    for field in instance.fields.borrow().values() {
+                        self.mark_value(field);
+                    }
+                }
+                ObjValue::BoundMethod(bound) => {
+                    self.mark_value(&bound.receiver);
+                    self.mark_closure(&bound.method);
+                }
+                ObjValue::List(list) => {
+                    for item in list.items.borrow().iter() {
+                        self.mark_value(item);
+                    }
+                }
+                ObjValue::Map(map) => {
+                    for value in map.entries.borrow().values() {
+                        self.mark_value(value);
+                    }
+                }
+                ObjValue::String(_) | ObjValue::Function(_) | ObjValue::Native(_) => {}
+            }
+        }
+    }
+
+    fn is_marked(&self, value: &ObjValue) -> bool {
+        self.marked.contains(&identity(value))
+    }
+
+    fn is_upvalue_marked(&self, upvalue: &Rc<RefCell<Upvalue>>) -> bool {
+        self.marked.contains(&(Rc::as_ptr(upvalue) as *const ()))
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::object::{Native, NativeFn, Obj};
+use crate::value::Value;
+
+/// Adds a native function to the globals under `name`.
+pub fn define_native(
+    globals: &mut HashMap<String, Value>,
+    name: &str,
+    arity: usize,
+    function: NativeFn,
+) {
+    insert_native(globals, Native::new(name, arity, function));
+}
+
+fn insert_native(globals: &mut HashMap<String, Value>, native: Native) {
+    let name = native.name.clone();
+    globals.insert(name, Value::Obj(Obj::new_native(native)));
+}
+
+/// Adds the standard native functions to the globals.
+///
+/// `assert` lets a script check its own results:
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut globals = HashMap::new();
+/// lox::define_natives(&mut globals);
+/// assert!(lox::interpret("assert(1 == 1, \"maths is broken\");", &mut globals).is_ok());
+/// ```
+///
+/// `write` prints a value without a newline after it:
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut globals = HashMap::new();
+/// lox::define_natives(&mut globals);
+/// let mut out = Vec::new();
+/// lox::interpret_with_writer("write(\"a\"); print 1;", &mut globals, &mut out).unwrap();
+/// assert_eq!(out, b"a1\n");
+/// ```
+pub fn define_natives(globals: &mut HashMap<String, Value>) {
+    define_native(globals, "clock", 0, clock);
+    define_native(globals, "len", 1, len);
+    define_native(globals, "substr", 3, substr);
+    define_native(globals, "abs", 1, abs);
+    define_native(globals, "floor", 1, floor);
+    define_native(globals, "ceil", 1, ceil);
+    define_native(globals, "sqrt", 1, sqrt);
+    define_native(globals, "min", 2, min);
+    define_native(globals, "max", 2, max);
+    define_native(globals, "pow", 2, pow);
+    define_native(globals, "type", 1, type_of);
+    define_native(globals, "num", 1, num);
+    define_native(globals, "str", 1, str);
+    insert_native(globals, Native::with_optional_args("assert", 1, 2, assert));
+    insert_native(globals, Native::reading_input("readline", 0, readline));
+    insert_native(globals, Native::writing_output("write", 1, write));
+}
+
+fn number_error<T>(function: &str) -> Result<T, String> {
+    Err(format!("{}() arguments must be numbers.", function))
+}
+
+/// Returns a numeric argument as a float.
+fn float_arg(function: &str, arg: &Value) -> Result<f64, String> {
+    match arg.as_f64() {
+        Some(x) => Ok(x),
+        None => number_error(function),
+    }
+}
+
+/// Stops the program with a runtime error, using the optional second argument
+/// as the message, unless the first argument is truthy.
+fn assert(args: &[Value]) -> Result<Value, String> {
+    if !args[0].is_falsey() {
+        return Ok(Value::Nil);
+    }
+    match args.get(1) {
+        Some(message) => Err(message.to_string()),
+        None => Err(String::from("Assertion failed.")),
+    }
+}
+
+/// Returns the name of the argument's type.
+fn type_of(args: &[Value]) -> Result<Value, String> {
+    Ok(Value::new_string(args[0].type_name()))
+}
+
+/// Converts a string, or a bool, to a number.  A string holding a whole
+/// number gives an integer, as the same literal in source would.
+fn num(args: &[Value]) -> Result<Value, String> {
+    let text = match &args[0] {
+        Value::Int(_) | Value::Number(_) => return Ok(args[0].clone()),
+        Value::Bool(x) => return Ok(Value::Int(*x as i64)),
+        value => match value.as_str() {
+            Some(text) => text.trim(),
+            None => return Err(String::from("num() expects a string, number or bool.")),
+        },
+    };
+
+    if let Ok(x) = text.parse() {
+        return Ok(Value::Int(x));
+    }
+    // Rust also reads words such as "inf" and "NaN", which Lox can't write.
+    match text.parse::<f64>() {
+        Ok(x) if x.is_finite() => Ok(Value::Number(x)),
+        _ => Err(format!("num() can't convert '{}' to a number.", text)),
+    }
+}
+
+/// Converts any value to a string, the way `print` would write it.
+fn str(args: &[Value]) -> Result<Value, String> {
+    if args[0].as_str().is_some() {
+        return Ok(args[0].clone());
+    }
+    Ok(Value::new_string(&args[0].to_string()))
+}
+
+/// Reads a line of input, without its line ending, or returns nil once the
+/// input runs out.
+fn readline(input: &mut dyn BufRead, _args: &[Value]) -> Result<Value, String> {
+    let mut line = String::new();
+    match input.read_line(&mut line) {
+        Ok(0) => Ok(Value::Nil),
+        Ok(_) => {
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            Ok(Value::new_string(line))
+        }
+        Err(e) => Err(format!("Could not read input: {}", e)),
+    }
+}
+
+/// Writes a value to the output as `print` does, but without a newline.
+fn write(out: &mut dyn Write, args: &[Value]) -> Result<Value, String> {
+    write!(out, "{}", args[0]).map_err(|e| format!("Could not write output: {}", e))?;
+    Ok(Value::Nil)
+}
+
+/// Returns the number of seconds since the Unix epoch.
+fn clock(_args: &[Value]) -> Result<Value, String> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |elapsed| elapsed.as_secs_f64());
+    Ok(Value::Number(now))
+}
+
+fn abs(args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Int(x) => x
+            .checked_abs()
+            .map(Value::Int)
+            .ok_or_else(|| String::from("Integer overflow.")),
+        Value::Number(x) => Ok(Value::Number(x.abs())),
+        _ => number_error("abs"),
+    }
+}
+
+fn floor(args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Int(x) => Ok(Value::Int(*x)),
+        Value::Number(x) => Ok(Value::Number(x.floor())),
+        _ => number_error("floor"),
+    }
+}
+
+fn ceil(args: &[Value]) -> Result<Value, String> {
+    match &args[0] {
+        Value::Int(x) => Ok(Value::Int(*x)),
+        Value::Number(x) => Ok(Value::Number(x.ceil())),
+        _ => number_error("ceil"),
+    }
+}
+
+fn sqrt(args: &[Value]) -> Result<Value, String> {
+    let x = float_arg("sqrt", &args[0])?;
+    Ok(Value::Number(x.sqrt()))
+}
+
+/// Returns the smaller argument, keeping its type.
+fn min(args: &[Value]) -> Result<Value, String> {
+    let a = float_arg("min", &args[0])?;
+    let b = float_arg("min", &args[1])?;
+    let smaller = if b < a { &args[1] } else { &args[0] };
+    Ok(smaller.clone())
+}
+
+/// Returns the larger argument, keeping its type.
+fn max(args: &[Value]) -> Result<Value, String> {
+    let a = float_arg("max", &args[0])?;
+    let b = float_arg("max", &args[1])?;
+    let larger = if b > a { &args[1] } else { &args[0] };
+    Ok(larger.clone())
+}
+
+/// Raises a number to a power, the same as the `**` operator.
+fn pow(args: &[Value]) -> Result<Value, String> {
+    match (&args[0], &args[1]) {
+        (Value::Int(a), Value::Int(b)) if *b >= 0 => u32::try_from(*b)
+            .ok()
+            .and_then(|b| a.checked_pow(b))
+            .map(Value::Int)
+            .ok_or_else(|| String::from("Integer overflow.")),
+        (a, b) => {
+            let a = float_arg("pow", a)?;
+            let b = float_arg("pow", b)?;
+            Ok(Value::Number(a.powf(b)))
+        }
+    }
+}
+
+/// Returns the number of characters in a string or items in a list.
+fn len(args: &[Value]) -> Result<Value, String> {
+    let len = if let Some(len) = args[0].as_obj().and_then(|obj| obj.char_len()) {
+        len
+    } else if let Some(list) = args[0].as_obj().and_then(|obj| obj.as_list()) {
+        list.items.borrow().len()
+    } else {
+        return Err(String::from("len() expects a string or a list."));
+    };
+    Ok(Value::Int(len as i64))
+}
+
+/// Returns the characters of a string from `start` up to, but not including,
+/// `end`.
+fn substr(args: &[Value]) -> Result<Value, String> {
+    let (s, start, end) = match (args[0].as_obj(), &args[1], &args[2]) {
+        (Some(s), Value::Int(start), Value::Int(end)) if s.is_string() => (s, *start, *end),
+        _ => return Err(String::from("substr() expects a string and two integers.")),
+    };
+
+    let range = usize::try_from(start).ok().zip(usize::try_from(end).ok());
+    match range.and_then(|(start, end)| s.char_slice(start, end)) {
+        Some(sub) => Ok(Value::new_string(sub)),
+        None => Err(format!(
+            "substr() range {}..{} out of bounds for length {}.",
+            start,
+            end,
+            s.char_len().unwrap_or(0)
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn len_counts_chars() {
+        assert!(matches!(len(&[Value::new_string("héllo")]), Ok(Value::Int(5))));
+        assert_eq!(len(&[Value::Int(1)]).err().unwrap(), "len() expects a string or a list.");
+    }
+
+    #[test]
+    fn substr_slices_chars() {
+        let s = Value::new_string("héllo");
+        assert!(substr(&[s.clone(), Value::Int(1), Value::Int(4)]).ok().unwrap() == Value::new_string("éll"));
+        assert!(substr(&[s.clone(), Value::Int(2), Value::Int(2)]).ok().unwrap() == Value::new_string(""));
+
+        let error = substr(&[s, Value::Int(3), Value::Int(6)]).err().unwrap();
+        assert_eq!(error, "substr() range 3..6 out of bounds for length 5.");
+    }
+
+    #[test]
+    fn numeric_natives() {
+        assert!(matches!(sqrt(&[Value::Int(16)]), Ok(Value::Number(x)) if x == 4.0));
+        assert!(matches!(max(&[Value::Int(3), Value::Int(7)]), Ok(Value::Int(7))));
+        assert!(matches!(min(&[Value::Int(3), Value::Number(2.5)]), Ok(Value::Number(x)) if x == 2.5));
+        assert!(matches!(abs(&[Value::Int(-3)]), Ok(Value::Int(3))));
+        assert!(matches!(floor(&[Value::Number(1.5)]), Ok(Value::Number(x)) if x == 1.0));
+        assert!(matches!(ceil(&[Value::Number(1.5)]), Ok(Value::Number(x)) if x == 2.0));
+        assert!(matches!(pow(&[Value::Int(2), Value::Int(10)]), Ok(Value::Int(1024))));
+        assert_eq!(abs(&[Value::new_string("x")]).err().unwrap(), "abs() arguments must be numbers.");
+        assert_eq!(abs(&[Value::Int(i64::MIN)]).err().unwrap(), "Integer overflow.");
+    }
+
+    #[test]
+    fn assert_stops_on_falsey_values() {
+        assert!(matches!(assert(&[Value::Int(0)]), Ok(Value::Nil)));
+        assert_eq!(assert(&[Value::Bool(false), Value::new_string("boom")]).err().unwrap(), "boom");
+        assert_eq!(assert(&[Value::Nil]).err().unwrap(), "Assertion failed.");
+    }
+
+    #[test]
+    fn converting_to_numbers() {
+        assert!(matches!(num(&[Value::new_string("10")]), Ok(Value::Int(10))));
+        assert!(matches!(num(&[Value::new_string(" 3.5 ")]), Ok(Value::Number(x)) if x == 3.5));
+        assert!(matches!(num(&[Value::Bool(true)]), Ok(Value::Int(1))));
+        assert_eq!(num(&[Value::new_string("ten")]).err().unwrap(), "num() can't convert 'ten' to a number.");
+        assert_eq!(num(&[Value::new_string("inf")]).err().unwrap(), "num() can't convert 'inf' to a number.");
+        assert_eq!(num(&[Value::Nil]).err().unwrap(), "num() expects a string, number or bool.");
+    }
+
+    #[test]
+    fn converting_to_strings() {
+        assert!(str(&[Value::Bool(true)]).ok().unwrap() == Value::new_string("true"));
+        assert!(str(&[Value::Number(1.5)]).ok().unwrap() == Value::new_string("1.5"));
+        assert!(str(&[Value::Nil]).ok().unwrap() == Value::new_string("nil"));
+    }
+
+    #[test]
+    fn reading_lines() {
+        let mut input = "one\r\ntwo\nthree".as_bytes();
+        let mut lines = Vec::new();
+        while let Ok(Value::Obj(line)) = readline(&mut input, &[]) {
+            lines.push(String::from(line.as_str().unwrap()));
+        }
+        assert_eq!(lines, ["one", "two", "three"]);
+        assert!(matches!(readline(&mut input, &[]), Ok(Value::Nil)));
+    }
+
+    #[test]
+    fn writing_without_a_newline() {
+        let mut out = Vec::new();
+        assert!(matches!(write(&mut out, &[Value::new_string("a")]), Ok(Value::Nil)));
+        write(&mut out, &[Value::Int(1)]).ok().unwrap();
+        write(&mut out, &[Value::Number(2.5)]).ok().unwrap();
+        assert_eq!(out, b"a12.5");
+    }
+}
@@ -1,34 +1,398 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::fmt;
+use std::io::{BufRead, Write};
+use std::iter;
 use std::rc::Rc;
 
-#[derive(Clone, PartialEq)]
+use crate::chunk::Chunk;
+use crate::value::{NumberFormat, Value};
+
+pub struct Function {
+    pub name: String,
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub chunk: Chunk,
+}
+
+impl Function {
+    pub fn new(name: &str, arity: usize, upvalue_count: usize, chunk: Chunk) -> Self {
+        let name = String::from(name);
+        Function {
+            name,
+            arity,
+            upvalue_count,
+            chunk,
+        }
+    }
+
+    /// Wraps the chunk compiled from a whole program.  The script has an
+    /// empty name.
+    pub fn script(chunk: Chunk) -> Self {
+        Function::new("", 0, 0, chunk)
+    }
+}
+
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.name.is_empty() {
+            write!(f, "<script>")
+        } else {
+            write!(f, "<fn {}>", self.name)
+        }
+    }
+}
+
+/// A variable captured by a closure.  It refers to a slot on the VM's stack
+/// while that slot is live, and holds the value itself once it's closed.
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+/// A function together with the variables it has captured.
+pub struct Closure {
+    pub function: Rc<Function>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+}
+
+impl Closure {
+    pub fn new(function: Rc<Function>, upvalues: Vec<Rc<RefCell<Upvalue>>>) -> Self {
+        Closure { function, upvalues }
+    }
+}
+
+pub struct Class {
+    pub name: String,
+    pub methods: RefCell<HashMap<String, Rc<Closure>>>,
+}
+
+impl Class {
+    pub fn new(name: &str) -> Self {
+        let name = String::from(name);
+        let methods = RefCell::new(HashMap::new());
+        Class { name, methods }
+    }
+}
+
+pub struct Instance {
+    pub class: Rc<Class>,
+    pub fields: RefCell<HashMap<String, Value>>,
+}
+
+impl Instance {
+    pub fn new(class: Rc<Class>) -> Self {
+        let fields = RefCell::new(HashMap::new());
+        Instance { class, fields }
+    }
+}
+
+/// A method together with the instance it was accessed from.
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Rc<Closure>,
+}
+
+impl BoundMethod {
+    pub fn new(receiver: Value, method: Rc<Closure>) -> Self {
+        BoundMethod { receiver, method }
+    }
+}
+
+/// A growable sequence of values.
+pub struct List {
+    pub items: RefCell<Vec<Value>>,
+    // Set while the list is being displayed, so that a list that holds
+    // itself isn't displayed forever.
+    displaying: Cell<bool>,
+}
+
+impl List {
+    pub fn new(items: Vec<Value>) -> Self {
+        let items = RefCell::new(items);
+        List {
+            items,
+            displaying: Cell::new(false),
+        }
+    }
+}
+
+impl List {
+    fn fmt_with(&self, f: &mut fmt::Formatter, format: NumberFormat) -> fmt::Result {
+        if self.displaying.replace(true) {
+            return write!(f, "[...]");
+        }
+        let result = self.fmt_items(f, format);
+        self.displaying.set(false);
+        result
+    }
+
+    fn fmt_items(&self, f: &mut fmt::Formatter, format: NumberFormat) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, item) in self.items.borrow().iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            item.fmt_with(f, format)?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// A table of values keyed by string.  Reading a key that isn't in the map
+/// gives nil; writing one adds it.
+pub struct Map {
+    pub entries: RefCell<HashMap<String, Value>>,
+    // Set while the map is being displayed, as for a list.
+    displaying: Cell<bool>,
+}
+
+impl Map {
+    pub fn new(entries: HashMap<String, Value>) -> Self {
+        let entries = RefCell::new(entries);
+        Map {
+            entries,
+            displaying: Cell::new(false),
+        }
+    }
+}
+
+impl Map {
+    fn fmt_with(&self, f: &mut fmt::Formatter, format: NumberFormat) -> fmt::Result {
+        if self.displaying.replace(true) {
+            return write!(f, "{{...}}");
+        }
+        let result = self.fmt_entries(f, format);
+        self.displaying.set(false);
+        result
+    }
+
+    fn fmt_entries(&self, f: &mut fmt::Formatter, format: NumberFormat) -> fmt::Result {
+        // Sorted by key, so that printing a map always gives the same output.
+        let entries = self.entries.borrow();
+        let mut keys: Vec<&String> = entries.keys().collect();
+        keys.sort();
+
+        write!(f, "{{")?;
+        for (i, key) in keys.into_iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: ", key)?;
+            entries[key].fmt_with(f, format)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+/// A function implemented in Rust that Lox code can call.  An error is
+/// reported as a runtime error.
+pub type NativeFn = fn(&[Value]) -> Result<Value, String>;
+
+/// A native function that also reads from the VM's input.
+pub type InputFn = fn(&mut dyn BufRead, &[Value]) -> Result<Value, String>;
+
+/// A native function that also writes to the VM's output.
+pub type OutputFn = fn(&mut dyn Write, &[Value]) -> Result<Value, String>;
+
+pub enum NativeBody {
+    Plain(NativeFn),
+    Input(InputFn),
+    Output(OutputFn),
+}
+
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    // The most arguments the function takes.  Any past `arity` are optional.
+    pub max_arity: usize,
+    pub function: NativeBody,
+}
+
+impl Native {
+    pub fn new(name: &str, arity: usize, function: NativeFn) -> Self {
+        Native::with_optional_args(name, arity, arity, function)
+    }
+
+    pub fn reading_input(name: &str, arity: usize, function: InputFn) -> Self {
+        let name = String::from(name);
+        Native {
+            name,
+            arity,
+            max_arity: arity,
+            function: NativeBody::Input(function),
+        }
+    }
+
+    pub fn writing_output(name: &str, arity: usize, function: OutputFn) -> Self {
+        let name = String::from(name);
+        Native {
+            name,
+            arity,
+            max_arity: arity,
+            function: NativeBody::Output(function),
+        }
+    }
+
+    /// Makes a native function that takes between `arity` and `max_arity`
+    /// arguments.
+    pub fn with_optional_args(name: &str, arity: usize, max_arity: usize, function: NativeFn) -> Self {
+        let name = String::from(name);
+        Native {
+            name,
+            arity,
+            max_arity,
+            function: NativeBody::Plain(function),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub enum ObjValue {
     String(String),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Native(Rc<Native>),
+    Class(Rc<Class>),
+    Instance(Rc<Instance>),
+    BoundMethod(Rc<BoundMethod>),
+    List(Rc<List>),
+    Map(Rc<Map>),
 }
 
 impl ObjValue {
-    pub fn is_string(&self) -> bool {
+    pub fn type_name(&self) -> &'static str {
         match self {
-            ObjValue::String(_) => true,
+            ObjValue::String(_) => "string",
+            ObjValue::Function(_) | ObjValue::Closure(_) | ObjValue::Native(_) | ObjValue::BoundMethod(_) => "function",
+            ObjValue::Class(_) => "class",
+            ObjValue::Instance(_) => "instance",
+            ObjValue::List(_) => "list",
+            ObjValue::Map(_) => "map",
         }
     }
 
+    pub fn is_string(&self) -> bool {
+        matches!(self, ObjValue::String(_))
+    }
+
     pub fn as_str(&self) -> Option<&str> {
         match self {
-            ObjValue::String(s) => Some(&s),
+            ObjValue::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    // Strings are stored as UTF-8, but Lox measures and indexes them by
+    // character (Unicode scalar value), never by byte.  Anything that takes a
+    // length or position from Lox code should go through these helpers.
+
+    /// Returns the number of characters in a string.
+    pub fn char_len(&self) -> Option<usize> {
+        self.as_str().map(|s| s.chars().count())
+    }
+
+    /// Returns the characters of a string from `start` up to, but not
+    /// including, `end`.  Returns None if the range is out of bounds.
+    pub fn char_slice(&self, start: usize, end: usize) -> Option<&str> {
+        let s = self.as_str()?;
+        if start > end {
+            return None;
+        }
+
+        // Byte offsets of each character, plus the end of the string.
+        let mut offsets = s.char_indices().map(|(i, _)| i).chain(iter::once(s.len()));
+        let start_byte = offsets.nth(start)?;
+        let end_byte = if end == start {
+            start_byte
+        } else {
+            offsets.nth(end - start - 1)?
+        };
+        Some(&s[start_byte..end_byte])
+    }
+
+    pub fn as_function(&self) -> Option<&Rc<Function>> {
+        match self {
+            ObjValue::Function(function) => Some(function),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<&Rc<Instance>> {
+        match self {
+            ObjValue::Instance(instance) => Some(instance),
+            _ => None,
+        }
+    }
+
+    pub fn as_class(&self) -> Option<&Rc<Class>> {
+        match self {
+            ObjValue::Class(class) => Some(class),
+            _ => None,
+        }
+    }
+
+    pub fn as_closure(&self) -> Option<&Rc<Closure>> {
+        match self {
+            ObjValue::Closure(closure) => Some(closure),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<&Rc<List>> {
+        match self {
+            ObjValue::List(list) => Some(list),
+            _ => None,
+        }
+    }
+
+    pub fn as_map(&self) -> Option<&Rc<Map>> {
+        match self {
+            ObjValue::Map(map) => Some(map),
+            _ => None,
+        }
+    }
+}
+
+impl PartialEq for ObjValue {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ObjValue::String(a), ObjValue::String(b)) => a == b,
+            (ObjValue::Function(a), ObjValue::Function(b)) => Rc::ptr_eq(a, b),
+            (ObjValue::Closure(a), ObjValue::Closure(b)) => Rc::ptr_eq(a, b),
+            (ObjValue::Native(a), ObjValue::Native(b)) => Rc::ptr_eq(a, b),
+            (ObjValue::Class(a), ObjValue::Class(b)) => Rc::ptr_eq(a, b),
+            (ObjValue::Instance(a), ObjValue::Instance(b)) => Rc::ptr_eq(a, b),
+            (ObjValue::BoundMethod(a), ObjValue::BoundMethod(b)) => Rc::ptr_eq(a, b),
+            (ObjValue::List(a), ObjValue::List(b)) => Rc::ptr_eq(a, b),
+            (ObjValue::Map(a), ObjValue::Map(b)) => Rc::ptr_eq(a, b),
+            _ => false,
         }
     }
 }
 
 impl fmt::Display for ObjValue {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, NumberFormat::default())
+    }
+}
+
+impl ObjValue {
+    pub(crate) fn fmt_with(&self, f: &mut fmt::Formatter, format: NumberFormat) -> fmt::Result {
         match self {
             ObjValue::String(x) => write!(f, "{}", x),
+            ObjValue::Function(x) => write!(f, "{}", x),
+            ObjValue::Closure(x) => write!(f, "{}", x.function),
+            ObjValue::Native(x) => write!(f, "<native fn {}>", x.name),
+            ObjValue::Class(x) => write!(f, "{}", x.name),
+            ObjValue::Instance(x) => write!(f, "{} instance", x.class.name),
+            ObjValue::BoundMethod(x) => write!(f, "{}", x.method.function),
+            ObjValue::List(x) => x.fmt_with(f, format),
+            ObjValue::Map(x) => x.fmt_with(f, format),
         }
     }
 }
 
-#[derive(Clone)]
 pub struct Obj {
     pub value: ObjValue,
 }
@@ -40,6 +404,54 @@ impl Obj {
         Rc::new(obj)
     }
 
+    pub fn new_function(function: Function) -> Rc<Obj> {
+        let value = ObjValue::Function(Rc::new(function));
+        let obj = Obj { value };
+        Rc::new(obj)
+    }
+
+    pub fn new_closure(closure: Closure) -> Rc<Obj> {
+        let value = ObjValue::Closure(Rc::new(closure));
+        let obj = Obj { value };
+        Rc::new(obj)
+    }
+
+    pub fn new_class(class: Class) -> Rc<Obj> {
+        let value = ObjValue::Class(Rc::new(class));
+        let obj = Obj { value };
+        Rc::new(obj)
+    }
+
+    pub fn new_instance(instance: Instance) -> Rc<Obj> {
+        let value = ObjValue::Instance(Rc::new(instance));
+        let obj = Obj { value };
+        Rc::new(obj)
+    }
+
+    pub fn new_bound_method(bound: BoundMethod) -> Rc<Obj> {
+        let value = ObjValue::BoundMethod(Rc::new(bound));
+        let obj = Obj { value };
+        Rc::new(obj)
+    }
+
+    pub fn new_native(native: Native) -> Rc<Obj> {
+        let value = ObjValue::Native(Rc::new(native));
+        let obj = Obj { value };
+        Rc::new(obj)
+    }
+
+    pub fn new_list(list: List) -> Rc<Obj> {
+        let value = ObjValue::List(Rc::new(list));
+        let obj = Obj { value };
+        Rc::new(obj)
+    }
+
+    pub fn new_map(map: Map) -> Rc<Obj> {
+        let value = ObjValue::Map(Rc::new(map));
+        let obj = Obj { value };
+        Rc::new(obj)
+    }
+
     pub fn is_string(&self) -> bool {
         self.value.is_string()
     }
@@ -47,6 +459,34 @@ impl Obj {
     pub fn as_str(&self) -> Option<&str> {
         self.value.as_str()
     }
+
+    pub fn char_len(&self) -> Option<usize> {
+        self.value.char_len()
+    }
+
+    pub fn char_slice(&self, start: usize, end: usize) -> Option<&str> {
+        self.value.char_slice(start, end)
+    }
+
+    pub fn as_function(&self) -> Option<&Rc<Function>> {
+        self.value.as_function()
+    }
+
+    pub fn as_instance(&self) -> Option<&Rc<Instance>> {
+        self.value.as_instance()
+    }
+
+    pub fn as_class(&self) -> Option<&Rc<Class>> {
+        self.value.as_class()
+    }
+
+    pub fn as_closure(&self) -> Option<&Rc<Closure>> {
+        self.value.as_closure()
+    }
+
+    pub fn as_list(&self) -> Option<&Rc<List>> {
+        self.value.as_list()
+    }
 }
 
 impl PartialEq for Obj {
@@ -59,3 +499,36 @@ impl fmt::Display for Obj {
         write!(f, "{}", self.value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_holding_itself() {
+        let list = Obj::new_list(List::new(vec![Value::Int(1)]));
+        let items = &list.as_list().unwrap().items;
+        items.borrow_mut().push(Value::Obj(Rc::clone(&list)));
+
+        assert_eq!(list.to_string(), "[1, [...]]");
+        // Break the cycle so the list is freed.
+        items.borrow_mut().clear();
+    }
+
+    #[test]
+    fn map_holding_itself() {
+        let map = Obj::new_map(Map::new(HashMap::new()));
+        let entries = &map.value.as_map().unwrap().entries;
+        entries.borrow_mut().insert(String::from("self"), Value::Obj(Rc::clone(&map)));
+
+        assert_eq!(map.to_string(), "{self: {...}}");
+        entries.borrow_mut().clear();
+    }
+
+    #[test]
+    fn list_holding_another_twice() {
+        let inner = Value::Obj(Obj::new_list(List::new(vec![Value::Int(1)])));
+        let outer = Obj::new_list(List::new(vec![inner.clone(), inner]));
+        assert_eq!(outer.to_string(), "[[1], [1]]");
+    }
+}
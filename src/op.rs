@@ -21,3 +21,32 @@ pub const OP_PRINT: u8 = 19;
 pub const OP_JUMP: u8 = 20;
 pub const OP_JUMP_IF_FALSE: u8 = 21;
 pub const OP_RETURN: u8 = 22;
+pub const OP_MODULO: u8 = 23;
+pub const OP_LOOP: u8 = 24;
+pub const OP_CALL: u8 = 25;
+pub const OP_CLOSURE: u8 = 26;
+pub const OP_GET_UPVALUE: u8 = 27;
+pub const OP_SET_UPVALUE: u8 = 28;
+pub const OP_CLOSE_UPVALUE: u8 = 29;
+pub const OP_CLASS: u8 = 30;
+pub const OP_GET_PROPERTY: u8 = 31;
+pub const OP_SET_PROPERTY: u8 = 32;
+pub const OP_METHOD: u8 = 33;
+pub const OP_CONSTANT_LONG: u8 = 34;
+pub const OP_LIST: u8 = 35;
+pub const OP_GET_INDEX: u8 = 36;
+pub const OP_SET_INDEX: u8 = 37;
+pub const OP_POWER: u8 = 38;
+pub const OP_BIT_AND: u8 = 39;
+pub const OP_BIT_OR: u8 = 40;
+pub const OP_BIT_XOR: u8 = 41;
+pub const OP_SHIFT_LEFT: u8 = 42;
+pub const OP_SHIFT_RIGHT: u8 = 43;
+pub const OP_INHERIT: u8 = 44;
+pub const OP_GET_SUPER: u8 = 45;
+pub const OP_MAP: u8 = 46;
+pub const OP_JUMP_LONG: u8 = 47;
+pub const OP_JUMP_IF_FALSE_LONG: u8 = 48;
+pub const OP_LOOP_LONG: u8 = 49;
+pub const OP_TAIL_CALL: u8 = 50;
+pub const OP_DEFINE_CONST_GLOBAL: u8 = 51;
@@ -0,0 +1,252 @@
+//! An interactive prompt that runs each statement as it's entered.
+
+use std::io::{self, BufRead, Write};
+
+use crate::chunk::Chunk;
+use crate::compiler::{self, CompileOptions, ParseError};
+use crate::vm::{Vm, VmOptions};
+
+/// Where the REPL gets its input.  Anything that implements `BufRead` gives
+/// plain lines; a line editor can do more, such as recalling earlier lines.
+pub trait LineReader {
+    /// Reads a line, keeping its newline, or returns None at the end of the
+    /// input.  The REPL has already printed `prompt`, followed by a space.
+    fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>>;
+
+    /// Called with each line entered, without its newline, apart from blank
+    /// ones.
+    fn add_history(&mut self, _line: &str) {}
+}
+
+impl<R: BufRead> LineReader for R {
+    fn read_line(&mut self, _prompt: &str) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        if BufRead::read_line(self, &mut line)? == 0 {
+            return Ok(None);
+        }
+        Ok(Some(line))
+    }
+}
+
+/// Settings that change how the REPL looks.
+pub struct ReplConfig {
+    /// Shown when the REPL is ready for a new statement.
+    pub prompt: String,
+    /// Shown when the input so far is unfinished, as inside a block.
+    pub continuation_prompt: String,
+    /// Greet the user before the first prompt.
+    pub show_banner: bool,
+}
+
+impl Default for ReplConfig {
+    fn default() -> Self {
+        ReplConfig {
+            prompt: String::from(">"),
+            continuation_prompt: String::from("..."),
+            show_banner: true,
+        }
+    }
+}
+
+/// Runs statements read from `input` until it's closed or the user enters
+/// `exit`, `quit` or `exit(code)`.  Prompts, and what the statements print,
+/// go to `out`; errors go to stderr.  Returns the code to exit with, which
+/// is 0 unless `exit(code)` said otherwise.
+///
+/// ```
+/// let config = lox::ReplConfig {
+///     prompt: String::from("lox>"),
+///     show_banner: false,
+///     ..Default::default()
+/// };
+/// let mut input = "print 1 + 1;\n".as_bytes();
+/// let mut out = Vec::new();
+/// let options = lox::VmOptions::default();
+/// assert_eq!(lox::repl(&config, &options, &mut input, &mut out).unwrap(), 0);
+/// assert_eq!(out, b"lox> 2\nlox> \n");
+/// ```
+pub fn repl(
+    config: &ReplConfig,
+    options: &VmOptions,
+    input: &mut impl LineReader,
+    out: &mut impl Write,
+) -> io::Result<i32> {
+    if config.show_banner {
+        writeln!(out, "Welcome to lox!")?;
+    }
+    let mut vm = Vm::new();
+
+    // A bare expression at the prompt prints its value.
+    let compile_options = CompileOptions {
+        repl: true,
+        ..Default::default()
+    };
+
+    // Input is gathered here until it forms a complete program.
+    let mut source = String::new();
+    loop {
+        let prompt = if source.is_empty() {
+            &config.prompt
+        } else {
+            &config.continuation_prompt
+        };
+        write!(out, "{} ", prompt)?;
+        // Have to flush or the prompt never gets printed.
+        out.flush()?;
+
+        let line = match input.read_line(prompt)? {
+            Some(line) => line,
+            None => {
+                writeln!(out)?;
+                return Ok(0);
+            }
+        };
+        if !line.trim().is_empty() {
+            input.add_history(line.trim_end());
+        }
+        if source.is_empty() {
+            if let Some(code) = exit_command(&line) {
+                return Ok(code);
+            }
+        }
+        let blank = line.trim().is_empty();
+        source.push_str(&line);
+
+        // Errors at the end of the input usually mean it's unfinished, so
+        // keep reading.  A blank line gives up and reports them.
+        let mut chunk = Chunk::new();
+        let mut warnings = Vec::new();
+        let result = compiler::compile_with_warnings(&source, &mut chunk, &compile_options, &mut warnings);
+        let unfinished = matches!(&result, Err(errors) if errors.iter().any(ParseError::is_at_end));
+        if unfinished && !blank {
+            continue;
+        }
+
+        for warning in warnings {
+            eprint!("{}", warning.render(&source));
+        }
+        match result {
+            Ok(()) => {
+                // Runtime errors have already been reported.
+                let _ = vm.interpret_chunk(chunk, options, out);
+            }
+            Err(errors) => {
+                for error in errors {
+                    eprint!("{}", error.render(&source));
+                }
+            }
+        }
+        source.clear();
+    }
+}
+
+/// Returns the code to exit with if `line` asks to leave the REPL.
+fn exit_command(line: &str) -> Option<i32> {
+    let line = line.trim();
+    let line = line.strip_suffix(';').unwrap_or(line).trim_end();
+    match line {
+        "exit" | "quit" => Some(0),
+        _ => line.strip_prefix("exit(")?.strip_suffix(')')?.trim().parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Feeds `input` to a REPL without a banner, returning what it writes.
+    fn session(input: &str) -> String {
+        let config = ReplConfig {
+            show_banner: false,
+            ..Default::default()
+        };
+        let mut out = Vec::new();
+        repl(&config, &VmOptions::default(), &mut input.as_bytes(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn unfinished_input_continues() {
+        assert_eq!(session("if (true) {\nprint 1;\n}\n"), "> ... ... 1\n> \n");
+        assert_eq!(session("print (1 +\n2);\n"), "> ... 3\n> \n");
+    }
+
+    #[test]
+    fn end_of_input_while_unfinished() {
+        assert_eq!(session("{\n"), "> ... \n");
+    }
+
+    #[test]
+    fn bare_expressions_print_their_value() {
+        assert_eq!(session("1 + 2\n"), "> 3\n> \n");
+        assert_eq!(session("var x = 4;\nx * 2\nx * 3;\n"), "> > 8\n> > \n");
+    }
+
+    #[test]
+    fn custom_prompts_and_banner() {
+        let config = ReplConfig {
+            prompt: String::from("lox>"),
+            continuation_prompt: String::from("  |"),
+            show_banner: true,
+        };
+        let mut out = Vec::new();
+        repl(&config, &VmOptions::default(), &mut "{\nprint 1;\n}\n".as_bytes(), &mut out).unwrap();
+        assert_eq!(out, b"Welcome to lox!\nlox>   |   | 1\nlox> \n");
+    }
+
+    /// Feeds `input` to a REPL, returning the code it exits with.
+    fn exit_code(input: &str) -> i32 {
+        let config = ReplConfig {
+            show_banner: false,
+            ..Default::default()
+        };
+        repl(&config, &VmOptions::default(), &mut input.as_bytes(), &mut io::sink()).unwrap()
+    }
+
+    #[test]
+    fn exit_commands() {
+        assert_eq!(session("print 1;\nexit\nprint 2;\n"), "> 1\n> ");
+        assert_eq!(session("quit;\n"), "> ");
+        assert_eq!(exit_code("exit\n"), 0);
+        assert_eq!(exit_code("print 1;\nexit(3)\nprint 2;\n"), 3);
+        assert_eq!(exit_code("  exit( 4 );  \n"), 4);
+        // End of input leaves on a new line.
+        assert_eq!(session(""), "> \n");
+        assert_eq!(exit_code(""), 0);
+    }
+
+    #[test]
+    fn exit_only_starts_a_statement() {
+        assert_eq!(session("{\nexit\n"), "> ... ... \n");
+    }
+
+    /// Feeds the REPL canned lines and remembers what it entered.
+    struct Script {
+        lines: Vec<&'static str>,
+        prompts: Vec<String>,
+        history: Vec<String>,
+    }
+
+    impl LineReader for Script {
+        fn read_line(&mut self, prompt: &str) -> io::Result<Option<String>> {
+            self.prompts.push(String::from(prompt));
+            Ok(self.lines.pop().map(String::from))
+        }
+
+        fn add_history(&mut self, line: &str) {
+            self.history.push(String::from(line));
+        }
+    }
+
+    #[test]
+    fn history_leaves_out_blank_lines() {
+        let mut script = Script {
+            lines: vec!["print 2;\n", "\n", "  \n", "print 1;\n"],
+            prompts: Vec::new(),
+            history: Vec::new(),
+        };
+        repl(&ReplConfig::default(), &VmOptions::default(), &mut script, &mut io::sink()).unwrap();
+        assert_eq!(script.history, ["print 1;", "print 2;"]);
+        assert_eq!(script.prompts, [">", ">", ">", ">", ">"]);
+    }
+}
@@ -8,6 +8,8 @@ pub enum TokenTag {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -15,6 +17,12 @@ pub enum TokenTag {
     Semicolon,
     Slash,
     Star,
+    Percent,
+    Question,
+    Colon,
+    Ampersand,
+    Pipe,
+    Caret,
 
     // One or two character tokens.
     Bang,
@@ -25,6 +33,15 @@ pub enum TokenTag {
     GreaterEqual,
     Less,
     LessEqual,
+    LessLess,
+    GreaterGreater,
+    PlusEqual,
+    MinusEqual,
+    PlusPlus,
+    MinusMinus,
+    StarEqual,
+    StarStar,
+    SlashEqual,
 
     // Literals.
     Identifier,
@@ -33,7 +50,13 @@ pub enum TokenTag {
 
     // Keywords.
     And,
+    Break,
+    Case,
     Class,
+    Const,
+    Continue,
+    Default,
+    Do,
     Else,
     False,
     For,
@@ -44,6 +67,7 @@ pub enum TokenTag {
     Print,
     Return,
     Super,
+    Switch,
     This,
     True,
     Var,
@@ -53,50 +77,120 @@ pub enum TokenTag {
     Eof,
 }
 
+/// A piece of source text.  For an `Error` token, the lexeme is a message
+/// saying what's wrong.
 #[derive(Debug, Clone)]
 pub struct Token {
     pub tag: TokenTag,
     pub lexeme: String,
     pub line: usize,
+    // Counts characters from 1.
+    pub column: usize,
+    // The line in the source text, which is only different from `line`
+    // after a line directive.
+    pub source_line: usize,
+}
+
+/// Scans all of `source` into tokens, not including the final `Eof`.  A
+/// mistake doesn't stop the scan; it gives an `Error` token.
+///
+/// ```
+/// let tokens = lox::scan_tokens("print 1 + 2;");
+/// let lexemes: Vec<&str> = tokens.iter().map(|token| token.lexeme.as_str()).collect();
+/// assert_eq!(lexemes, ["print", "1", "+", "2", ";"]);
+/// ```
+pub fn scan_tokens(source: &str) -> Vec<Token> {
+    let mut scanner = Scanner::new(source);
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.next_token();
+        if token.tag == TokenTag::Eof {
+            return tokens;
+        }
+        tokens.push(token);
+    }
 }
 
 fn is_alpha(c: char) -> bool {
-    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || c == '_'
+    c.is_ascii_alphabetic() || c == '_'
 }
 
 fn is_digit(c: char) -> bool {
-    c >= '0' && c <= '9'
+    c.is_ascii_digit()
 }
 
+fn is_hex_digit(c: char) -> bool {
+    c.is_ascii_hexdigit()
+}
+
+const INVALID_SEPARATOR: &str = "'_' must separate digits in a number literal";
+
 pub struct Scanner<'a> {
     itr: Peekable<Chars<'a>>,
     current: Option<char>,
     next: Option<char>,
     line: usize,
+    // The line in the source text, however `line` has been changed.
+    source_line: usize,
+    // Column of the current character, counting from 1.
+    column: usize,
+    // Column where the token being scanned starts.
+    start_column: usize,
 }
 
 impl<'a> Scanner<'a> {
-    pub fn new(source: &str) -> Scanner {
+    pub fn new(source: &str) -> Scanner<'_> {
         let mut scanner = Scanner {
             itr: source.chars().peekable(),
             current: None,
             next: None,
             line: 1,
+            source_line: 1,
+            column: 0,
+            start_column: 0,
         };
         scanner.advance();
         scanner
     }
 
     fn advance(&mut self) {
+        if self.current == Some('\n') {
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
         self.current = self.itr.next();
-        self.next = self.itr.peek().map(|&c| c);
+        self.next = self.itr.peek().copied();
     }
 
     fn make_token(&self, tag: TokenTag, lexeme: String) -> Token {
         Token {
-            tag: tag,
-            lexeme: lexeme,
+            tag,
+            lexeme,
             line: self.line,
+            column: self.start_column,
+            source_line: self.source_line,
+        }
+    }
+
+    fn new_line(&mut self) {
+        self.line += 1;
+        self.source_line += 1;
+    }
+
+    /// Handles a `//# line N "file"` comment, which makes the line after it
+    /// line N in error messages.  Generated code uses it to report lines in
+    /// the file it was generated from.  The file name is optional, and
+    /// isn't used.  Any other comment is ignored.
+    fn line_directive(&mut self, comment: &str) {
+        let line = comment
+            .strip_prefix('#')
+            .and_then(|rest| rest.trim_start().strip_prefix("line "))
+            .and_then(|rest| rest.split_whitespace().next())
+            .and_then(|number| number.parse::<usize>().ok());
+        if let Some(line) = line.filter(|&line| line > 0) {
+            // The newline ending the comment moves on to it.
+            self.line = line - 1;
         }
     }
 
@@ -104,6 +198,52 @@ impl<'a> Scanner<'a> {
         self.make_token(tag, String::from(lexeme))
     }
 
+    /// Skips a block comment, including any comments nested inside it.
+    /// Returns false if the source ends before the comment is closed.
+    fn skip_block_comment(&mut self) -> bool {
+        let mut depth = 0;
+        loop {
+            match (self.current, self.next) {
+                (Some('/'), Some('*')) => {
+                    depth += 1;
+                    self.advance();
+                }
+                (Some('*'), Some('/')) => {
+                    depth -= 1;
+                    self.advance();
+                    if depth == 0 {
+                        self.advance();
+                        return true;
+                    }
+                }
+                (Some('\n'), _) => self.new_line(),
+                (None, _) => return false,
+                _ => {}
+            }
+            self.advance();
+        }
+    }
+
+    /// Scans a run of digits into `s`, dropping any `_` digit separators.
+    /// Returns false if a separator doesn't sit between two digits.
+    fn digits(&mut self, s: &mut String, is_digit: fn(char) -> bool) -> bool {
+        let mut ok = true;
+        let mut after_digit = false;
+
+        while let Some(c) = self.current.filter(|&c| is_digit(c) || c == '_') {
+            if c == '_' {
+                ok &= after_digit;
+                after_digit = false;
+            } else {
+                s.push(c);
+                after_digit = true;
+            }
+            self.advance();
+        }
+
+        ok && after_digit
+    }
+
     pub fn next_token(&mut self) -> Token {
         use TokenTag::*;
 
@@ -114,18 +254,31 @@ impl<'a> Scanner<'a> {
                     self.advance();
                 }
                 Some('\n') => {
-                    self.line += 1;
+                    self.new_line();
                     self.advance();
                 }
-                Some('/') if self.next.map_or(false, |c| c == '/') => {
-                    while self.current.map_or(false, |c| c != '\n') {
+                Some('/') if self.next == Some('/') => {
+                    let mut comment = String::new();
+                    self.advance();
+                    self.advance();
+                    while let Some(c) = self.current.filter(|&c| c != '\n') {
+                        comment.push(c);
                         self.advance();
                     }
+                    self.line_directive(&comment);
+                }
+                Some('/') if self.next == Some('*') => {
+                    self.start_column = self.column;
+                    if !self.skip_block_comment() {
+                        return self.make_token_str(Error, "unterminated block comment");
+                    }
                 }
                 _ => break,
             }
         }
 
+        self.start_column = self.column;
+
         // Handle end of code.
         if self.current.is_none() {
             return self.make_token_str(Eof, "");
@@ -134,14 +287,44 @@ impl<'a> Scanner<'a> {
         // Handle a string literal.
         if let Some('"') = self.current {
             let mut s = String::new();
+            let mut error = None;
             self.advance();
 
-            while self.current.map_or(false, |c| c != '"') {
+            while self.current.is_some_and(|c| c != '"') {
                 let c = self.current.unwrap();
-                s.push(c);
                 if c == '\n' {
-                    self.line += 1;
+                    self.new_line();
+                }
+                self.advance();
+
+                if c != '\\' {
+                    s.push(c);
+                    continue;
+                }
+
+                // Interpret the escape sequence.  A backslash at the very end
+                // of the source leaves the string unterminated.
+                let escaped = match self.current {
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    Some('r') => '\r',
+                    Some('\\') => '\\',
+                    Some('"') => '"',
+                    Some('0') => '\0',
+                    Some(c) => {
+                        // Keep scanning to the closing quote so the rest of
+                        // the string isn't mistaken for more tokens.
+                        if error.is_none() {
+                            error = Some(format!("invalid escape sequence '\\{}'", c));
+                        }
+                        c
+                    }
+                    None => break,
+                };
+                if self.current == Some('\n') {
+                    self.new_line();
                 }
+                s.push(escaped);
                 self.advance();
             }
 
@@ -152,22 +335,30 @@ impl<'a> Scanner<'a> {
             // Skip past the closing quote.
             self.advance();
 
+            if let Some(msg) = error {
+                return self.make_token(Error, msg);
+            }
+
             return self.make_token(StringLiteral, s);
         }
 
         // Handle identifiers and keywords.
-        if self.current.map_or(false, |c| is_alpha(c)) {
+        if self.current.is_some_and(is_alpha) {
             let mut s = String::new();
-            while self.current.map_or(false, |c| {
-                is_alpha(c) || self.current.map_or(false, |c| is_digit(c))
-            }) {
+            while self.current.is_some_and(|c| is_alpha(c) || is_digit(c)) {
                 s.push(self.current.unwrap());
                 self.advance();
             }
 
             let tag = match s.as_ref() {
                 "and" => And,
+                "break" => Break,
+                "case" => Case,
                 "class" => Class,
+                "const" => Const,
+                "continue" => Continue,
+                "default" => Default,
+                "do" => Do,
                 "else" => Else,
                 "false" => False,
                 "for" => For,
@@ -178,6 +369,7 @@ impl<'a> Scanner<'a> {
                 "print" => Print,
                 "return" => Return,
                 "super" => Super,
+                "switch" => Switch,
                 "this" => This,
                 "true" => True,
                 "var" => Var,
@@ -188,25 +380,57 @@ impl<'a> Scanner<'a> {
             return self.make_token(tag, s);
         }
 
+        // Handle a hexadecimal number literal.  The lexeme keeps its "0x"
+        // prefix so the compiler knows to parse it in base 16.
+        if self.current == Some('0') && matches!(self.next, Some('x') | Some('X')) {
+            let mut s = String::from("0x");
+            self.advance();
+            self.advance();
+
+            let separators_ok = self.digits(&mut s, is_hex_digit);
+
+            if s.len() == 2 {
+                return self.make_token_str(Error, "expected hex digits after '0x'");
+            }
+            if !separators_ok {
+                return self.make_token_str(Error, INVALID_SEPARATOR);
+            }
+
+            return self.make_token(Number, s);
+        }
+
         // Handle a number literal.
-        if self.current.map_or(false, |c| is_digit(c)) {
+        if self.current.is_some_and(is_digit) {
             let mut s = String::new();
+            let mut separators_ok = self.digits(&mut s, is_digit);
 
-            while self.current.map_or(false, |c| is_digit(c)) {
+            // Look for fractional part.
+            if self.current == Some('.') && self.next.is_some_and(is_digit) {
                 s.push(self.current.unwrap());
                 self.advance();
+
+                separators_ok &= self.digits(&mut s, is_digit);
             }
 
-            // Look for fractional part.
-            if self.current.map_or(false, |c| c == '.') && self.next.map_or(false, |c| is_digit(c))
-            {
-                s.push(self.current.unwrap());
+            // Look for an exponent, which may be signed.
+            if matches!(self.current, Some('e') | Some('E')) {
+                s.push('e');
                 self.advance();
 
-                while self.current.map_or(false, |c| is_digit(c)) {
-                    s.push(self.current.unwrap());
+                if let Some(sign) = self.current.filter(|&c| c == '+' || c == '-') {
+                    s.push(sign);
                     self.advance();
                 }
+
+                let len = s.len();
+                separators_ok &= self.digits(&mut s, is_digit);
+                if s.len() == len {
+                    return self.make_token_str(Error, "expected digits in number exponent");
+                }
+            }
+
+            if !separators_ok {
+                return self.make_token_str(Error, INVALID_SEPARATOR);
             }
 
             return self.make_token(Number, s);
@@ -214,26 +438,64 @@ impl<'a> Scanner<'a> {
 
         // Handle operators.
         let token = match self.current.unwrap() {
-            '!' if self.next.map_or(false, |c| c == '=') => {
+            '!' if self.next == Some('=') => {
                 self.advance();
                 self.make_token_str(BangEqual, "!=")
             }
-            '=' if self.next.map_or(false, |c| c == '=') => {
+            '=' if self.next == Some('=') => {
                 self.advance();
                 self.make_token_str(EqualEqual, "==")
             }
-            '<' if self.next.map_or(false, |c| c == '=') => {
+            '<' if self.next == Some('=') => {
                 self.advance();
                 self.make_token_str(LessEqual, "<=")
             }
-            '>' if self.next.map_or(false, |c| c == '=') => {
+            '>' if self.next == Some('=') => {
                 self.advance();
                 self.make_token_str(GreaterEqual, ">=")
             }
+            '<' if self.next == Some('<') => {
+                self.advance();
+                self.make_token_str(LessLess, "<<")
+            }
+            '>' if self.next == Some('>') => {
+                self.advance();
+                self.make_token_str(GreaterGreater, ">>")
+            }
+            '+' if self.next == Some('+') => {
+                self.advance();
+                self.make_token_str(PlusPlus, "++")
+            }
+            '-' if self.next == Some('-') => {
+                self.advance();
+                self.make_token_str(MinusMinus, "--")
+            }
+            '+' if self.next == Some('=') => {
+                self.advance();
+                self.make_token_str(PlusEqual, "+=")
+            }
+            '-' if self.next == Some('=') => {
+                self.advance();
+                self.make_token_str(MinusEqual, "-=")
+            }
+            '*' if self.next == Some('*') => {
+                self.advance();
+                self.make_token_str(StarStar, "**")
+            }
+            '*' if self.next == Some('=') => {
+                self.advance();
+                self.make_token_str(StarEqual, "*=")
+            }
+            '/' if self.next == Some('=') => {
+                self.advance();
+                self.make_token_str(SlashEqual, "/=")
+            }
             '(' => self.make_token_str(LeftParen, "("),
             ')' => self.make_token_str(RightParen, ")"),
             '{' => self.make_token_str(LeftBrace, "{"),
             '}' => self.make_token_str(RightBrace, "}"),
+            '[' => self.make_token_str(LeftBracket, "["),
+            ']' => self.make_token_str(RightBracket, "]"),
             ';' => self.make_token_str(Semicolon, ";"),
             ',' => self.make_token_str(Comma, ","),
             '.' => self.make_token_str(Dot, "."),
@@ -241,13 +503,18 @@ impl<'a> Scanner<'a> {
             '+' => self.make_token_str(Plus, "+"),
             '/' => self.make_token_str(Slash, "/"),
             '*' => self.make_token_str(Star, "*"),
+            '%' => self.make_token_str(Percent, "%"),
+            '?' => self.make_token_str(Question, "?"),
+            ':' => self.make_token_str(Colon, ":"),
+            '&' => self.make_token_str(Ampersand, "&"),
+            '|' => self.make_token_str(Pipe, "|"),
+            '^' => self.make_token_str(Caret, "^"),
             '!' => self.make_token_str(Bang, "!"),
             '=' => self.make_token_str(Equal, "="),
             '<' => self.make_token_str(Less, "<"),
             '>' => self.make_token_str(Greater, ">"),
-            _ => {
-                let msg = format!("unexpected character '{:?}'", self.current);
-                self.advance();
+            c => {
+                let msg = format!("unexpected character '{}' on line {}", c, self.line);
                 self.make_token(Error, msg)
             }
         };
@@ -255,6 +522,140 @@ impl<'a> Scanner<'a> {
         // Advance past the last character in the operator.
         self.advance();
 
-        return token;
+        token
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the tag and lexeme of each token in `source`.
+    fn scan(source: &str) -> Vec<(TokenTag, String)> {
+        scan_tokens(source).into_iter().map(|token| (token.tag, token.lexeme)).collect()
+    }
+
+    /// Returns the single token `source` scans to.
+    fn scan_one(source: &str) -> (TokenTag, String) {
+        let mut tokens = scan(source);
+        assert_eq!(tokens.len(), 1, "{:?} scanned to {:?}", source, tokens);
+        tokens.remove(0)
+    }
+
+    fn token(tag: TokenTag, lexeme: &str) -> (TokenTag, String) {
+        (tag, String::from(lexeme))
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(
+            scan_one(r#""a\nb\tc\rd\\e\"f\0""#),
+            token(TokenTag::StringLiteral, "a\nb\tc\rd\\e\"f\0")
+        );
+        assert_eq!(scan_one(r#""\q""#), token(TokenTag::Error, "invalid escape sequence '\\q'"));
+        // The backslash escapes the quote, so the string never ends.
+        assert_eq!(scan_one(r#""a\""#), token(TokenTag::Error, "unterminated string"));
+    }
+
+    #[test]
+    fn newline_in_string_counts_a_line() {
+        let tokens = scan_tokens("\"a\nb\" x");
+        assert_eq!(tokens[0].lexeme, "a\nb");
+        assert_eq!(tokens[1].line, 2);
+    }
+
+    #[test]
+    fn nested_block_comments() {
+        let tokens = scan_tokens("/* a /* b */ c\n */ x");
+        assert_eq!(tokens.len(), 1);
+        assert_eq!(tokens[0].lexeme, "x");
+        assert_eq!(tokens[0].line, 2);
+
+        assert_eq!(scan_one("/* a /* b */"), token(TokenTag::Error, "unterminated block comment"));
+    }
+
+    #[test]
+    fn hex_literals() {
+        assert_eq!(scan_one("0xFF"), token(TokenTag::Number, "0xFF"));
+        assert_eq!(scan_one("0X1a2b"), token(TokenTag::Number, "0x1a2b"));
+        assert_eq!(scan_one("0x"), token(TokenTag::Error, "expected hex digits after '0x'"));
+    }
+
+    #[test]
+    fn digit_separators() {
+        assert_eq!(scan_one("1_000_000"), token(TokenTag::Number, "1000000"));
+        assert_eq!(scan_one("1_0.2_5"), token(TokenTag::Number, "10.25"));
+        assert_eq!(scan_one("0xFF_FF"), token(TokenTag::Number, "0xFFFF"));
+        for source in ["1__0", "1_", "1_.5", "1.5_", "0x_F"].iter() {
+            assert_eq!(scan_one(source), token(TokenTag::Error, INVALID_SEPARATOR), "{}", source);
+        }
+        // A leading underscore makes an identifier.
+        assert_eq!(scan_one("_1"), token(TokenTag::Identifier, "_1"));
+    }
+
+    #[test]
+    fn exponents() {
+        assert_eq!(scan_one("1e10"), token(TokenTag::Number, "1e10"));
+        assert_eq!(scan_one("1.5e-3"), token(TokenTag::Number, "1.5e-3"));
+        assert_eq!(scan_one("2E+2"), token(TokenTag::Number, "2e+2"));
+        assert_eq!(scan_one("1e"), token(TokenTag::Error, "expected digits in number exponent"));
+    }
+
+    #[test]
+    fn columns() {
+        let tokens = scan_tokens("var a;\n  x = \"ü\" + b;");
+        let columns: Vec<(usize, usize)> = tokens.iter().map(|token| (token.line, token.column)).collect();
+        // Columns count characters, not bytes, from 1 on each line.
+        assert_eq!(columns, [(1, 1), (1, 5), (1, 6), (2, 3), (2, 5), (2, 7), (2, 11), (2, 13), (2, 14)]);
+    }
+
+    #[test]
+    fn identifiers_with_digits() {
+        assert_eq!(scan_one("foo123"), token(TokenTag::Identifier, "foo123"));
+        assert_eq!(scan_one("a1b2"), token(TokenTag::Identifier, "a1b2"));
+        assert_eq!(scan_one("_9"), token(TokenTag::Identifier, "_9"));
+    }
+
+    #[test]
+    fn unexpected_character() {
+        assert_eq!(scan_one("@"), token(TokenTag::Error, "unexpected character '@' on line 1"));
+        assert_eq!(scan("\n\n#")[0], token(TokenTag::Error, "unexpected character '#' on line 3"));
+    }
+
+    #[test]
+    fn line_directives() {
+        // Each token's reported line, and the line it's really on.
+        let lines = |source| {
+            let tokens = scan_tokens(source);
+            tokens.iter().map(|token| (token.line, token.source_line)).collect::<Vec<_>>()
+        };
+        assert_eq!(lines("a\n//# line 100 \"page.tmpl\"\nb\nc"), [(1, 1), (100, 3), (101, 4)]);
+        assert_eq!(lines("//#line 7\na"), [(7, 2)]);
+        // Anything else is an ordinary comment.
+        assert_eq!(lines("//# line x\na\n// line 9\nb\n//# line 0\nc"), [(2, 2), (4, 4), (6, 6)]);
+    }
+
+    #[test]
+    fn token_stream() {
+        assert_eq!(
+            scan("print 1 + 2;"),
+            [
+                token(TokenTag::Print, "print"),
+                token(TokenTag::Number, "1"),
+                token(TokenTag::Plus, "+"),
+                token(TokenTag::Number, "2"),
+                token(TokenTag::Semicolon, ";"),
+            ]
+        );
+        // An error doesn't end the stream.
+        assert_eq!(
+            scan("a @ b"),
+            [
+                token(TokenTag::Identifier, "a"),
+                token(TokenTag::Error, "unexpected character '@' on line 1"),
+                token(TokenTag::Identifier, "b"),
+            ]
+        );
+        assert!(scan_tokens("").is_empty());
     }
 }
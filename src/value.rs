@@ -3,10 +3,10 @@ use std::rc::Rc;
 
 use crate::object::Obj;
 
-#[derive(PartialEq)]
 pub enum Value {
     Bool(bool),
     Nil,
+    Int(i64),
     Number(f64),
     Obj(Rc<Obj>),
 }
@@ -19,9 +19,19 @@ impl Value {
     }
 
     pub fn is_number(&self) -> bool {
+        matches!(self, Value::Int(_) | Value::Number(_))
+    }
+
+    pub fn is_int(&self) -> bool {
+        matches!(self, Value::Int(_))
+    }
+
+    /// Returns the value as a float, promoting an integer if need be.
+    pub fn as_f64(&self) -> Option<f64> {
         match self {
-            Value::Number(_) => true,
-            _ => false,
+            Value::Int(x) => Some(*x as f64),
+            Value::Number(x) => Some(*x),
+            _ => None,
         }
     }
 
@@ -32,7 +42,25 @@ impl Value {
         }
     }
 
-    pub fn is_falsey_rust(&self) -> bool {
+    /// Names the value's type, as the `type` native reports it.  Integers
+    /// and floats are both numbers.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Bool(_) => "bool",
+            Value::Nil => "nil",
+            Value::Int(_) | Value::Number(_) => "number",
+            Value::Obj(obj) => obj.value.type_name(),
+        }
+    }
+
+    /// Returns true for nil and false.  Every other value, including 0 and
+    /// the empty string, is truthy.
+    ///
+    /// ```
+    /// assert!(lox::Value::Nil.is_falsey());
+    /// assert!(!lox::Value::Int(0).is_falsey());
+    /// ```
+    pub fn is_falsey(&self) -> bool {
         match self {
             Value::Bool(x) => !x,
             Value::Nil => true,
@@ -40,10 +68,6 @@ impl Value {
         }
     }
 
-    pub fn is_falsey(&self) -> Value {
-        Value::Bool(self.is_falsey_rust())
-    }
-
     pub fn as_obj(&self) -> Option<&Rc<Obj>> {
         match self {
             Value::Obj(obj) => Some(obj),
@@ -56,27 +80,210 @@ impl Value {
     }
 }
 
+/// Cloning a value shares its object, if it has one, rather than copying it.
 impl Clone for Value {
     fn clone(&self) -> Self {
         match self {
             Value::Bool(x) => Value::Bool(*x),
             Value::Nil => Value::Nil,
+            Value::Int(x) => Value::Int(*x),
             Value::Number(x) => Value::Number(*x),
-            Value::Obj(o) => {
-                let o = (**o).clone();
-                Value::Obj(Rc::new(o))
-            }
+            Value::Obj(o) => Value::Obj(Rc::clone(o)),
         }
     }
 }
 
+/// Equality as Lox's `==` sees it.
+///
+/// Floats follow IEEE 754: NaN is not equal to anything, itself included, and
+/// `-0.0 == 0.0`.  An integer equals a float with exactly the same value, so
+/// `1 == 1.0`, but not one that is merely the nearest float to it.
+///
+/// ```
+/// use lox::Value;
+///
+/// assert!(Value::Int(1) == Value::Number(1.0));
+/// assert!(Value::Number(f64::NAN) != Value::Number(f64::NAN));
+/// ```
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::Nil, Value::Nil) => true,
+            (Value::Int(a), Value::Int(b)) => a == b,
+            (Value::Int(a), Value::Number(b)) => int_equals_float(*a, *b),
+            (Value::Number(a), Value::Int(b)) => int_equals_float(*b, *a),
+            (Value::Number(a), Value::Number(b)) => a == b,
+            (Value::Obj(a), Value::Obj(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+// Converting the integer to a float can round it, so instead check that the
+// float is a whole number in range and compare as integers.
+fn int_equals_float(a: i64, b: f64) -> bool {
+    // 2^63, the first float too large for an i64.
+    let limit = 9_223_372_036_854_775_808.0;
+    b.fract() == 0.0 && b >= -limit && b < limit && a == b as i64
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.fmt_with(f, NumberFormat::default())
+    }
+}
+
+impl Value {
+    /// Returns something that displays the value with floats written in
+    /// `format`, including those inside lists and maps.
+    ///
+    /// ```
+    /// use lox::{NumberFormat, Value};
+    ///
+    /// let one = Value::Number(1.0);
+    /// assert_eq!(one.display(NumberFormat::Decimal).to_string(), "1.0");
+    /// ```
+    pub fn display(&self, format: NumberFormat) -> impl fmt::Display + '_ {
+        Formatted { value: self, format }
+    }
+
+    pub(crate) fn fmt_with(&self, f: &mut fmt::Formatter, format: NumberFormat) -> fmt::Result {
         match self {
             Value::Bool(x) => write!(f, "{}", x),
             Value::Nil => write!(f, "nil"),
-            Value::Number(x) => write!(f, "{}", x),
-            Value::Obj(x) => write!(f, "{}", x),
+            Value::Int(x) => write!(f, "{}", x),
+            Value::Number(x) => format.write(f, *x),
+            Value::Obj(x) => x.value.fmt_with(f, format),
         }
     }
 }
+
+struct Formatted<'a> {
+    value: &'a Value,
+    format: NumberFormat,
+}
+
+impl fmt::Display for Formatted<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.value.fmt_with(f, self.format)
+    }
+}
+
+/// How floats are written when a value is displayed.  Integers are always
+/// written in full.
+#[derive(Clone, Copy, Default)]
+pub enum NumberFormat {
+    /// The fewest digits that read back as the same float, without a
+    /// decimal point for a whole number: `1`, `1.5`, `0.30000000000000004`.
+    #[default]
+    Shortest,
+    /// Like `Shortest`, but a whole number keeps its decimal point, so it
+    /// can't be mistaken for an integer: `1.0`.
+    Decimal,
+    /// Rounded to at most this many decimal places, dropping trailing
+    /// zeros: with 6, `0.1 + 0.2` is written `0.3`.
+    Precision(usize),
+}
+
+impl NumberFormat {
+    fn write(self, f: &mut fmt::Formatter, x: f64) -> fmt::Result {
+        match self {
+            NumberFormat::Decimal if x.is_finite() && x.fract() == 0.0 => write!(f, "{:.1}", x),
+            NumberFormat::Precision(places) => {
+                let rounded = format!("{:.*}", places, x);
+                if rounded.contains('.') {
+                    write!(f, "{}", rounded.trim_end_matches('0').trim_end_matches('.'))
+                } else {
+                    write!(f, "{}", rounded)
+                }
+            }
+            _ => write!(f, "{}", x),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cloning_shares_the_object() {
+        let value = Value::new_string("shared");
+        let copy = value.clone();
+        match (&value, &copy) {
+            (Value::Obj(a), Value::Obj(b)) => {
+                assert!(Rc::ptr_eq(a, b));
+                assert_eq!(Rc::strong_count(a), 2);
+            }
+            _ => panic!("expected strings"),
+        }
+    }
+
+    #[test]
+    fn truthiness() {
+        let cases = [
+            (Value::Nil, true),
+            (Value::Bool(false), true),
+            (Value::Bool(true), false),
+            (Value::Int(0), false),
+            (Value::Number(0.0), false),
+            (Value::Number(f64::NAN), false),
+            (Value::new_string(""), false),
+        ];
+        for (value, falsey) in cases.iter() {
+            assert_eq!(value.is_falsey(), *falsey, "{}", value);
+        }
+    }
+
+    #[test]
+    fn float_equality() {
+        let nan = Value::Number(f64::NAN);
+        assert!(nan != nan);
+        assert!(Value::Number(-0.0) == Value::Number(0.0));
+        assert!(Value::Int(0) == Value::Number(-0.0));
+        assert!(Value::Number(f64::INFINITY) == Value::Number(f64::INFINITY));
+    }
+
+    #[test]
+    fn int_float_equality() {
+        assert!(Value::Int(1) == Value::Number(1.0));
+        assert!(Value::Number(1.0) == Value::Int(1));
+        assert!(Value::Int(1) != Value::Number(1.5));
+        // i64::MAX rounds up to 2^63 as a float, so they differ.
+        assert!(Value::Int(i64::MAX) != Value::Number(i64::MAX as f64));
+        assert!(Value::Int(i64::MIN) == Value::Number(i64::MIN as f64));
+        assert!(Value::Int(0) != Value::Number(f64::NAN));
+        assert!(Value::Int(1) != Value::Bool(true));
+    }
+
+    /// Returns how a float is written in `format`.
+    fn shown(x: f64, format: NumberFormat) -> String {
+        Value::Number(x).display(format).to_string()
+    }
+
+    #[test]
+    fn shortest_format() {
+        assert_eq!(shown(1.0, NumberFormat::Shortest), "1");
+        assert_eq!(shown(1.5, NumberFormat::Shortest), "1.5");
+        assert_eq!(shown(100000000.0, NumberFormat::Shortest), "100000000");
+        assert_eq!(shown(0.1 + 0.2, NumberFormat::Shortest), "0.30000000000000004");
+    }
+
+    #[test]
+    fn decimal_format() {
+        assert_eq!(shown(1.0, NumberFormat::Decimal), "1.0");
+        assert_eq!(shown(1.5, NumberFormat::Decimal), "1.5");
+        assert_eq!(shown(100000000.0, NumberFormat::Decimal), "100000000.0");
+        assert_eq!(shown(f64::INFINITY, NumberFormat::Decimal), "inf");
+        assert_eq!(Value::Int(1).display(NumberFormat::Decimal).to_string(), "1");
+    }
+
+    #[test]
+    fn precision_format() {
+        assert_eq!(shown(0.1 + 0.2, NumberFormat::Precision(6)), "0.3");
+        assert_eq!(shown(1.5, NumberFormat::Precision(6)), "1.5");
+        assert_eq!(shown(100000000.0, NumberFormat::Precision(6)), "100000000");
+        assert_eq!(shown(2.0 / 3.0, NumberFormat::Precision(2)), "0.67");
+    }
+}
@@ -1,18 +1,28 @@
 use crate::chunk::Chunk;
 use crate::compiler;
-use crate::object::Obj;
+use crate::intern::{Interner, Symbol};
+use crate::memory::{Heap, Marker};
+use crate::natives;
+use crate::object::{BoundMethod, Class, Closure, Function, Instance, List, Map, Native, NativeBody, NativeFn, Obj, ObjValue, Upvalue};
 use crate::op::*;
-use crate::value::Value;
+use crate::value::{NumberFormat, Value};
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::error::Error;
 use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::mem;
 use std::rc::Rc;
 
 #[derive(Debug)]
 pub enum InterpretError {
     Compile,
-    Runtime,
+    /// The program stopped with an error, which has already been printed.
+    /// Holds the message, prefixed with the line it came from.
+    Runtime(String),
 }
 
 impl Error for InterpretError {}
@@ -21,14 +31,182 @@ impl fmt::Display for InterpretError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             InterpretError::Compile => write!(f, "compile failed"),
-            InterpretError::Runtime => write!(f, "runtime error"),
+            InterpretError::Runtime(message) => write!(f, "runtime error: {}", message),
         }
     }
 }
 
-fn runtime_error<T>(msg: &str) -> Result<T, InterpretError> {
-    eprintln!("runtime error: {}", msg);
-    Err(InterpretError::Runtime)
+/// Settings that change how the VM runs a program.
+#[derive(Default)]
+pub struct VmOptions {
+    /// Disassemble the compiled code, then print the stack and each
+    /// instruction as it executes.
+    pub trace: bool,
+    /// How `print` writes floats.
+    pub number_format: NumberFormat,
+}
+
+/// An error raised while running an instruction.  `run` reports it along
+/// with the line of the instruction that failed.
+type RunResult<T> = Result<T, String>;
+
+fn error<T>(message: impl Into<String>) -> RunResult<T> {
+    Err(message.into())
+}
+
+fn runtime_error(line: usize, message: &str) -> InterpretError {
+    let error = InterpretError::Runtime(format!("[line {}] {}", line, message));
+    eprintln!("{}", error);
+    error
+}
+
+/// Reports bytecode that the compiler would never have produced, as from a
+/// damaged compiled file.
+fn corrupt_error<T>(problem: &str) -> RunResult<T> {
+    error(format!("corrupt bytecode: {}", problem))
+}
+
+fn output_error<T>(e: io::Error) -> RunResult<T> {
+    error(format!("Could not write output: {}", e))
+}
+
+fn arity_error<T>(arity: usize, arg_count: usize) -> RunResult<T> {
+    error(format!("Expected {} arguments but got {}.", arity, arg_count))
+}
+
+fn overflow_error<T>() -> RunResult<T> {
+    error("Integer overflow.")
+}
+
+/// Reports a global that isn't defined, suggesting the defined one with the
+/// closest name in case it was a typo.
+fn undefined_variable<'a, T>(name: &str, defined: impl Iterator<Item = &'a Symbol>) -> RunResult<T> {
+    // Allow an edit for every three characters, so names too short to have
+    // an obvious typo get no suggestion.
+    let limit = name.chars().count() / 3;
+    let closest = defined
+        .map(|key| (edit_distance(name, key.as_str()), key.as_str()))
+        .filter(|&(distance, _)| distance <= limit)
+        .min();
+    match closest {
+        Some((_, key)) => error(format!("Undefined variable '{}'. Did you mean '{}'?", name, key)),
+        None => error(format!("Undefined variable '{}'.", name)),
+    }
+}
+
+/// Counts the characters that must be inserted, removed or replaced to turn
+/// `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    // The distances from the part of `a` seen so far to each prefix of `b`.
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, x) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &y) in b.iter().enumerate() {
+            let replace = diagonal + (x != y) as usize;
+            diagonal = row[j + 1];
+            row[j + 1] = replace.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// Orders two numbers, comparing as integers when both of them are.
+fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Int(a), Value::Int(b)) => Some(a.cmp(b)),
+        (Value::Int(a), Value::Number(b)) => compare_int_float(*a, *b),
+        (Value::Number(a), Value::Int(b)) => compare_int_float(*b, *a).map(Ordering::reverse),
+        _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+    }
+}
+
+// Converting the integer to a float can round it, so as `==` does, compare
+// the float's whole part as an integer when it's in range.
+fn compare_int_float(a: i64, b: f64) -> Option<Ordering> {
+    // 2^63, the first float too large for an i64.
+    let limit = 9_223_372_036_854_775_808.0;
+    if b.is_nan() {
+        None
+    } else if b >= limit {
+        Some(Ordering::Less)
+    } else if b < -limit {
+        Some(Ordering::Greater)
+    } else {
+        Some(a.cmp(&(b.trunc() as i64)).then(0.0.partial_cmp(&b.fract())?))
+    }
+}
+
+/// Pops two numbers and pushes the result of applying an operator to them.
+/// Two integers give an integer; otherwise both are promoted to floats.
+fn arithmetic(
+    stack: &mut ValueStack,
+    int_op: fn(i64, i64) -> Option<i64>,
+    float_op: fn(f64, f64) -> f64,
+) -> RunResult<()> {
+    let b = stack.pop()?;
+    let a = stack.pop()?;
+    match (&a, &b) {
+        (Value::Int(a), Value::Int(b)) => match int_op(*a, *b) {
+            Some(x) => stack.push(Value::Int(x)),
+            None => return overflow_error(),
+        },
+        _ => match (a.as_f64(), b.as_f64()) {
+            (Some(a), Some(b)) => stack.push_f64(float_op(a, b)),
+            _ => return error("arithmetic called on non-numbers"),
+        },
+    }
+    Ok(())
+}
+
+/// Repeats `s` `count` times, where the count must be a whole number that
+/// isn't negative.
+fn repeat(s: &str, count: &Value) -> RunResult<String> {
+    let count = match *count {
+        Value::Int(count) => usize::try_from(count).ok(),
+        Value::Number(count) if count >= 0.0 && count.fract() == 0.0 => Some(count as usize),
+        _ => None,
+    };
+    let count = match count {
+        Some(count) => count,
+        None => return error("String repetition count must be a non-negative integer."),
+    };
+
+    // Report a string too long to allocate rather than aborting.
+    let mut repeated = String::new();
+    match s.len().checked_mul(count) {
+        Some(len) if repeated.try_reserve_exact(len).is_ok() => {}
+        _ => return error("String repetition result is too long."),
+    }
+    for _ in 0..count {
+        repeated.push_str(s);
+    }
+    Ok(repeated)
+}
+
+fn index_error<T>() -> RunResult<T> {
+    error("Only lists and maps can be indexed.")
+}
+
+fn map_key(key: &Value) -> RunResult<&str> {
+    match key.as_obj().and_then(|obj| obj.as_str()) {
+        Some(key) => Ok(key),
+        None => error("Map key must be a string."),
+    }
+}
+
+/// Checks that `index` is an integer within the bounds of `list`.
+fn list_index(list: &List, index: &Value) -> RunResult<usize> {
+    let index = match index {
+        Value::Int(index) => *index,
+        _ => return error("List index must be an integer."),
+    };
+    let len = list.items.borrow().len();
+    match usize::try_from(index) {
+        Ok(index) if index < len => Ok(index),
+        _ => error(format!("List index {} out of bounds for length {}.", index, len)),
+    }
 }
 
 struct ValueStack {
@@ -40,17 +218,77 @@ impl ValueStack {
         ValueStack { stack: Vec::new() }
     }
 
-    pub fn peek(&self, distance: usize) -> &Value {
-        let offset = self.stack.len() - 1 - distance;
-        &self.stack[offset]
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Value> {
+        self.stack.iter()
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.stack.truncate(len);
     }
 
+    /// Returns the top `count` values, deepest first.
+    pub fn top(&self, count: usize) -> RunResult<&[Value]> {
+        match self.stack.len().checked_sub(count) {
+            Some(offset) => Ok(&self.stack[offset..]),
+            None => error("stack underflow"),
+        }
+    }
+
+    pub fn get(&self, offset: usize) -> RunResult<&Value> {
+        match self.stack.get(offset) {
+            Some(value) => Ok(value),
+            None => corrupt_error("stack slot out of range"),
+        }
+    }
+
+    pub fn set(&mut self, offset: usize, value: Value) -> RunResult<()> {
+        match self.stack.get_mut(offset) {
+            Some(slot) => {
+                *slot = value;
+                Ok(())
+            }
+            None => corrupt_error("stack slot out of range"),
+        }
+    }
+
+    fn value_at(&self, distance: usize) -> Option<&Value> {
+        let offset = self.stack.len().checked_sub(distance + 1)?;
+        self.stack.get(offset)
+    }
+
+    pub fn peek(&self, distance: usize) -> RunResult<&Value> {
+        match self.value_at(distance) {
+            Some(value) => Ok(value),
+            None => error("stack underflow"),
+        }
+    }
+
+    // The type checks below are used in match guards, so rather than fail
+    // they answer false when the stack is too short.  The arm that handles a
+    // failed check then reports the underflow through `peek`.
+
     pub fn is_number(&self, distance: usize) -> bool {
-        self.peek(distance).is_number()
+        self.value_at(distance).is_some_and(Value::is_number)
+    }
+
+    pub fn is_int(&self, distance: usize) -> bool {
+        self.value_at(distance).is_some_and(Value::is_int)
+    }
+
+    pub fn is_int_zero(&self, distance: usize) -> bool {
+        matches!(self.value_at(distance), Some(Value::Int(0)))
     }
 
     pub fn is_string(&self, distance: usize) -> bool {
-        self.peek(distance).is_string()
+        self.value_at(distance).is_some_and(Value::is_string)
+    }
+
+    pub fn is_bool(&self, distance: usize) -> bool {
+        matches!(self.value_at(distance), Some(Value::Bool(_)))
     }
 
     pub fn push(&mut self, x: Value) {
@@ -65,268 +303,1868 @@ impl ValueStack {
         self.stack.push(Value::Bool(x));
     }
 
-    pub fn push_offset(&mut self, offset: usize) {
-        let value = self.stack[offset].clone();
+    pub fn push_offset(&mut self, offset: usize) -> RunResult<()> {
+        let value = self.get(offset)?.clone();
         self.push(value);
+        Ok(())
     }
 
-    pub fn pop(&mut self) -> Result<Value, InterpretError> {
+    pub fn pop(&mut self) -> RunResult<Value> {
         match self.stack.pop() {
             Some(x) => Ok(x),
-            None => runtime_error("stack underflow"),
+            None => error("stack underflow"),
+        }
+    }
+
+    pub fn pop_i64(&mut self) -> RunResult<i64> {
+        match self.stack.pop() {
+            Some(Value::Int(x)) => Ok(x),
+            None => error("stack underflow"),
+            _ => error("pop_i64 called on non-integer"),
         }
     }
 
-    pub fn pop_f64(&mut self) -> Result<f64, InterpretError> {
+    pub fn pop_f64(&mut self) -> RunResult<f64> {
         match self.stack.pop() {
             Some(Value::Number(x)) => Ok(x),
-            None => runtime_error("stack underflow"),
-            _ => runtime_error("pop_f64 called on non-number"),
+            None => error("stack underflow"),
+            _ => error("pop_f64 called on non-number"),
         }
     }
 
-    pub fn pop_obj(&mut self) -> Result<Rc<Obj>, InterpretError> {
+    pub fn pop_obj(&mut self) -> RunResult<Rc<Obj>> {
         match self.stack.pop() {
             Some(Value::Obj(x)) => Ok(Rc::clone(&x)),
-            None => runtime_error("stack underflow"),
-            _ => runtime_error("pop_obj called on non-obj"),
+            None => error("stack underflow"),
+            _ => error("pop_obj called on non-obj"),
         }
     }
 
-    pub fn dup_to(&mut self, offset: usize) {
-        let top_offset = self.stack.len() - 1;
-        let top_value = self.stack[top_offset].clone();
-        self.stack[offset] = top_value;
+    pub fn dup_to(&mut self, offset: usize) -> RunResult<()> {
+        let top_value = self.peek(0)?.clone();
+        self.set(offset, top_value)
     }
 
-    pub fn print(&self) {
-        print!("          ");
+    pub fn print(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "          ")?;
         for value in self.stack.iter() {
-            print!("[ {} ]", value);
+            write!(out, "[ {} ]", value)?;
+        }
+        writeln!(out)
+    }
+}
+
+/// A function invocation that is in progress.
+struct CallFrame {
+    closure: Rc<Closure>,
+    ip: usize,
+    // Stack offset of the frame's first local slot.
+    slots: usize,
+}
+
+impl CallFrame {
+    fn new(closure: Rc<Closure>, slots: usize) -> Self {
+        CallFrame {
+            closure,
+            ip: 0,
+            slots,
+        }
+    }
+}
+
+/// Sets up a frame to call `closure` with the `arg_count` arguments on top of
+/// the stack.
+fn call_closure(
+    closure: Rc<Closure>,
+    arg_count: usize,
+    stack: &ValueStack,
+) -> RunResult<CallFrame> {
+    if arg_count != closure.function.arity {
+        return arity_error(closure.function.arity, arg_count);
+    }
+
+    let slots = stack.len() - arg_count - 1;
+    Ok(CallFrame::new(closure, slots))
+}
+
+/// Returns where a backward jump of `offset` from `ip` lands.
+fn jump_back(ip: usize, offset: usize) -> RunResult<usize> {
+    match ip.checked_sub(offset) {
+        Some(ip) => Ok(ip),
+        None => corrupt_error("jump before the start of the code"),
+    }
+}
+
+fn upvalue_at(frame: &CallFrame, index: usize) -> RunResult<&Rc<RefCell<Upvalue>>> {
+    match frame.closure.upvalues.get(index) {
+        Some(upvalue) => Ok(upvalue),
+        None => corrupt_error("upvalue index out of range"),
+    }
+}
+
+/// Returns the upvalue for the stack slot at `offset`, reusing an open one if
+/// another closure has already captured the slot.
+fn capture_upvalue(open_upvalues: &mut Vec<Rc<RefCell<Upvalue>>>, offset: usize) -> Rc<RefCell<Upvalue>> {
+    for upvalue in open_upvalues.iter() {
+        if let Upvalue::Open(slot) = *upvalue.borrow() {
+            if slot == offset {
+                return Rc::clone(upvalue);
+            }
         }
-        println!("");
     }
+
+    let upvalue = Rc::new(RefCell::new(Upvalue::Open(offset)));
+    open_upvalues.push(Rc::clone(&upvalue));
+    upvalue
+}
+
+/// Makes `callee` the running frame, saving the caller's frame in `frames`.
+fn push_frame(
+    frames: &mut Vec<CallFrame>,
+    frame: &mut CallFrame,
+    callee: CallFrame,
+    max_frames: usize,
+) -> RunResult<()> {
+    if frames.len() + 1 >= max_frames {
+        return error("Stack overflow.");
+    }
+
+    frames.push(mem::replace(frame, callee));
+    Ok(())
+}
+
+/// Closes every open upvalue that refers to a stack slot at or above `last`,
+/// moving the slot's value into the upvalue.
+fn close_upvalues(open_upvalues: &mut Vec<Rc<RefCell<Upvalue>>>, stack: &ValueStack, last: usize) -> RunResult<()> {
+    let mut result = Ok(());
+    open_upvalues.retain(|upvalue| {
+        let slot = match *upvalue.borrow() {
+            Upvalue::Open(slot) => slot,
+            Upvalue::Closed(_) => return false,
+        };
+        if slot < last {
+            return true;
+        }
+
+        match stack.get(slot) {
+            Ok(value) => *upvalue.borrow_mut() = Upvalue::Closed(value.clone()),
+            Err(e) => result = Err(e),
+        }
+        false
+    });
+    result
+}
+
+/// Sets a global variable, for a program run with `globals` to read.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut globals = HashMap::new();
+/// lox::set_global(&mut globals, "x", lox::Value::Int(41));
+///
+/// let mut out = Vec::new();
+/// lox::interpret_with_writer("print x + 1;", &mut globals, &mut out).unwrap();
+/// assert_eq!(out, b"42\n");
+/// ```
+pub fn set_global(globals: &mut HashMap<String, Value>, name: &str, value: Value) {
+    globals.insert(String::from(name), value);
 }
 
+/// Returns the value of a global variable, such as one a program has set.
+pub fn get_global<'a>(globals: &'a HashMap<String, Value>, name: &str) -> Option<&'a Value> {
+    globals.get(name)
+}
+
+/// Compiles and runs `source` with `globals`, printing to stdout.
 pub fn interpret(source: &str, globals: &mut HashMap<String, Value>) -> Result<(), InterpretError> {
-    let mut chunk = Chunk::new();
-    if compiler::compile(source, &mut chunk) {
-        run(&chunk, globals)
-    } else {
-        Err(InterpretError::Compile)
+    interpret_with_writer(source, globals, &mut io::stdout())
+}
+
+/// Runs `source`, sending everything the program prints to `out`.
+///
+/// ```
+/// use std::collections::HashMap;
+///
+/// let mut globals = HashMap::new();
+/// let mut out = Vec::new();
+/// lox::interpret_with_writer("print 1;", &mut globals, &mut out).unwrap();
+/// assert_eq!(out, b"1\n");
+/// ```
+pub fn interpret_with_writer(
+    source: &str,
+    globals: &mut HashMap<String, Value>,
+    out: &mut impl Write,
+) -> Result<(), InterpretError> {
+    interpret_with_options(source, globals, &VmOptions::default(), out)
+}
+
+pub fn interpret_with_options(
+    source: &str,
+    globals: &mut HashMap<String, Value>,
+    options: &VmOptions,
+    out: &mut impl Write,
+) -> Result<(), InterpretError> {
+    let chunk = match compiler::compile_to_chunk(source) {
+        Ok(chunk) => chunk,
+        Err(errors) => {
+            for error in errors {
+                eprintln!("{}", error);
+            }
+            return Err(InterpretError::Compile);
+        }
+    };
+
+    interpret_chunk(chunk, globals, options, out)
+}
+
+/// Runs a chunk that has already been compiled.
+pub fn interpret_chunk(
+    chunk: Chunk,
+    globals: &mut HashMap<String, Value>,
+    options: &VmOptions,
+    out: &mut impl Write,
+) -> Result<(), InterpretError> {
+    // Lend the caller's globals to a fresh VM for the length of the run.
+    let mut vm = Vm::with_globals(mem::take(globals));
+    let result = vm.interpret_chunk(chunk, options, out);
+    *globals = vm.take_globals();
+    result
+}
+
+/// The default limit on how deeply calls can nest.
+pub const DEFAULT_MAX_FRAMES: usize = 64;
+
+/// A virtual machine that runs compiled chunks.
+///
+/// Globals outlive each run, so a program can build on what an earlier one
+/// defined:
+///
+/// ```
+/// let mut vm = lox::Vm::new();
+/// vm.interpret("var x = 40;").unwrap();
+/// vm.interpret("x = x + 2;").unwrap();
+/// assert!(vm.get_global("x") == Some(&lox::Value::Int(42)));
+/// ```
+pub struct Vm {
+    /// The most call frames, counting the script's own, that can be live at
+    /// once.  A call past the limit is a stack overflow.
+    pub max_frames: usize,
+    stack: ValueStack,
+    // Frames of the callers of the running function.
+    frames: Vec<CallFrame>,
+    open_upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    heap: Heap,
+    strings: Interner,
+    globals: HashMap<Symbol, Value>,
+    // Globals declared with `const`, which code compiled later, as in the
+    // REPL, can't assign to either.
+    const_globals: HashSet<Symbol>,
+    // Where `readline` reads from, or stdin if None.
+    input: Option<Box<dyn BufRead>>,
+}
+
+impl Vm {
+    /// Creates a VM with the standard native functions defined.
+    pub fn new() -> Self {
+        let mut globals = HashMap::new();
+        natives::define_natives(&mut globals);
+        Vm::with_globals(globals)
+    }
+
+    fn with_globals(globals: HashMap<String, Value>) -> Self {
+        let mut vm = Vm {
+            max_frames: DEFAULT_MAX_FRAMES,
+            stack: ValueStack::new(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            heap: Heap::new(),
+            strings: Interner::default(),
+            globals: HashMap::new(),
+            const_globals: HashSet::new(),
+            input: None,
+        };
+        for (name, value) in globals {
+            vm.set_global(&name, value);
+        }
+        vm
+    }
+
+    fn take_globals(&mut self) -> HashMap<String, Value> {
+        self.globals
+            .drain()
+            .map(|(name, value)| (String::from(name.as_str()), value))
+            .collect()
+    }
+
+    /// Compiles and runs `source`, printing to stdout.
+    pub fn interpret(&mut self, source: &str) -> Result<(), InterpretError> {
+        let chunk = match compiler::compile_to_chunk(source) {
+            Ok(chunk) => chunk,
+            Err(errors) => {
+                for error in errors {
+                    eprintln!("{}", error);
+                }
+                return Err(InterpretError::Compile);
+            }
+        };
+
+        self.interpret_chunk(chunk, &VmOptions::default(), &mut io::stdout())
+    }
+
+    /// Runs a chunk that has already been compiled.
+    ///
+    /// A chunk may come from a file that has been damaged, so bytecode the
+    /// compiler couldn't have produced is a runtime error rather than a panic.
+    ///
+    /// ```
+    /// let chunk = lox::compile_to_chunk("print 1;").unwrap();
+    /// let mut out = Vec::new();
+    /// lox::Vm::new().interpret_chunk(chunk, &lox::VmOptions::default(), &mut out).unwrap();
+    /// assert_eq!(out, b"1\n");
+    /// ```
+    pub fn interpret_chunk(
+        &mut self,
+        mut chunk: Chunk,
+        options: &VmOptions,
+        out: &mut impl Write,
+    ) -> Result<(), InterpretError> {
+        // Global variables are looked up by interned name.
+        self.strings.intern_constants(&mut chunk);
+
+        let script = Function::script(chunk);
+        let script = Closure::new(Rc::new(script), Vec::new());
+        self.run(Rc::new(script), options, out)
+    }
+
+    /// Gives programs run on this VM something other than stdin to read.
+    ///
+    /// ```
+    /// let mut vm = lox::Vm::new();
+    /// vm.set_input("hello\n".as_bytes());
+    ///
+    /// let mut chunk = lox::Chunk::new();
+    /// lox::compile("print readline(); print readline();", &mut chunk).unwrap();
+    /// let mut out = Vec::new();
+    /// vm.interpret_chunk(chunk, &lox::VmOptions::default(), &mut out).unwrap();
+    /// assert_eq!(out, b"hello\nnil\n");
+    /// ```
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Some(Box::new(input));
+    }
+
+    /// Sets a global variable for programs run on this VM.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name = Symbol::new(self.strings.intern(name));
+        self.globals.insert(name, value);
+    }
+
+    /// Returns the value of a global variable.
+    pub fn get_global(&self, name: &str) -> Option<&Value> {
+        // A name that was never interned can't be a global.
+        let name = Symbol::new(self.strings.get(name)?);
+        self.globals.get(&name)
+    }
+
+    /// Adds a native function to the globals under `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = Obj::new_native(Native::new(name, arity, function));
+        self.set_global(name, Value::Obj(native));
+    }
+
+    /// Frees the objects that programs run on this VM can no longer reach.
+    /// This happens on its own as a program allocates, but a host may want
+    /// to collect between runs.
+    ///
+    /// ```
+    /// let mut vm = lox::Vm::new();
+    /// vm.interpret("var s = \"a\" + \"b\"; s = nil;").unwrap();
+    /// vm.collect_garbage();
+    /// assert_eq!(vm.object_count(), 0);
+    /// ```
+    pub fn collect_garbage(&mut self) {
+        self.collect(None);
+    }
+
+    /// Returns how many objects the VM is tracking.
+    pub fn object_count(&self) -> usize {
+        self.heap.len()
+    }
+
+    /// Collects garbage, treating everything the VM holds as a root, along
+    /// with the running frame's closure if there is one.
+    fn collect(&mut self, frame: Option<&CallFrame>) {
+        let mut marker = Marker::default();
+        for value in self.stack.iter() {
+            marker.mark_value(value);
+        }
+        for value in self.globals.values() {
+            marker.mark_value(value);
+        }
+        for frame in self.frames.iter().chain(frame) {
+            marker.mark_closure(&frame.closure);
+        }
+        for upvalue in self.open_upvalues.iter() {
+            marker.mark_upvalue(upvalue);
+        }
+        marker.trace();
+
+        self.heap.sweep(&mut marker);
+    }
+
+    /// Drops everything left over from a run, such as the locals of a
+    /// program that stopped on an error.
+    fn reset_stack(&mut self) {
+        self.stack.truncate(0);
+        self.frames.clear();
+        self.open_upvalues.clear();
+    }
+
+    /// Starts running `callee`.  A tail call replaces `frame`, whose function
+    /// would only return what the callee does, rather than keeping it to
+    /// return to, so tail recursion never overflows.
+    fn enter(&mut self, frame: &mut CallFrame, mut callee: CallFrame, tail_call: bool) -> RunResult<()> {
+        if !tail_call {
+            return push_frame(&mut self.frames, frame, callee, self.max_frames);
+        }
+
+        close_upvalues(&mut self.open_upvalues, &self.stack, frame.slots)?;
+        // Move the callee and its arguments down over the caller's slots.
+        let count = self.stack.len() - callee.slots;
+        for i in 0..count {
+            let value = self.stack.get(callee.slots + i)?.clone();
+            self.stack.set(frame.slots + i, value)?;
+        }
+        self.stack.truncate(frame.slots + count);
+        callee.slots = frame.slots;
+        *frame = callee;
+        Ok(())
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
     }
 }
 
+// The macros below return from `execute` with an error when the bytecode
+// doesn't hold what the instruction needs.
+
 macro_rules! read_u8 {
-    ($code:expr, $ip:expr) => {{
-        let result = $code[$ip];
-        $ip += 1;
-        result
+    ($frame:expr) => {{
+        match $frame.closure.function.chunk.code.get($frame.ip) {
+            Some(&byte) => {
+                $frame.ip += 1;
+                byte
+            }
+            None => return corrupt_error("unexpected end of code"),
+        }
     }};
 }
 
 macro_rules! read_u16 {
-    ($code:expr, $ip:expr) => {{
-        let high = $code[$ip] as u16;
-        let low = $code[$ip + 1] as u16;
-        $ip += 2;
+    ($frame:expr) => {{
+        let high = read_u8!($frame) as u16;
+        let low = read_u8!($frame) as u16;
         (high << 8) | low
     }};
 }
 
+macro_rules! read_u24 {
+    ($frame:expr) => {{
+        let high = read_u8!($frame) as usize;
+        let middle = read_u8!($frame) as usize;
+        let low = read_u8!($frame) as usize;
+        (high << 16) | (middle << 8) | low
+    }};
+}
+
+macro_rules! constant_at {
+    ($frame:expr, $constant_offset:expr) => {{
+        match $frame.closure.function.chunk.constants.get($constant_offset) {
+            Some(constant) => constant,
+            None => return corrupt_error("constant index out of range"),
+        }
+    }};
+}
+
 macro_rules! read_constant {
-    ($code:expr, $ip:expr, $constants:expr) => {{
-        let constant_offset = read_u8!($code, $ip) as usize;
-        &$constants[constant_offset]
+    ($frame:expr) => {{
+        let constant_offset = read_u8!($frame) as usize;
+        constant_at!($frame, constant_offset)
+    }};
+}
+
+macro_rules! read_constant_long {
+    ($frame:expr) => {{
+        let constant_offset = read_u24!($frame);
+        constant_at!($frame, constant_offset)
+    }};
+}
+
+macro_rules! read_symbol {
+    ($frame:expr) => {{
+        match read_constant!($frame).as_obj() {
+            Some(name) if name.is_string() => Symbol::new(Rc::clone(name)),
+            _ => return corrupt_error("expected a string constant"),
+        }
     }};
 }
 
 macro_rules! read_string {
-    ($code:expr, $ip:expr, $constants:expr) => {{
-        read_constant!($code, $ip, $constants)
-            .as_str()
-            .expect("expected string constant for a global variable")
-            .to_owned()
+    ($frame:expr) => {{
+        match read_constant!($frame).as_obj().and_then(|obj| obj.as_str()) {
+            Some(s) => s.to_owned(),
+            None => return corrupt_error("expected a string constant"),
+        }
     }};
 }
 
-fn run(chunk: &Chunk, globals: &mut HashMap<String, Value>) -> Result<(), InterpretError> {
-    if chunk.code.len() == 0 {
-        return Ok(());
+impl Vm {
+    fn run(
+        &mut self,
+        script: Rc<Closure>,
+        options: &VmOptions,
+        out: &mut dyn Write,
+    ) -> Result<(), InterpretError> {
+        if script.function.chunk.code.is_empty() {
+            return Ok(());
+        }
+
+        let mut line = script.function.chunk.line_at(0);
+        let result = self.execute(script, options, out, &mut line);
+        self.reset_stack();
+        result.map_err(|message| runtime_error(line, &message))
     }
 
-    let mut ip = 0;
-    let mut stack = ValueStack::new();
-    let mut objects = Vec::new();
+    /// Runs `script`, keeping `line` up to date with the line of the
+    /// instruction being executed so that an error can be reported against it.
+    fn execute(
+        &mut self,
+        script: Rc<Closure>,
+        options: &VmOptions,
+        out: &mut dyn Write,
+        line: &mut usize,
+    ) -> RunResult<()> {
+        if options.trace {
+            script.function.chunk.disassemble("code", out).or_else(output_error)?;
+        }
 
-    loop {
-        //stack.print();
-        //chunk.disassemble_instruction(ip);
+        let mut frame = CallFrame::new(script, 0);
 
-        let line = chunk.lines[ip];
-        let op = read_u8!(chunk.code, ip);
+        loop {
+            // Between instructions every live value is somewhere the
+            // collector looks, so this is a safe point to collect.
+            if self.heap.should_collect() {
+                self.collect(Some(&frame));
+            }
 
-        match op {
-            OP_CONSTANT => {
-                let constant = read_constant!(chunk.code, ip, chunk.constants);
-                let constant = constant.clone();
-                if let Value::Obj(obj) = &constant {
-                    objects.push(Rc::clone(obj));
-                }
-                stack.push(constant);
+            if options.trace {
+                self.stack.print(out).or_else(output_error)?;
+                frame
+                    .closure
+                    .function
+                    .chunk
+                    .disassemble_instruction(frame.ip, out)
+                    .or_else(output_error)?;
             }
 
-            OP_NIL => stack.push(Value::Nil),
-            OP_TRUE => stack.push(Value::Bool(true)),
-            OP_FALSE => stack.push(Value::Bool(false)),
+            *line = frame.closure.function.chunk.line_at(frame.ip);
+            let op = read_u8!(frame);
 
-            OP_POP => {
-                stack.pop()?;
-            }
+            match op {
+                OP_CONSTANT | OP_CONSTANT_LONG => {
+                    let constant = if op == OP_CONSTANT {
+                        read_constant!(frame)
+                    } else {
+                        read_constant_long!(frame)
+                    };
+                    // Constants are owned by the chunk, so pushing one only
+                    // shares it; there's no new object for the VM to track.
+                    let constant = constant.clone();
+                    self.stack.push(constant);
+                }
 
-            OP_GET_LOCAL => {
-                let slot = read_u8!(chunk.code, ip);
-                stack.push_offset(slot as usize);
-            }
+                OP_NIL => self.stack.push(Value::Nil),
+                OP_TRUE => self.stack.push(Value::Bool(true)),
+                OP_FALSE => self.stack.push(Value::Bool(false)),
 
-            OP_SET_LOCAL => {
-                let slot = read_u8!(chunk.code, ip);
-                stack.dup_to(slot as usize);
-            }
+                OP_POP => {
+                    self.stack.pop()?;
+                }
+
+                OP_GET_LOCAL => {
+                    let slot = read_u8!(frame) as usize;
+                    self.stack.push_offset(frame.slots + slot)?;
+                }
 
-            OP_GET_GLOBAL => {
-                let key = read_string!(chunk.code, ip, chunk.constants);
-                match globals.get(&key) {
-                    Some(value) => stack.push(value.clone()),
-                    None => {
-                        let message = format!("Undefined variable '{}'.", key);
-                        return runtime_error(&message);
+                OP_SET_LOCAL => {
+                    let slot = read_u8!(frame) as usize;
+                    self.stack.dup_to(frame.slots + slot)?;
+                }
+
+                OP_GET_GLOBAL => {
+                    let key = read_symbol!(frame);
+                    match self.globals.get(&key) {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => return undefined_variable(key.as_str(), self.globals.keys()),
                     }
                 }
-            }
 
-            OP_DEFINE_GLOBAL => {
-                let key = read_string!(chunk.code, ip, chunk.constants);
-                let value = stack.peek(0).clone();
-                globals.insert(key, value);
+                OP_DEFINE_GLOBAL | OP_DEFINE_CONST_GLOBAL => {
+                    let key = read_symbol!(frame);
+                    if self.const_globals.contains(&key) {
+                        return error(format!("Cannot redeclare constant '{}'.", key.as_str()));
+                    }
+                    if op == OP_DEFINE_CONST_GLOBAL {
+                        self.const_globals.insert(key.clone());
+                    }
+                    let value = self.stack.peek(0)?.clone();
+                    self.globals.insert(key, value);
 
-                stack.pop()?;
-            }
+                    self.stack.pop()?;
+                }
 
-            OP_SET_GLOBAL => {
-                let key = read_string!(chunk.code, ip, chunk.constants);
-                if globals.contains_key(&key) {
-                    let value = stack.peek(0).clone();
-                    globals.insert(key, value);
-                } else {
-                    let message = format!("Undefined variable '{}'.", key);
-                    return runtime_error(&message);   
+                OP_SET_GLOBAL => {
+                    let key = read_symbol!(frame);
+                    if self.const_globals.contains(&key) {
+                        return error(format!("Cannot assign to constant '{}'.", key.as_str()));
+                    }
+                    if let Some(global) = self.globals.get_mut(&key) {
+                        *global = self.stack.peek(0)?.clone();
+                    } else {
+                        return undefined_variable(key.as_str(), self.globals.keys());
+                    }
                 }
-            }
 
-            OP_EQUAL => {
-                let b = stack.pop()?;
-                let a = stack.pop()?;
-                stack.push_bool(a == b);
-            }
+                OP_EQUAL => {
+                    let b = self.stack.pop()?;
+                    let a = self.stack.pop()?;
+                    self.stack.push_bool(a == b);
+                }
 
-            OP_GREATER if stack.is_number(0) && stack.is_number(1) => {
-                let b = stack.pop_f64()?;
-                let a = stack.pop_f64()?;
-                stack.push_bool(a > b);
-            }
+                OP_GREATER if self.stack.is_number(0) && self.stack.is_number(1) => {
+                    let b = self.stack.pop()?;
+                    let a = self.stack.pop()?;
+                    self.stack.push_bool(compare(&a, &b) == Some(Ordering::Greater));
+                }
 
-            OP_LESS if stack.is_number(0) && stack.is_number(1) => {
-                let b = stack.pop_f64()?;
-                let a = stack.pop_f64()?;
-                stack.push_bool(a < b);
-            }
+                OP_LESS if self.stack.is_number(0) && self.stack.is_number(1) => {
+                    let b = self.stack.pop()?;
+                    let a = self.stack.pop()?;
+                    self.stack.push_bool(compare(&a, &b) == Some(Ordering::Less));
+                }
 
-            OP_ADD if stack.is_string(0) && stack.is_string(1) => {
-                let b = stack.pop_obj()?;
-                let a = stack.pop_obj()?;
+                OP_GREATER | OP_LESS if self.stack.is_string(0) && self.stack.is_string(1) => {
+                    let b = self.stack.pop_obj()?;
+                    let a = self.stack.pop_obj()?;
+                    let ordering = a.as_str().cmp(&b.as_str());
+                    let expected = if op == OP_GREATER { Ordering::Greater } else { Ordering::Less };
+                    self.stack.push_bool(ordering == expected);
+                }
 
-                let mut s = a.as_str().unwrap().to_owned();
-                s.push_str(b.as_str().unwrap());
+                OP_ADD if self.stack.is_string(0) && self.stack.is_string(1) => {
+                    let b = self.stack.pop_obj()?;
+                    let a = self.stack.pop_obj()?;
 
-                let s = Obj::new_string(s);
-                objects.push(Rc::clone(&s));
-                let value = Value::Obj(s);
+                    let mut s = a.as_str().unwrap().to_owned();
+                    s.push_str(b.as_str().unwrap());
 
-                stack.push(value);
-            }
+                    let s = Obj::new_string(s);
+                    self.heap.track(Rc::clone(&s));
+                    let value = Value::Obj(s);
 
-            OP_ADD if stack.is_number(0) && stack.is_number(1) => {
-                let b = stack.pop_f64()?;
-                let a = stack.pop_f64()?;
-                stack.push_f64(a + b);
-            }
+                    self.stack.push(value);
+                }
 
-            OP_SUBTRACT if stack.is_number(0) && stack.is_number(1) => {
-                let b = stack.pop_f64()?;
-                let a = stack.pop_f64()?;
-                stack.push_f64(a - b);
-            }
+                OP_ADD if self.stack.is_number(0) && self.stack.is_number(1) => {
+                    arithmetic(&mut self.stack, i64::checked_add, |a, b| a + b)?;
+                }
 
-            OP_MULTIPLY if stack.is_number(0) && stack.is_number(1) => {
-                let b = stack.pop_f64()?;
-                let a = stack.pop_f64()?;
-                stack.push_f64(a * b);
-            }
+                OP_SUBTRACT if self.stack.is_number(0) && self.stack.is_number(1) => {
+                    arithmetic(&mut self.stack, i64::checked_sub, |a, b| a - b)?;
+                }
 
-            OP_DIVIDE if stack.is_number(0) && stack.is_number(1) => {
-                let b = stack.pop_f64()?;
-                let a = stack.pop_f64()?;
-                stack.push_f64(a / b);
-            }
+                OP_MULTIPLY if self.stack.is_number(0) && self.stack.is_number(1) => {
+                    arithmetic(&mut self.stack, i64::checked_mul, |a, b| a * b)?;
+                }
 
-            OP_NOT => {
-                let a = stack.pop()?;
-                stack.push(a.is_falsey());
-            }
+                // A string times a count, in either order, repeats the string.
+                OP_MULTIPLY
+                    if (self.stack.is_string(0) && self.stack.is_number(1))
+                        || (self.stack.is_number(0) && self.stack.is_string(1)) =>
+                {
+                    let b = self.stack.pop()?;
+                    let a = self.stack.pop()?;
+                    let (s, count) = match a.as_str() {
+                        Some(s) => (s, &b),
+                        None => (b.as_str().unwrap(), &a),
+                    };
 
-            OP_ADD | OP_SUBTRACT | OP_MULTIPLY | OP_DIVIDE => {
-                let msg = format!("[line {}] operands must be numbers", line);
-                return runtime_error(&msg);
-            }
+                    let s = Obj::new_string(repeat(s, count)?);
+                    self.heap.track(Rc::clone(&s));
+                    self.stack.push(Value::Obj(s));
+                }
 
-            OP_NEGATE if stack.is_number(0) => {
-                let a = stack.pop_f64()?;
-                stack.push_f64(-a);
-            }
+                // Integers have no infinity or NaN, so dividing one by zero is an
+                // error.  If either operand is a float the division is done in
+                // floating point and follows IEEE 754, giving inf or NaN.
+                OP_DIVIDE | OP_MODULO if self.stack.is_int_zero(0) && self.stack.is_int(1) => {
+                    return error("Division by zero.");
+                }
+
+                OP_DIVIDE if self.stack.is_number(0) && self.stack.is_number(1) => {
+                    arithmetic(&mut self.stack, i64::checked_div, |a, b| a / b)?;
+                }
+
+                OP_MODULO if self.stack.is_number(0) && self.stack.is_number(1) => {
+                    arithmetic(&mut self.stack, i64::checked_rem, |a, b| a % b)?;
+                }
+
+                OP_POWER if self.stack.is_number(0) && self.stack.is_number(1) => {
+                    let b = self.stack.pop()?;
+                    let a = self.stack.pop()?;
+                    match (&a, &b) {
+                        // A negative exponent gives a fraction, so only a
+                        // non-negative one keeps integers integral.
+                        (Value::Int(a), Value::Int(b)) if *b >= 0 => {
+                            match u32::try_from(*b).ok().and_then(|b| a.checked_pow(b)) {
+                                Some(x) => self.stack.push(Value::Int(x)),
+                                None => return overflow_error(),
+                            }
+                        }
+                        _ => match (a.as_f64(), b.as_f64()) {
+                            (Some(a), Some(b)) => self.stack.push_f64(a.powf(b)),
+                            _ => return error("power called on non-numbers"),
+                        },
+                    }
+                }
+
+                OP_BIT_AND if self.stack.is_int(0) && self.stack.is_int(1) => {
+                    let b = self.stack.pop_i64()?;
+                    let a = self.stack.pop_i64()?;
+                    self.stack.push(Value::Int(a & b));
+                }
+
+                OP_BIT_OR if self.stack.is_int(0) && self.stack.is_int(1) => {
+                    let b = self.stack.pop_i64()?;
+                    let a = self.stack.pop_i64()?;
+                    self.stack.push(Value::Int(a | b));
+                }
+
+                OP_BIT_XOR if self.stack.is_int(0) && self.stack.is_int(1) => {
+                    let b = self.stack.pop_i64()?;
+                    let a = self.stack.pop_i64()?;
+                    self.stack.push(Value::Int(a ^ b));
+                }
+
+                OP_SHIFT_LEFT | OP_SHIFT_RIGHT if self.stack.is_int(0) && self.stack.is_int(1) => {
+                    let b = self.stack.pop_i64()?;
+                    let a = self.stack.pop_i64()?;
+                    let shifted = u32::try_from(b).ok().and_then(|b| {
+                        if op == OP_SHIFT_LEFT {
+                            a.checked_shl(b)
+                        } else {
+                            a.checked_shr(b)
+                        }
+                    });
+                    match shifted {
+                        Some(x) => self.stack.push(Value::Int(x)),
+                        None => return error(format!("Shift amount {} out of range.", b)),
+                    }
+                }
+
+                OP_BIT_AND | OP_BIT_OR | OP_BIT_XOR | OP_SHIFT_LEFT | OP_SHIFT_RIGHT => {
+                    self.stack.peek(1)?;
+                    return error("operands must be integers");
+                }
+
+                OP_NOT => {
+                    let a = self.stack.pop()?;
+                    self.stack.push(Value::Bool(a.is_falsey()));
+                }
+
+                // Comparisons don't chain: `1 < 2 < 3` compares the bool from
+                // `1 < 2` with 3.  Say so, rather than just that the operands
+                // aren't numbers.
+                OP_GREATER | OP_LESS if self.stack.is_bool(0) || self.stack.is_bool(1) => {
+                    return error("Can't order a bool.  Comparisons don't chain; write 'a < b and b < c'.");
+                }
+
+                OP_ADD | OP_SUBTRACT | OP_MULTIPLY | OP_DIVIDE | OP_MODULO | OP_POWER | OP_GREATER | OP_LESS => {
+                    self.stack.peek(1)?;
+                    return error("operands must be numbers");
+                }
+
+                OP_NEGATE if self.stack.is_int(0) => {
+                    let a = self.stack.pop_i64()?;
+                    match a.checked_neg() {
+                        Some(x) => self.stack.push(Value::Int(x)),
+                        None => return overflow_error(),
+                    }
+                }
+
+                OP_NEGATE if self.stack.is_number(0) => {
+                    let a = self.stack.pop_f64()?;
+                    self.stack.push_f64(-a);
+                }
+
+                OP_NEGATE => {
+                    self.stack.peek(0)?;
+                    return error("operand must be a number");
+                }
+
+                OP_PRINT => {
+                    let value = self.stack.pop()?;
+                    writeln!(out, "{}", value.display(options.number_format)).or_else(output_error)?;
+                }
+
+                OP_JUMP => {
+                    let offset = read_u16!(frame) as usize;
+                    frame.ip += offset;
+                }
+
+                OP_JUMP_IF_FALSE => {
+                    let offset = read_u16!(frame) as usize;
+
+                    if self.stack.peek(0)?.is_falsey() {
+                        frame.ip += offset;
+                    }
+                }
+
+                OP_LOOP => {
+                    let offset = read_u16!(frame) as usize;
+                    frame.ip = jump_back(frame.ip, offset)?;
+                }
+
+                OP_JUMP_LONG => {
+                    let offset = read_u24!(frame);
+                    frame.ip += offset;
+                }
+
+                OP_JUMP_IF_FALSE_LONG => {
+                    let offset = read_u24!(frame);
+
+                    if self.stack.peek(0)?.is_falsey() {
+                        frame.ip += offset;
+                    }
+                }
+
+                OP_LOOP_LONG => {
+                    let offset = read_u24!(frame);
+                    frame.ip = jump_back(frame.ip, offset)?;
+                }
+
+                OP_CALL | OP_TAIL_CALL => {
+                    // Only a Lox function's frame can be replaced.  Other
+                    // callees return here, to the return that follows.
+                    let tail_call = op == OP_TAIL_CALL;
+                    let arg_count = read_u8!(frame) as usize;
+                    let callee = self.stack.peek(arg_count)?.as_obj().map(|obj| obj.value.clone());
+
+                    match callee {
+                        Some(ObjValue::Closure(closure)) => {
+                            let callee_frame = call_closure(closure, arg_count, &self.stack)?;
+                            self.enter(&mut frame, callee_frame, tail_call)?;
+                        }
+
+                        Some(ObjValue::BoundMethod(bound)) => {
+                            // The receiver takes the callee's slot, where the
+                            // method expects to find `this`.
+                            let slot = self.stack.len() - arg_count - 1;
+                            self.stack.set(slot, bound.receiver.clone())?;
+
+                            let callee_frame = call_closure(Rc::clone(&bound.method), arg_count, &self.stack)?;
+                            self.enter(&mut frame, callee_frame, tail_call)?;
+                        }
+
+                        Some(ObjValue::Class(class)) => {
+                            // The instance takes the class's slot, where
+                            // `init` expects to find `this`.
+                            let instance = Obj::new_instance(Instance::new(Rc::clone(&class)));
+                            self.heap.track(Rc::clone(&instance));
+                            let slot = self.stack.len() - arg_count - 1;
+                            self.stack.set(slot, Value::Obj(instance))?;
+
+                            let initializer = class.methods.borrow().get("init").map(Rc::clone);
+                            match initializer {
+                                Some(initializer) => {
+                                    let callee_frame = call_closure(initializer, arg_count, &self.stack)?;
+                                    push_frame(&mut self.frames, &mut frame, callee_frame, self.max_frames)?;
+                                }
+                                None if arg_count != 0 => return arity_error(0, arg_count),
+                                None => {}
+                            }
+                        }
 
-            OP_NEGATE => {
-                let msg = format!("[line {}] operand must be a number", line);
-                return runtime_error(&msg);
+                        Some(ObjValue::Native(native)) => {
+                            if arg_count < native.arity || arg_count > native.max_arity {
+                                if native.arity == native.max_arity {
+                                    return arity_error(native.arity, arg_count);
+                                }
+                                return error(format!(
+                                    "Expected {} to {} arguments but got {}.",
+                                    native.arity, native.max_arity, arg_count
+                                ));
+                            }
+
+                            let args = self.stack.top(arg_count)?;
+                            let result = match native.function {
+                                NativeBody::Plain(function) => function(args)?,
+                                NativeBody::Input(function) => match &mut self.input {
+                                    Some(input) => function(input.as_mut(), args)?,
+                                    None => function(&mut io::stdin().lock(), args)?,
+                                },
+                                NativeBody::Output(function) => function(out, args)?,
+                            };
+                            // A native may return an object that's already
+                            // tracked, such as one of its arguments.
+                            match &result {
+                                Value::Obj(obj) if Rc::strong_count(obj) == 1 => self.heap.track(Rc::clone(obj)),
+                                _ => {}
+                            }
+                            self.stack.truncate(self.stack.len() - arg_count - 1);
+                            self.stack.push(result);
+                        }
+
+                        _ => return error("Can only call functions and classes."),
+                    }
+                }
+
+                OP_CLOSURE => {
+                    let function = match read_constant!(frame).as_obj().and_then(|obj| obj.as_function()) {
+                        Some(function) => Rc::clone(function),
+                        None => return corrupt_error("expected a function constant"),
+                    };
+
+                    let mut upvalues = Vec::with_capacity(function.upvalue_count);
+                    for _ in 0..function.upvalue_count {
+                        let is_local = read_u8!(frame) != 0;
+                        let index = read_u8!(frame) as usize;
+                        let upvalue = if is_local {
+                            // Check the slot now, since it's read later.  A
+                            // local function captures itself, in the slot the
+                            // closure is about to take.
+                            if frame.slots + index > self.stack.len() {
+                                return corrupt_error("stack slot out of range");
+                            }
+                            capture_upvalue(&mut self.open_upvalues, frame.slots + index)
+                        } else {
+                            Rc::clone(upvalue_at(&frame, index)?)
+                        };
+                        upvalues.push(upvalue);
+                    }
+
+                    let closure = Obj::new_closure(Closure::new(function, upvalues));
+                    self.heap.track(Rc::clone(&closure));
+                    self.stack.push(Value::Obj(closure));
+                }
+
+                OP_GET_UPVALUE => {
+                    let index = read_u8!(frame) as usize;
+                    let value = match &*upvalue_at(&frame, index)?.borrow() {
+                        Upvalue::Open(slot) => self.stack.get(*slot)?.clone(),
+                        Upvalue::Closed(value) => value.clone(),
+                    };
+                    self.stack.push(value);
+                }
+
+                OP_SET_UPVALUE => {
+                    let index = read_u8!(frame) as usize;
+                    let value = self.stack.peek(0)?.clone();
+                    let mut upvalue = upvalue_at(&frame, index)?.borrow_mut();
+                    match &mut *upvalue {
+                        Upvalue::Open(slot) => self.stack.set(*slot, value)?,
+                        Upvalue::Closed(closed) => *closed = value,
+                    }
+                }
+
+                OP_CLOSE_UPVALUE => {
+                    self.stack.peek(0)?;
+                    close_upvalues(&mut self.open_upvalues, &self.stack, self.stack.len() - 1)?;
+                    self.stack.pop()?;
+                }
+
+                OP_CLASS => {
+                    let name = read_string!(frame);
+                    let class = Obj::new_class(Class::new(&name));
+                    self.heap.track(Rc::clone(&class));
+                    self.stack.push(Value::Obj(class));
+                }
+
+                OP_GET_PROPERTY => {
+                    let instance = match self.stack.peek(0)?.as_obj().and_then(|obj| obj.as_instance()) {
+                        Some(instance) => Rc::clone(instance),
+                        None => return error("Only instances have properties."),
+                    };
+                    let name = read_string!(frame);
+
+                    // Fields shadow methods.
+                    let field = instance.fields.borrow().get(&name).cloned();
+                    let method = instance.class.methods.borrow().get(&name).map(Rc::clone);
+
+                    let value = match (field, method) {
+                        (Some(value), _) => value,
+                        (None, Some(method)) => {
+                            let receiver = self.stack.peek(0)?.clone();
+                            let bound = Obj::new_bound_method(BoundMethod::new(receiver, method));
+                            self.heap.track(Rc::clone(&bound));
+                            Value::Obj(bound)
+                        }
+                        (None, None) => return error(format!("Undefined property '{}'.", name)),
+                    };
+                    self.stack.pop()?;
+                    self.stack.push(value);
+                }
+
+                OP_SET_PROPERTY => {
+                    let instance = match self.stack.peek(1)?.as_obj().and_then(|obj| obj.as_instance()) {
+                        Some(instance) => Rc::clone(instance),
+                        None => return error("Only instances have fields."),
+                    };
+                    let name = read_string!(frame);
+
+                    let value = self.stack.pop()?;
+                    instance.fields.borrow_mut().insert(name, value.clone());
+                    self.stack.pop()?;
+                    self.stack.push(value);
+                }
+
+                OP_INHERIT => {
+                    let superclass = match self.stack.peek(1)?.as_obj().and_then(|obj| obj.as_class()) {
+                        Some(superclass) => Rc::clone(superclass),
+                        None => return error("Superclass must be a class."),
+                    };
+                    let subclass = match self.stack.peek(0)?.as_obj().and_then(|obj| obj.as_class()) {
+                        Some(subclass) => Rc::clone(subclass),
+                        None => return corrupt_error("expected a class to inherit"),
+                    };
+
+                    // Copy the methods down before the subclass's own are
+                    // added, so that its own override them.
+                    let methods = superclass.methods.borrow();
+                    let inherited = methods.iter().map(|(name, method)| (name.clone(), Rc::clone(method)));
+                    subclass.methods.borrow_mut().extend(inherited);
+                    self.stack.pop()?;
+                }
+
+                OP_GET_SUPER => {
+                    let name = read_string!(frame);
+                    let superclass = match self.stack.pop()?.as_obj().and_then(|obj| obj.as_class()) {
+                        Some(superclass) => Rc::clone(superclass),
+                        None => return corrupt_error("expected a superclass"),
+                    };
+
+                    let method = match superclass.methods.borrow().get(&name) {
+                        Some(method) => Rc::clone(method),
+                        None => return error(format!("Undefined property '{}'.", name)),
+                    };
+                    let receiver = self.stack.pop()?;
+                    let bound = Obj::new_bound_method(BoundMethod::new(receiver, method));
+                    self.heap.track(Rc::clone(&bound));
+                    self.stack.push(Value::Obj(bound));
+                }
+
+                OP_LIST => {
+                    let item_count = read_u8!(frame) as usize;
+                    let items = self.stack.top(item_count)?.to_vec();
+                    self.stack.truncate(self.stack.len() - item_count);
+
+                    let list = Obj::new_list(List::new(items));
+                    self.heap.track(Rc::clone(&list));
+                    self.stack.push(Value::Obj(list));
+                }
+
+                OP_MAP => {
+                    let entry_count = read_u8!(frame) as usize;
+                    let mut entries = HashMap::new();
+                    for pair in self.stack.top(entry_count * 2)?.chunks(2) {
+                        let key = map_key(&pair[0])?;
+                        entries.insert(String::from(key), pair[1].clone());
+                    }
+                    self.stack.truncate(self.stack.len() - entry_count * 2);
+
+                    let map = Obj::new_map(Map::new(entries));
+                    self.heap.track(Rc::clone(&map));
+                    self.stack.push(Value::Obj(map));
+                }
+
+                OP_GET_INDEX => {
+                    // A key missing from a map reads as nil.
+                    let value = match self.stack.peek(1)?.as_obj().map(|obj| &obj.value) {
+                        Some(ObjValue::List(list)) => {
+                            let index = list_index(list, self.stack.peek(0)?)?;
+                            list.items.borrow()[index].clone()
+                        }
+                        Some(ObjValue::Map(map)) => {
+                            let key = map_key(self.stack.peek(0)?)?;
+                            map.entries.borrow().get(key).cloned().unwrap_or(Value::Nil)
+                        }
+                        _ => return index_error(),
+                    };
+                    self.stack.pop()?;
+                    self.stack.pop()?;
+                    self.stack.push(value);
+                }
+
+                OP_SET_INDEX => {
+                    let value = self.stack.peek(0)?.clone();
+                    match self.stack.peek(2)?.as_obj().map(|obj| &obj.value) {
+                        Some(ObjValue::List(list)) => {
+                            let index = list_index(list, self.stack.peek(1)?)?;
+                            list.items.borrow_mut()[index] = value.clone();
+                        }
+                        Some(ObjValue::Map(map)) => {
+                            let key = map_key(self.stack.peek(1)?)?;
+                            map.entries.borrow_mut().insert(String::from(key), value.clone());
+                        }
+                        _ => return index_error(),
+                    }
+                    self.stack.pop()?;
+                    self.stack.pop()?;
+                    self.stack.pop()?;
+                    self.stack.push(value);
+                }
+
+                OP_METHOD => {
+                    let name = read_string!(frame);
+                    let method = match self.stack.peek(0)?.as_obj().and_then(|obj| obj.as_closure()) {
+                        Some(method) => Rc::clone(method),
+                        None => return corrupt_error("expected a closure for a method"),
+                    };
+                    let class = match self.stack.peek(1)?.as_obj().and_then(|obj| obj.as_class()) {
+                        Some(class) => class,
+                        None => return corrupt_error("expected a class for a method"),
+                    };
+
+                    class.methods.borrow_mut().insert(name, method);
+                    self.stack.pop()?;
+                }
+
+                OP_RETURN => {
+                    let result = self.stack.pop()?;
+                    close_upvalues(&mut self.open_upvalues, &self.stack, frame.slots)?;
+                    match self.frames.pop() {
+                        Some(caller_frame) => {
+                            // Discard the callee and its arguments and locals.
+                            self.stack.truncate(frame.slots);
+                            self.stack.push(result);
+                            frame = caller_frame;
+                        }
+                        None => {
+                            // Every statement leaves the stack as it found it, so
+                            // by the implicit return at the end of the script the
+                            // stack is empty.  An explicit `return` may leave
+                            // locals behind.
+                            let at_end = frame.ip == frame.closure.function.chunk.code.len();
+                            if at_end && self.stack.len() != 0 {
+                                return corrupt_error(&format!(
+                                    "stack unbalanced at end of script: {} values left",
+                                    self.stack.len()
+                                ));
+                            }
+                            return Ok(());
+                        }
+                    }
+                }
+
+                _ => {
+                    return corrupt_error(&format!("unknown opcode {}", op));
+                }
             }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs `chunk` on `vm`, returning what it prints.
+    fn run_chunk(vm: &mut Vm, chunk: Chunk) -> Result<String, InterpretError> {
+        let mut out = Vec::new();
+        vm.interpret_chunk(chunk, &VmOptions::default(), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn cloned_chunk_reads_globals_from_functions() {
+        let chunk = compiler::compile_to_chunk("var g = 5; fun f() { return g; } print f();").ok().unwrap();
+        let copy = chunk.clone();
+
+        assert_eq!(run_chunk(&mut Vm::new(), chunk).ok().unwrap(), "5\n");
+        assert_eq!(run_chunk(&mut Vm::new(), copy).ok().unwrap(), "5\n");
+    }
+
+    /// Compiles and runs `source` on a fresh `Vm`, returning what it prints.
+    fn run(source: &str) -> String {
+        let chunk = compiler::compile_to_chunk(source).ok().unwrap();
+        run_chunk(&mut Vm::new(), chunk).ok().unwrap()
+    }
+
+    /// Returns the message of the runtime error `source` stops with.
+    fn runtime_error(vm: &mut Vm, source: &str) -> String {
+        match vm.interpret(source) {
+            Err(InterpretError::Runtime(message)) => message,
+            _ => panic!("expected a runtime error from {:?}", source),
+        }
+    }
+
+    #[test]
+    fn const_global_stays_const_across_runs() {
+        let mut vm = Vm::new();
+        vm.interpret("const x = 1;").unwrap();
+
+        assert_eq!(runtime_error(&mut vm, "x = 2;"), "[line 1] Cannot assign to constant 'x'.");
+        assert_eq!(runtime_error(&mut vm, "var x = 3;"), "[line 1] Cannot redeclare constant 'x'.");
+        assert_eq!(runtime_error(&mut vm, "const x = 4;"), "[line 1] Cannot redeclare constant 'x'.");
+        assert!(vm.get_global("x") == Some(&Value::Int(1)));
+    }
+
+    #[test]
+    fn collecting_keeps_values_the_host_holds() {
+        let mut vm = Vm::new();
+        vm.interpret("var l = [1, 2, 3]; class Node {} var n = Node(); n.next = n;").unwrap();
+        let list = vm.get_global("l").cloned().unwrap();
+        let node = vm.get_global("n").cloned().unwrap();
+
+        vm.interpret("l = nil; n = nil;").unwrap();
+        vm.collect_garbage();
+        assert_eq!(list.to_string(), "[1, 2, 3]");
+        let fields = &node.as_obj().unwrap().as_instance().unwrap().fields;
+        assert!(fields.borrow().get("next") == Some(&node));
+
+        // Once the host lets go, only the class is left.
+        drop((list, node));
+        vm.collect_garbage();
+        assert_eq!(vm.object_count(), 1);
+    }
+
+    #[test]
+    fn collecting_frees_unreachable_cycles() {
+        let mut vm = Vm::new();
+        vm.interpret("class Node {} { var a = Node(); var b = Node(); a.next = b; b.next = a; }").unwrap();
+        assert_eq!(vm.object_count(), 3);
+
+        vm.collect_garbage();
+        assert_eq!(vm.object_count(), 1);
+    }
+
+    #[test]
+    fn native_returning_an_argument_is_tracked_once() {
+        fn identity(args: &[Value]) -> Result<Value, String> {
+            Ok(args[0].clone())
+        }
+
+        let mut vm = Vm::new();
+        vm.define_native("identity", 1, identity);
+        vm.interpret("var l = identity([1]);").unwrap();
+        assert_eq!(vm.object_count(), 1);
+    }
+
+    #[test]
+    fn modulo() {
+        assert_eq!(run("print 7 % 3; print -7 % 3; print 7.5 % 2;"), "1\n-1\n1.5\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "print 7 % \"a\";"), "[line 1] operands must be numbers");
+        assert_eq!(runtime_error(&mut Vm::new(), "print 7 % 0;"), "[line 1] Division by zero.");
+    }
+
+    #[test]
+    fn while_loop() {
+        assert_eq!(run("var i = 0; while (i < 3) { print i; i = i + 1; }"), "0\n1\n2\n");
+        assert_eq!(run("while (false) print 1; print 2;"), "2\n");
+    }
+
+    #[test]
+    fn for_loop() {
+        assert_eq!(run("var sum = 0; for (var i = 1; i <= 5; i = i + 1) sum = sum + i; print sum;"), "15\n");
+        assert_eq!(run("var i = 0; for (;;) { if (i == 2) return; print i; i = i + 1; }"), "0\n1\n");
+        assert_eq!(run("var i = 3; for (; i > 0;) i = i - 1; print i;"), "0\n");
+    }
+
+    #[test]
+    fn and_short_circuits() {
+        assert_eq!(run("print false and undefined_thing;"), "false\n");
+        assert_eq!(run("print nil and 1; print 1 and 2; print true and false;"), "nil\n2\nfalse\n");
+    }
+
+    #[test]
+    fn or_short_circuits() {
+        assert_eq!(run("print true or (1 / 0);"), "true\n");
+        assert_eq!(run("print nil or 1; print false or nil; print 1 or 2;"), "1\nnil\n1\n");
+    }
+
+    #[test]
+    fn break_leaves_the_loop() {
+        assert_eq!(run("var i = 0; while (true) { if (i == 2) break; print i; i = i + 1; } print \"done\";"), "0\n1\ndone\n");
+        assert_eq!(run("for (var i = 0; i < 10; i = i + 1) { { var x = i; if (x == 1) break; } print i; }"), "0\n");
+    }
+
+    #[test]
+    fn continue_skips_the_rest_of_the_body() {
+        assert_eq!(run("var i = 0; while (i < 4) { i = i + 1; if (i == 2) continue; print i; }"), "1\n3\n4\n");
+        // The increment still runs, so this ends rather than repeating i == 1.
+        assert_eq!(run("for (var i = 0; i < 4; i = i + 1) { var x = i; if (x == 1) continue; print x; }"), "0\n2\n3\n");
+    }
 
-            OP_PRINT => {
-                let value = stack.pop()?;
-                println!("{}", value);
+    #[test]
+    fn calling_functions() {
+        assert_eq!(run("fun outer() { fun inner(x) { print x; } inner(1); inner(2); } outer();"), "1\n2\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "fun f(a, b) {}\nvar g = f;\ng(1);"), "[line 3] Expected 2 arguments but got 1.");
+    }
+
+    #[test]
+    fn returning_values() {
+        assert_eq!(run("fun add(a, b) { return a + b; } print add(1, 2);"), "3\n");
+        assert_eq!(run("fun f() { return; } print f(); print 1; return; print 2;"), "nil\n1\n");
+    }
+
+    #[test]
+    fn calling_clock() {
+        let mut vm = Vm::new();
+        vm.interpret("var t = clock();").unwrap();
+        assert!(matches!(vm.get_global("t"), Some(Value::Number(t)) if *t >= 0.0));
+        assert_eq!(runtime_error(&mut vm, "clock(1);"), "[line 1] Expected 0 arguments but got 1.");
+    }
+
+    #[test]
+    fn closures_keep_captured_locals() {
+        let source = "
+            fun make_counter() {
+                var count = 0;
+                fun increment() { count = count + 1; return count; }
+                return increment;
             }
+            var a = make_counter();
+            var b = make_counter();
+            print a(); print a(); print b(); print a();
+        ";
+        assert_eq!(run(source), "1\n2\n1\n3\n");
+    }
+
+    #[test]
+    fn creating_instances() {
+        assert_eq!(run("class Point {} print Point; print Point();"), "Point\nPoint instance\n");
+    }
+
+    #[test]
+    fn instance_fields() {
+        assert_eq!(run("class P {} var p = P(); p.x = 1; p.x = p.x + 1; print p.x;"), "2\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "class P {} P().x;"), "[line 1] Undefined property 'x'.");
+    }
+
+    #[test]
+    fn methods_read_this() {
+        let source = "
+            class Person { greet() { print \"Hi, \" + this.name; } }
+            var p = Person();
+            p.name = \"Ada\";
+            var greet = p.greet;
+            p.name = \"Grace\";
+            greet();
+        ";
+        assert_eq!(run(source), "Hi, Grace\n");
+    }
+
+    #[test]
+    fn ternary() {
+        assert_eq!(run("print true ? 1 : 2; print nil ? 1 : 2;"), "1\n2\n");
+        assert_eq!(run("fun sign(n) { return n > 0 ? 1 : n < 0 ? -1 : 0; } print sign(5); print sign(-5); print sign(0);"), "1\n-1\n0\n");
+        // Only the chosen branch runs.
+        assert_eq!(run("print false ? 1 / 0 : 3;"), "3\n");
+    }
+
+    #[test]
+    fn compound_assignment() {
+        assert_eq!(run("var x = 1; x += 4; print x;"), "5\n");
+        assert_eq!(run("{ var x = 10; x -= 4; x *= 3; x /= 2; print x; }"), "9\n");
+    }
+
+    #[test]
+    fn more_than_256_constants() {
+        let mut source = String::from("var sum = 0;");
+        for n in 1..=300 {
+            source.push_str(&format!(" sum = sum + {};", n));
+        }
+        source.push_str(" print sum;");
+        assert!(compiler::compile_to_chunk(&source).ok().unwrap().constants.len() > 256);
+        assert_eq!(run(&source), "45150\n");
+    }
+
+    #[test]
+    fn tracing_is_opt_in() {
+        let chunk = compiler::compile_to_chunk("print 1;").ok().unwrap();
+        assert_eq!(run_chunk(&mut Vm::new(), chunk.clone()).ok().unwrap(), "1\n");
+
+        let mut out = Vec::new();
+        let options = VmOptions { trace: true, ..VmOptions::default() };
+        Vm::new().interpret_chunk(chunk, &options, &mut out).ok().unwrap();
+        let traced = String::from_utf8(out).unwrap();
+        assert!(traced.starts_with("== code ==\n"));
+        assert!(traced.contains("OP_PRINT"));
+    }
+
+    #[test]
+    fn int_and_float_arithmetic() {
+        let mut vm = Vm::new();
+        // Mixing in a float promotes, but ints stay integral.
+        vm.interpret("var a = 7 / 2; var b = 7.0 / 2; var c = 7 % 2; var d = 1 + 0.5; var e = 1000000000000 * 3;").unwrap();
+        assert!(matches!(vm.get_global("a"), Some(Value::Int(3))));
+        assert!(matches!(vm.get_global("b"), Some(Value::Number(x)) if *x == 3.5));
+        assert!(matches!(vm.get_global("c"), Some(Value::Int(1))));
+        assert!(matches!(vm.get_global("d"), Some(Value::Number(x)) if *x == 1.5));
+        assert!(matches!(vm.get_global("e"), Some(Value::Int(3000000000000))));
+    }
+
+    #[test]
+    fn list_indexing() {
+        assert_eq!(run("var l = [1, 2, 3]; print l[1]; l[1] = \"two\"; print l;"), "2\n[1, two, 3]\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "[1, 2][2];"), "[line 1] List index 2 out of bounds for length 2.");
+        assert_eq!(runtime_error(&mut Vm::new(), "[1, 2][-1] = 0;"), "[line 1] List index -1 out of bounds for length 2.");
+        assert_eq!(runtime_error(&mut Vm::new(), "[1, 2][0.5];"), "[line 1] List index must be an integer.");
+    }
+
+    #[test]
+    fn negating_an_empty_stack() {
+        let mut chunk = Chunk::new();
+        chunk.emit(OP_NEGATE, 1);
+        chunk.emit(OP_RETURN, 1);
+        let result = run_chunk(&mut Vm::new(), chunk);
+        assert!(matches!(result, Err(InterpretError::Runtime(message)) if message == "[line 1] stack underflow"));
+    }
+
+    #[test]
+    fn printing_to_a_writer() {
+        let mut globals = HashMap::new();
+        let mut out = Vec::new();
+        interpret_with_writer("var x = \"hi\"; print x;", &mut globals, &mut out).unwrap();
+        interpret_with_writer("print x + \"!\";", &mut globals, &mut out).unwrap();
+        assert_eq!(out, b"hi\nhi!\n");
+    }
 
-            OP_JUMP => {
-                let offset = read_u16!(chunk.code, ip) as usize;
-                ip += offset;
+    #[test]
+    fn runtime_errors_report_their_line() {
+        let source = "var a = 1; var b = 2;\nprint a + b;\nprint a + nil;";
+        assert_eq!(runtime_error(&mut Vm::new(), source), "[line 3] operands must be numbers");
+    }
+
+    #[test]
+    fn local_variables() {
+        let source = "
+            var a = \"global\";
+            {
+                var a = \"outer\";
+                {
+                    var a = \"inner\";
+                    print a;
+                }
+                print a;
+                if (true) { var b = a + \"!\"; print b; }
             }
+            print a;
+        ";
+        assert_eq!(run(source), "inner\nouter\nouter!\nglobal\n");
+    }
+
+    #[test]
+    fn power() {
+        assert_eq!(run("print 2 ** 3 ** 2; print (2 ** 3) ** 2; print 2 ** -1; print -2 ** 2;"), "512\n64\n0.5\n-4\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "print 2 ** \"a\";"), "[line 1] operands must be numbers");
+    }
+
+    #[test]
+    fn bitwise_operators() {
+        assert_eq!(run("print 6 & 3; print 6 | 3; print 6 ^ 3; print 1 << 4; print -16 >> 2;"), "2\n7\n5\n16\n-4\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "print 6 & 3.0;"), "[line 1] operands must be integers");
+        assert_eq!(runtime_error(&mut Vm::new(), "print 1 << 64;"), "[line 1] Shift amount 64 out of range.");
+    }
+
+    #[test]
+    fn infinite_recursion_overflows() {
+        assert_eq!(runtime_error(&mut Vm::new(), "fun f() { f(); }\nf();"), "[line 1] Stack overflow.");
 
-            OP_JUMP_IF_FALSE => {
-                let offset = read_u16!(chunk.code, ip) as usize;
+        let mut vm = Vm::new();
+        vm.max_frames = 4;
+        let source = "var depth = 0; fun f() { depth = depth + 1; f(); } f();";
+        assert_eq!(runtime_error(&mut vm, source), "[line 1] Stack overflow.");
+        assert!(vm.get_global("depth") == Some(&Value::Int(3)));
+    }
 
-                if stack.peek(0).is_falsey_rust() {
-                    ip += offset;
+    #[test]
+    fn switch_statement() {
+        let source = "
+            fun name(n) {
+                switch (n) {
+                    case 1: print \"one\";
+                    case 2: print \"two\";
+                    case 1: print \"one again\";
+                    default: print \"many\";
                 }
             }
+            name(1); name(2); name(3);
+            switch (3) { case 1: print \"unreachable\"; }
+            print \"done\";
+        ";
+        // A duplicate case never runs, since the first match wins.
+        assert_eq!(run(source), "one\ntwo\nmany\ndone\n");
+
+        let source = "var calls = 0; fun next() { calls = calls + 1; return calls; }
+            switch (next()) { case 2: print 2; case 3: print 3; } print calls;";
+        assert_eq!(run(source), "1\n");
+    }
+
+    #[test]
+    fn comparing_strings() {
+        assert_eq!(run("print \"apple\" < \"banana\"; print \"b\" > \"abc\"; print \"a\" < \"a\";"), "true\ntrue\nfalse\n");
+        assert_eq!(run("print \"a\" + \"b\" == \"ab\"; print \"a\" != \"b\";"), "true\ntrue\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "print \"a\" < 1;"), "[line 1] operands must be numbers");
+    }
+
+    #[test]
+    fn concatenating_non_ascii_strings() {
+        assert_eq!(run("var s = \"café\" + \"✓\"; print s; print len(s); print substr(s, 3, 5);"), "café✓\n5\né✓\n");
+    }
 
-            OP_RETURN => {
-                return Ok(());
-                // let value = stack.pop()?;
-                // println!("{}", value);
+    #[test]
+    fn host_globals_and_natives() {
+        fn double(args: &[Value]) -> Result<Value, String> {
+            match args[0] {
+                Value::Int(x) => Ok(Value::Int(x * 2)),
+                _ => Err(String::from("double() expects an integer.")),
             }
+        }
+
+        let mut globals = HashMap::new();
+        set_global(&mut globals, "x", Value::Int(41));
+        let mut out = Vec::new();
+        interpret_with_writer("print x + 1; var y = x - 1;", &mut globals, &mut out).unwrap();
+        assert_eq!(out, b"42\n");
+        assert!(get_global(&globals, "y") == Some(&Value::Int(40)));
+
+        let mut vm = Vm::new();
+        vm.set_global("x", Value::Int(41));
+        vm.define_native("double", 1, double);
+        vm.interpret("var y = double(x);").unwrap();
+        assert!(vm.get_global("y") == Some(&Value::Int(82)));
+        assert_eq!(runtime_error(&mut vm, "double(nil);"), "[line 1] double() expects an integer.");
+    }
+
+    #[test]
+    fn calling_a_numeric_native_wrongly() {
+        assert_eq!(runtime_error(&mut Vm::new(), "abs(\"x\");"), "[line 1] abs() arguments must be numbers.");
+        assert_eq!(runtime_error(&mut Vm::new(), "var f = max; f(1);"), "[line 1] Expected 2 arguments but got 1.");
+    }
+
+    #[test]
+    fn dividing_by_zero() {
+        assert_eq!(runtime_error(&mut Vm::new(), "print 1 / 0;"), "[line 1] Division by zero.");
+        assert_eq!(runtime_error(&mut Vm::new(), "print 1 % 0;"), "[line 1] Division by zero.");
+        // Float division follows IEEE 754.
+        assert_eq!(run("print 1.0 / 0; print -1 / 0.0; print 0.0 / 0 == 0.0 / 0;"), "inf\n-inf\nfalse\n");
+    }
+
+    #[test]
+    fn if_leaves_the_stack_balanced() {
+        // The script checks the stack is empty when it ends.
+        for source in ["if (true) {}", "if (false) {}", "var x = false; if (x) {} else {}", "if (nil) {} else {}"] {
+            assert_eq!(run(source), "");
+        }
+        // A value left behind would shift the slots of later locals.
+        assert_eq!(run("{ if (true) {} if (false) {} var a = 1; print a; }"), "1\n");
+    }
+
+    #[test]
+    fn undefined_variables_report_their_line() {
+        assert_eq!(runtime_error(&mut Vm::new(), "var a = 1;\n\nprint b;"), "[line 3] Undefined variable 'b'.");
+        assert_eq!(runtime_error(&mut Vm::new(), "var a = 1;\nb = 2;"), "[line 2] Undefined variable 'b'.");
+        assert_eq!(runtime_error(&mut Vm::new(), "fun f() {\n  return b;\n}\nf();"), "[line 2] Undefined variable 'b'.");
+    }
+
+    #[test]
+    fn runs_share_a_vm() {
+        let mut vm = Vm::new();
+        let mut run_on_vm = |source| run_chunk(&mut vm, compiler::compile_to_chunk(source).ok().unwrap()).ok().unwrap();
+        assert_eq!(run_on_vm("var x = 40; fun f() { return x; }"), "");
+        assert_eq!(run_on_vm("x = x + 2; print f();"), "42\n");
+
+        // An error part way through a run doesn't spoil the next one.
+        let mut vm = Vm::new();
+        vm.interpret("fun f() { return 1; }").unwrap();
+        assert_eq!(runtime_error(&mut vm, "{ var a = 1; f(nil); }"), "[line 1] Expected 0 arguments but got 1.");
+        vm.interpret("var x; { var b = 2; var c = b + f(); x = c; }").unwrap();
+        assert!(vm.get_global("x") == Some(&Value::Int(3)));
+    }
 
-            _ => {
-                return runtime_error("unknown op");
+    #[test]
+    fn temporary_strings_are_collected() {
+        let mut vm = Vm::new();
+        let source = "var a = \"a\"; var s; for (var i = 0; i < 10000; i = i + 1) { s = a + str(i); }";
+        vm.interpret(source).unwrap();
+        assert!(vm.object_count() < 1000);
+
+        // Only the last string is still reachable.
+        vm.collect_garbage();
+        assert_eq!(vm.object_count(), 1);
+    }
+
+    #[test]
+    fn anonymous_functions() {
+        assert_eq!(run("var add = fun (a, b) { return a + b; }; print add(1, 2); print add;"), "3\n<fn anonymous>\n");
+        assert_eq!(run("fun twice(f, x) { return f(f(x)); } print twice(fun (n) { return n * 3; }, 2);"), "18\n");
+    }
+
+    #[test]
+    fn calling_super_methods() {
+        let source = "
+            class A { name() { return \"A\"; } greet() { print \"I am \" + this.name(); } }
+            class B < A { name() { return \"B, not \" + super.name(); } }
+            B().greet();
+        ";
+        assert_eq!(run(source), "I am B, not A\n");
+    }
+
+    #[test]
+    fn initializers() {
+        assert_eq!(run("class P { init(n) { this.n = n; } } var p = P(5); print p.n;"), "5\n");
+        // A bare return still gives the instance.
+        assert_eq!(run("class P { init() { this.a = 1; if (true) return; this.a = 2; } } print P().a; print P().init();"), "1\nP instance\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "class P { init(n) {} } P();"), "[line 1] Expected 1 arguments but got 0.");
+    }
+
+    #[test]
+    fn comma_operator() {
+        assert_eq!(run("print (1, 2, 3);"), "3\n");
+        // Commas in calls and lists still separate items.
+        assert_eq!(run("print max(1, 2); print [1, 2];"), "2\n[1, 2]\n");
+        assert_eq!(run("var i; var j; for (i = 0, j = 3; i < j; i = i + 1, j = j - 1) print i * 10 + j;"), "3\n12\n");
+    }
+
+    #[test]
+    fn increment_and_decrement() {
+        assert_eq!(run("var g = 1; g++; g++; g--; print g;"), "2\n");
+        assert_eq!(run("{ var n = 0; while (n < 3) n++; print n; }"), "3\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "var s = \"a\"; s++;"), "[line 1] operands must be numbers");
+    }
+
+    #[test]
+    fn maps() {
+        let source = "
+            var m = {\"a\": 1, \"b\": 2};
+            print m[\"a\"];
+            print m[\"z\"];
+            m[\"a\"] = 3;
+            m[\"c\"] = 4;
+            print m[\"a\"];
+            print m[\"c\"];
+        ";
+        assert_eq!(run(source), "1\nnil\n3\n4\n");
+        assert_eq!(runtime_error(&mut Vm::new(), "var m = {\"a\": 1}; print m[1];"), "[line 1] Map key must be a string.");
+    }
+
+    #[test]
+    fn failed_assertions() {
+        assert_eq!(run("assert(1 == 1); assert(true, \"unused\");"), "");
+        assert_eq!(runtime_error(&mut Vm::new(), "assert(false, \"boom\");"), "[line 1] boom");
+        assert_eq!(runtime_error(&mut Vm::new(), "var f = assert; f();"), "[line 1] Expected 1 to 2 arguments but got 0.");
+    }
+
+    #[test]
+    fn type_names() {
+        let source = "
+            class C { m() {} }
+            fun f() {}
+            var values = [1, 1.5, true, nil, \"s\", f, fun () {}, clock, C().m, C, C(), [], {}];
+            for (var i = 0; i < len(values); i = i + 1) write(type(values[i]) + \" \");
+        ";
+        assert_eq!(
+            run(source),
+            "number number bool nil string function function function function class instance list map "
+        );
+    }
+
+    #[test]
+    fn equal_operator_on_floats() {
+        let source = "var nan = 0.0 / 0; print nan == nan; print nan != nan; print -0.0 == 0; print 1 == 1.0;";
+        assert_eq!(run(source), "false\ntrue\ntrue\ntrue\n");
+    }
+
+    #[test]
+    fn dropping_instructions_keeps_behaviour() {
+        assert_eq!(run("var a = 1; nil; print !(a != 2);"), "false\n");
+        // Jumps over and across the dropped instructions still land right.
+        let source = "var a = true; while (!!a) { nil; a = false; nil; } print !!a; if (!!nil) nil; else print \"else\";";
+        assert_eq!(run(source), "false\nelse\n");
+    }
+
+    #[test]
+    fn long_jumps() {
+        // Each statement in the body compiles to 8 bytes.
+        let body = "total = total + 0;".repeat(10_000);
+        let source = format!(
+            "var total = 0;\
+             for (var i = 0; i < 3; i = i + 1) {{ total = total + 1; {} }}\
+             if (total == 0) {{ {} }} else {{ {} }}\
+             print total;",
+            body, body, body
+        );
+
+        let listing = compiler::compile_to_chunk(&source).ok().unwrap().disassemble_to_string();
+        assert!(listing.contains("OP_JUMP_IF_FALSE_LONG"));
+        assert!(listing.contains("OP_JUMP_LONG"));
+        assert!(listing.contains("OP_LOOP_LONG"));
+        assert_eq!(run(&source), "3\n");
+    }
+
+    #[test]
+    fn do_while_loop() {
+        assert_eq!(run("var i = 10; do { print i; i = i + 1; } while (i < 3);"), "10\n");
+        assert_eq!(run("var i = 0; do i = i + 1; while (i < 3); print i;"), "3\n");
+    }
+
+    /// Returns the message of the runtime error running `code`, with a
+    /// single integer constant, stops with.
+    fn corrupt(code: &[u8]) -> String {
+        let mut chunk = Chunk::new();
+        for &byte in code {
+            chunk.emit(byte, 1);
+        }
+        chunk.constants.push(Value::Int(1));
+        match run_chunk(&mut Vm::new(), chunk) {
+            Err(InterpretError::Runtime(message)) => message,
+            _ => panic!("expected a runtime error from {:?}", code),
+        }
+    }
+
+    #[test]
+    fn running_corrupt_code() {
+        assert_eq!(corrupt(&[OP_CONSTANT]), "[line 1] corrupt bytecode: unexpected end of code");
+        assert_eq!(corrupt(&[OP_CONSTANT, 7]), "[line 1] corrupt bytecode: constant index out of range");
+        assert_eq!(corrupt(&[OP_GET_LOCAL, 9]), "[line 1] corrupt bytecode: stack slot out of range");
+        assert_eq!(corrupt(&[OP_LOOP, 0, 9]), "[line 1] corrupt bytecode: jump before the start of the code");
+        assert_eq!(corrupt(&[OP_GET_GLOBAL, 0]), "[line 1] corrupt bytecode: expected a string constant");
+        assert_eq!(corrupt(&[OP_GET_UPVALUE, 0]), "[line 1] corrupt bytecode: upvalue index out of range");
+        assert_eq!(corrupt(&[OP_CLOSURE, 0]), "[line 1] corrupt bytecode: expected a function constant");
+    }
+
+    #[test]
+    fn suggesting_a_close_global() {
+        let mut vm = Vm::new();
+        vm.interpret("var fob = 1; var counter = 2;").unwrap();
+        assert_eq!(runtime_error(&mut vm, "print fib;"), "[line 1] Undefined variable 'fib'. Did you mean 'fob'?");
+        assert_eq!(
+            runtime_error(&mut vm, "cuonter = 3;"),
+            "[line 1] Undefined variable 'cuonter'. Did you mean 'counter'?"
+        );
+        // Too far from anything defined to guess.
+        assert_eq!(runtime_error(&mut vm, "print xyz;"), "[line 1] Undefined variable 'xyz'.");
+    }
+
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("fib", "fob"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("héllo", "hello"), 1);
+    }
+
+    #[test]
+    fn printing_with_a_number_format() {
+        let chunk = compiler::compile_to_chunk("print 1.0; print [1.0, 2]; print 3;").ok().unwrap();
+        let options = VmOptions { number_format: NumberFormat::Decimal, ..VmOptions::default() };
+        let mut out = Vec::new();
+        Vm::new().interpret_chunk(chunk, &options, &mut out).ok().unwrap();
+        assert_eq!(out, b"1.0\n[1.0, 2]\n3\n");
+    }
+
+    #[test]
+    fn converting_between_strings_and_numbers() {
+        assert_eq!(run("print num(\"10\") + 5; print str(true) + \"!\"; print str(42) + str(1.5);"), "15\ntrue!\n421.5\n");
+    }
+
+    #[test]
+    fn reading_input() {
+        let mut vm = Vm::new();
+        vm.set_input("hello\n".as_bytes());
+        let chunk = compiler::compile_to_chunk("print readline(); print readline();").ok().unwrap();
+        assert_eq!(run_chunk(&mut vm, chunk).ok().unwrap(), "hello\nnil\n");
+    }
+
+    #[test]
+    fn redeclaring_a_global_replaces_it() {
+        assert_eq!(run("var x = 1; var x = 2; print x;"), "2\n");
+    }
+
+    #[test]
+    fn line_directive_shifts_runtime_errors() {
+        let source = "//# line 50 \"gen.tmpl\"\nprint 1;\nprint nil + 1;";
+        assert_eq!(runtime_error(&mut Vm::new(), source), "[line 51] operands must be numbers");
+    }
+
+    #[test]
+    fn loading_a_string_constant_shares_it() {
+        let mut vm = Vm::new();
+        let source = "
+            var first; var last;
+            for (var i = 0; i < 3; i = i + 1) {
+                var s = \"lox\";
+                if (i == 0) first = s;
+                last = s;
             }
+        ";
+        vm.interpret(source).unwrap();
+        match (vm.get_global("first"), vm.get_global("last")) {
+            (Some(Value::Obj(first)), Some(Value::Obj(last))) => assert!(Rc::ptr_eq(first, last)),
+            _ => panic!("expected two strings"),
         }
     }
+
+    #[test]
+    fn tail_calls_reuse_the_frame() {
+        let source = "fun count(n) { if (n == 0) return \"done\"; return count(n - 1); }\nprint count(100000);";
+        assert_eq!(run(source), "done\n");
+
+        let source = "fun sum(n, total) { if (n == 0) return total; return sum(n - 1, total + n); }\nprint sum(10000, 0);";
+        assert_eq!(run(source), "50005000\n");
+
+        // A call whose value is still used isn't in tail position.
+        let source = "fun count(n) { if (n == 0) return 0; return 1 + count(n - 1); }\ncount(100);";
+        assert_eq!(runtime_error(&mut Vm::new(), source), "[line 1] Stack overflow.");
+    }
+
+    #[test]
+    fn repeating_strings() {
+        assert_eq!(run("print \"x\" * 3; print 3 * \"x\"; print \"ab\" * 2.0; print \"ab\" * 0;"), "xxx\nxxx\nabab\n\n");
+
+        let message = "[line 1] String repetition count must be a non-negative integer.";
+        assert_eq!(runtime_error(&mut Vm::new(), "print \"x\" * -1;"), message);
+        assert_eq!(runtime_error(&mut Vm::new(), "print \"x\" * 1.5;"), message);
+        assert_eq!(runtime_error(&mut Vm::new(), "print \"x\" * \"y\";"), "[line 1] operands must be numbers");
+
+        let message = "[line 1] String repetition result is too long.";
+        assert_eq!(runtime_error(&mut Vm::new(), "print \"xx\" * 9223372036854775807;"), message);
+    }
+
+    #[test]
+    fn comparisons_dont_chain() {
+        let message = "[line 1] Can't order a bool.  Comparisons don't chain; write 'a < b and b < c'.";
+        assert_eq!(runtime_error(&mut Vm::new(), "print 1 < 2 < 3;"), message);
+        assert_eq!(runtime_error(&mut Vm::new(), "print 3 >= 2 >= 1;"), message);
+        assert_eq!(run("print 1 < 2 and 2 < 3; print 1 < 2 == true;"), "true\ntrue\n");
+    }
+
+    #[test]
+    fn ordering_ints_and_floats_exactly() {
+        let source = "var max = 9223372036854775807; var limit = 9223372036854775808.0;\n\
+                      print max < limit; print max <= limit; print max >= limit; print limit > max;";
+        assert_eq!(run(source), "true\ntrue\nfalse\ntrue\n");
+
+        let source = "var min = -9223372036854775807 - 1; var limit = -9223372036854775808.0;\n\
+                      print min < limit; print min >= limit; print min > -9223372036854775809.0;";
+        assert_eq!(run(source), "false\ntrue\nfalse\n");
+
+        // 2^53 + 1 rounds to 2^53 as a float.
+        assert_eq!(run("print 9007199254740993 > 9007199254740992.0;"), "true\n");
+        assert_eq!(run("print 2 < 2.5; print 3 > 2.5; print -2 > -2.5; print 1 < 0.0 / 0.0;"), "true\ntrue\ntrue\nfalse\n");
+    }
 }
@@ -0,0 +1,47 @@
+//! Runs the `lox` binary the way a user would, checking what it prints and
+//! the code it exits with.
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+/// Runs `lox` with `args`, feeding it `input` on stdin.
+fn lox(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_lox"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn dump_prints_code_without_running_it() {
+    let output = lox(&["--dump", "-"], "print 1;");
+    assert_eq!(output.status.code(), Some(0));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("== code ==\n"));
+    assert!(stdout.contains("OP_PRINT"));
+    assert!(!stdout.contains("\n1\n"));
+}
+
+#[test]
+fn running_stdin() {
+    for args in [&["-"][..], &[]] {
+        let output = lox(args, "print 1 + 2;");
+        assert_eq!(output.status.code(), Some(0));
+        assert_eq!(output.stdout, b"3\n");
+    }
+    assert_eq!(lox(&["-"], "print 1 +;").status.code(), Some(65));
+    assert_eq!(lox(&["-"], "print 1 + nil;").status.code(), Some(70));
+}
+
+#[test]
+fn failed_assertion_exits_with_70() {
+    let output = lox(&["-"], "print 1; assert(false, \"boom\"); print 2;");
+    assert_eq!(output.status.code(), Some(70));
+    assert_eq!(output.stdout, b"1\n");
+    assert!(String::from_utf8(output.stderr).unwrap().contains("boom"));
+}
@@ -0,0 +1,37 @@
+//! Runs the Lox test scripts and compares what they print with the output
+//! saved next to this file.  After changing a script on purpose, update the
+//! saved output with `cargo run -- test.lox > tests/test.lox.out`.
+
+use std::fs;
+use std::path::Path;
+
+/// Runs `chunk` on a fresh VM, returning what it prints.
+fn run(chunk: lox::Chunk) -> String {
+    let mut out = Vec::new();
+    if let Err(error) = lox::Vm::new().interpret_chunk(chunk, &lox::VmOptions::default(), &mut out) {
+        panic!("{}", error);
+    }
+    String::from_utf8(out).unwrap()
+}
+
+/// Runs the script at `name`, relative to the crate, and checks its output
+/// against `tests/<name>.out`, both compiled directly and after a round trip
+/// through the `.loxc` format.
+fn check_script(name: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let source = fs::read_to_string(root.join(name)).unwrap();
+    let expected = fs::read_to_string(root.join("tests").join(format!("{}.out", name))).unwrap();
+
+    let chunk = match lox::compile_to_chunk(&source) {
+        Ok(chunk) => chunk,
+        Err(errors) => panic!("{}", errors[0]),
+    };
+    let loaded = lox::Chunk::from_bytes(&chunk.to_bytes()).unwrap();
+    assert_eq!(run(chunk), expected);
+    assert_eq!(run(loaded), expected);
+}
+
+#[test]
+fn test_lox() {
+    check_script("test.lox");
+}